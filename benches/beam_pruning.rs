@@ -0,0 +1,124 @@
+//! Speed / accuracy trade-off of beam pruning on a generated country-scale network.
+//!
+//! Run with `cargo bench --bench beam_pruning`. Each row decodes the same packets
+//! (random walks over the graph, logged as 1- and 2-byte hashes) with one beam setting.
+
+use app::graph::{Anchor, DecoderConfig, NetworkGraph, PacketPath};
+use app::models::{PathNode, Repeater};
+use app::propagation::SigmoidModel;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Instant;
+
+const NODE_COUNT: usize = 3000;
+const PACKET_COUNT: usize = 200;
+const MAX_HOPS: usize = 8;
+
+fn generate_network(rng: &mut StdRng) -> Vec<Repeater> {
+    // Roughly 400km x 450km around the middle of England
+    (0..NODE_COUNT)
+        .map(|i| Repeater {
+            id: format!("{:06X}", rng.random_range(0..0xFFFFFF)),
+            name: format!("Node_{}", i),
+            lat: 52.5 + rng.random_range(-1.8..1.8),
+            lon: -1.5 + rng.random_range(-3.2..3.2),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// A random walk over feasible links, never revisiting a repeater.
+fn random_walk(graph: &NetworkGraph, nodes: &[Repeater], rng: &mut StdRng) -> Vec<usize> {
+    let mut walk = vec![rng.random_range(0..nodes.len())];
+    while walk.len() < MAX_HOPS {
+        let current = walk[walk.len() - 1];
+        let next: Vec<usize> = (0..nodes.len())
+            .filter(|j| !walk.contains(j) && graph.link_cost(current, *j).is_some())
+            .collect();
+        if next.is_empty() {
+            break;
+        }
+        walk.push(next[rng.random_range(0..next.len())]);
+    }
+    walk
+}
+
+fn packets_for(walks: &[Vec<usize>], nodes: &[Repeater], width: u8) -> Vec<PacketPath> {
+    walks
+        .iter()
+        .map(|walk| {
+            let first = &nodes[walk[0]];
+            let last = &nodes[walk[walk.len() - 1]];
+            PacketPath {
+                hashes: walk.iter().map(|&i| nodes[i].path_hash(width).unwrap()).collect(),
+                origin: Some(Anchor::new(first.lat + 0.02, first.lon)),
+                observer: Some(Anchor::new(last.lat - 0.02, last.lon)),
+            }
+        })
+        .collect()
+}
+
+fn main() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let nodes = generate_network(&mut rng);
+    let exact_graph = NetworkGraph::new(nodes.clone(), None);
+    println!("{} repeaters, {} directed links", nodes.len(), exact_graph.edge_count());
+
+    let walks: Vec<Vec<usize>> = (0..PACKET_COUNT)
+        .map(|_| random_walk(&exact_graph, &nodes, &mut rng))
+        .collect();
+
+    let settings: [(&str, usize, f64); 7] = [
+        ("exact", 0, f64::INFINITY),
+        ("width 500", 500, f64::INFINITY),
+        ("width 100", 100, f64::INFINITY),
+        ("width 20", 20, f64::INFINITY),
+        ("width 5", 5, f64::INFINITY),
+        ("margin 10", 0, 10.0),
+        ("margin 4", 0, 4.0),
+    ];
+
+    for width in [1, 2] {
+        let packets = packets_for(&walks, &nodes, width);
+        let reference = exact_graph.decode_batch(&packets, 1);
+
+        println!();
+        println!("{}-byte hashes, {} packets", width, packets.len());
+        println!("{:<12} {:>10} {:>10} {:>10} {:>10} {:>10}", "beam", "ms/packet", "correct", "= exact", "flagged", "pruned");
+
+        for (label, beam_width, beam_margin) in settings {
+            let config = DecoderConfig { beam_width, beam_margin, ..DecoderConfig::default() };
+            let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config);
+
+            let start = Instant::now();
+            let decoded = graph.decode_batch(&packets, 1);
+            let per_packet_ms = start.elapsed().as_secs_f64() * 1000.0 / packets.len() as f64;
+
+            let (mut hops, mut correct, mut same, mut flagged, mut pruned) = (0, 0, 0, 0, 0);
+            for ((walk, result), exact) in walks.iter().zip(&decoded).zip(&reference) {
+                let (Ok(path), Ok(exact)) = (result, exact) else {
+                    continue;
+                };
+                hops += walk.len();
+                correct += walk
+                    .iter()
+                    .zip(&path.hops)
+                    .filter(|&(&truth, node)| *node == PathNode::Known(truth))
+                    .count();
+                same += usize::from(path.hops == exact.hops);
+                flagged += usize::from(path.pruning_may_have_changed);
+                pruned += path.pruned_states;
+            }
+
+            println!(
+                "{:<12} {:>10.3} {:>9.1}% {:>9.1}% {:>10} {:>10}",
+                label,
+                per_packet_ms,
+                100.0 * correct as f64 / hops.max(1) as f64,
+                100.0 * same as f64 / packets.len() as f64,
+                flagged,
+                pruned / packets.len(),
+            );
+        }
+    }
+}
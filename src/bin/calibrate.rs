@@ -0,0 +1,59 @@
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use app::calibration::{self, PathLossParams};
+use app::cli;
+use app::srtm;
+
+/// Fits the log-distance path-loss model to logged neighbour receptions and writes
+/// the parameters for `--model log-distance --calibration <yaml>`.
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 4 {
+        eprintln!("Usage: {} <repeaters_csv> <receptions_csv> <output_yaml> [--srtm-dir <dir>] [--radio-config <yaml>]", args[0]);
+        std::process::exit(1);
+    }
+
+    let repeaters_path = &args[1];
+    let receptions_path = &args[2];
+    let output_path = &args[3];
+
+    let mut srtm_dir = None;
+    let mut radio_config = None;
+    for (flag, value) in cli::option_pairs(&args[4..])? {
+        match flag {
+            "--srtm-dir" => srtm_dir = Some(value),
+            "--radio-config" => radio_config = Some(value),
+            other => return Err(format!("Unknown option: {}", other).into()),
+        }
+    }
+
+    let repeaters = cli::read_repeaters(Path::new(repeaters_path))?;
+
+    let terrain = match srtm_dir {
+        Some(dir) => Some(srtm::load_terrain_around(
+            Path::new(dir),
+            &repeaters,
+            srtm::DEFAULT_TERRAIN_MARGIN_DEG,
+        )?),
+        None => None,
+    };
+
+    let budget = cli::load_link_budget(radio_config)?;
+
+    let receptions = calibration::read_receptions(Path::new(receptions_path))?;
+    let params: PathLossParams =
+        calibration::fit_path_loss(&receptions, &repeaters, terrain.as_ref(), &budget)?;
+
+    eprintln!(
+        "Fitted {} receptions: exponent {:.2}, loss at 1km {:.1}dB, sigma {:.1}dB, noise floor {:.1}dBm",
+        params.sample_count,
+        params.path_loss_exponent,
+        params.reference_loss_db,
+        params.shadowing_sigma_db,
+        params.noise_floor_dbm,
+    );
+
+    params.save(Path::new(output_path))?;
+    Ok(())
+}
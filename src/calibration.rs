@@ -0,0 +1,495 @@
+use crate::models::Repeater;
+use crate::physics::{LinkBudget, excess_path_loss_db, haversine_distance};
+use crate::propagation::link_specific_budget;
+use crate::terrain::TerrainMap;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+/// Receptions closer than this are clamped, so `log10(d)` stays finite for co-located nodes.
+const MIN_DISTANCE_KM: f64 = 0.01;
+
+/// Lower bound on the fitted shadowing (dB), so a perfect fit keeps a finite likelihood.
+const MIN_SIGMA_DB: f64 = 0.1;
+
+/// Iteration cap for each Nelder-Mead run in `fit_path_loss`.
+const MAX_FIT_ITERATIONS: usize = 5000;
+
+/// A direct reception of one repeater by a neighbour, from the repeater logs.
+///
+/// Example: tx_id,rx_id,rssi,snr
+/// Example: 0xa4b6,0x12cd,-112.0,-4.25
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Reception {
+    pub tx_id: String,
+    pub rx_id: String,
+    /// Received signal strength in dBm.
+    pub rssi: f64,
+    /// Signal-to-noise ratio in dB.
+    pub snr: f64,
+}
+
+/// Reads a reception log CSV.
+pub fn read_receptions(path: &Path) -> Result<Vec<Reception>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Failed to open receptions {}", path.display()))?;
+    let mut receptions = Vec::new();
+    for result in reader.deserialize() {
+        receptions.push(result?);
+    }
+    Ok(receptions)
+}
+
+/// Log-distance path-loss model fitted to observed receptions:
+///
+/// `PL(d) = reference_loss_db + 10 * path_loss_exponent * log10(d / 1km) + diffraction + X`
+///
+/// where `X ~ N(0, shadowing_sigma_db)` is log-normal shadowing and diffraction comes from
+/// the terrain profile, as in `physics::link_budget_cost`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathLossParams {
+    pub path_loss_exponent: f64,
+    /// Path loss at 1km (dB).
+    pub reference_loss_db: f64,
+    /// Standard deviation of the residuals around the fitted line (dB).
+    pub shadowing_sigma_db: f64,
+    /// Network-wide noise floor (dBm), averaged over all receptions.
+    pub noise_floor_dbm: f64,
+    /// Noise floor (dBm) per receiving repeater ID.
+    #[serde(default)]
+    pub noise_floor_by_repeater: BTreeMap<String, f64>,
+    /// Number of receptions used for the fit.
+    pub sample_count: usize,
+    /// The radio parameters assumed when converting RSSI to path loss.
+    pub budget: LinkBudget,
+}
+
+impl PathLossParams {
+    /// Reads parameters written by `save`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open calibration {}", path.display()))?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create calibration {}", path.display()))?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Median path loss (dB) over `distance_km`, excluding diffraction.
+    pub fn path_loss_db(&self, distance_km: f64) -> f64 {
+        self.reference_loss_db
+            + 10.0 * self.path_loss_exponent * distance_km.max(MIN_DISTANCE_KM).log10()
+    }
+
+    /// The fitting budget with the fitted noise floor and a fading spread matching the
+    /// shadowing: a logistic curve with scale `s` has standard deviation `s * pi / sqrt(3)`.
+    pub fn calibrated_budget(&self) -> LinkBudget {
+        LinkBudget {
+            noise_floor_dbm: Some(self.noise_floor_dbm),
+            fading_spread_db: self.shadowing_sigma_db * 3f64.sqrt() / std::f64::consts::PI,
+            ..self.budget.clone()
+        }
+    }
+
+    /// Noise floor of a receiving repeater: its own `NoiseFloor` column, then the
+    /// calibrated per-repeater value, then the network-wide value.
+    pub fn noise_floor_for(&self, repeater: &Repeater) -> f64 {
+        repeater
+            .noise_floor_dbm
+            .or_else(|| self.noise_floor_by_repeater.get(&repeater.id).copied())
+            .unwrap_or(self.noise_floor_dbm)
+    }
+}
+
+/// Noise power (dBm) implied by a LoRa RSSI/SNR pair.
+/// RSSI is signal plus noise, so `noise = RSSI - 10 log10(1 + 10^(SNR/10))`.
+pub fn noise_from_rssi_snr(rssi_dbm: f64, snr_db: f64) -> f64 {
+    rssi_dbm - 10.0 * (1.0 + 10f64.powf(snr_db / 10.0)).log10()
+}
+
+/// Signal power (dBm) implied by a LoRa RSSI/SNR pair, i.e. the RSSI with the noise removed:
+/// `signal = RSSI - 10 log10(1 + 10^(-SNR/10))`.
+pub fn signal_from_rssi_snr(rssi_dbm: f64, snr_db: f64) -> f64 {
+    rssi_dbm - 10.0 * (1.0 + 10f64.powf(-snr_db / 10.0)).log10()
+}
+
+/// Fits the log-distance model to `receptions` by maximum likelihood.
+///
+/// Observed loss is the `budget` EIRP plus receive gain (with per-repeater overrides) minus
+/// the signal power from `signal_from_rssi_snr`, since the RSSI includes the noise. Each
+/// reception is modelled as `observed loss - diffraction ~ N(reference + exponent * 10 log10 d,
+/// sigma)`, truncated at the largest loss the link can decode: EIRP plus receive gain minus
+/// the link's `sensitivity_dbm`. The logs only hold decoded packets, so near the range limit
+/// only links that faded up are seen; the truncation term accounts for the ones that were
+/// missed, which a plain least-squares fit would read as a lower exponent and sigma.
+///
+/// The least-squares line is the starting point for a Nelder-Mead search over
+/// `(reference, exponent, ln sigma)`. Noise floors are the mean of `noise_from_rssi_snr`
+/// overall and per receiver.
+///
+/// Receptions naming repeaters that are not in `repeaters` are skipped.
+pub fn fit_path_loss(
+    receptions: &[Reception],
+    repeaters: &[Repeater],
+    terrain: Option<&TerrainMap>,
+    budget: &LinkBudget,
+) -> Result<PathLossParams> {
+    let by_id: HashMap<&str, &Repeater> = repeaters.iter().map(|r| (r.id.as_str(), r)).collect();
+
+    let mut xs = Vec::new();
+    let mut ys = Vec::new();
+    let mut cutoffs = Vec::new();
+    let mut noise_by_rx: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+
+    for rec in receptions {
+        let (Some(&tx), Some(&rx)) = (by_id.get(rec.tx_id.as_str()), by_id.get(rec.rx_id.as_str()))
+        else {
+            continue;
+        };
+
+        let link_budget = link_specific_budget(budget, tx, rx);
+        let dist_km = haversine_distance(tx.lat, tx.lon, rx.lat, rx.lon).max(MIN_DISTANCE_KM);
+        let observed_loss_db =
+            link_budget.received_power_dbm(0.0) - signal_from_rssi_snr(rec.rssi, rec.snr);
+        let diffraction_db = excess_path_loss_db(tx.antenna(), rx.antenna(), terrain, &link_budget);
+        let max_loss_db = link_budget.received_power_dbm(0.0) - link_budget.sensitivity_dbm();
+
+        xs.push(10.0 * dist_km.log10());
+        ys.push(observed_loss_db - diffraction_db);
+        // A reception below the configured sensitivity proves the cut-off is at least that deep
+        cutoffs.push((max_loss_db - diffraction_db).max(observed_loss_db - diffraction_db));
+        noise_by_rx
+            .entry(rx.id.clone())
+            .or_default()
+            .push(noise_from_rssi_snr(rec.rssi, rec.snr));
+    }
+
+    let n = xs.len();
+    if n < 2 {
+        return Err(anyhow!("Need at least 2 usable receptions to calibrate, found {}", n));
+    }
+
+    let (reference, exponent, sigma) = least_squares(&xs, &ys)?;
+    let nll = |p: &[f64; 3]| truncated_nll(&xs, &ys, &cutoffs, p[0], p[1], p[2].exp());
+    let start = [reference, exponent, sigma.max(MIN_SIGMA_DB).ln()];
+    let step = [1.0, 0.1, 0.1];
+    // Restarting from the first optimum guards against a prematurely collapsed simplex
+    let fitted = nelder_mead(&nll, nelder_mead(&nll, start, step), step);
+
+    let all_noise: Vec<f64> = noise_by_rx.values().flatten().copied().collect();
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+
+    Ok(PathLossParams {
+        path_loss_exponent: fitted[1],
+        reference_loss_db: fitted[0],
+        shadowing_sigma_db: fitted[2].exp().max(MIN_SIGMA_DB),
+        noise_floor_dbm: mean(&all_noise),
+        noise_floor_by_repeater: noise_by_rx
+            .iter()
+            .map(|(id, v)| (id.clone(), mean(v)))
+            .collect(),
+        sample_count: n,
+        budget: budget.clone(),
+    })
+}
+
+/// Ordinary least-squares line through `(xs, ys)`: `(intercept, slope, RMS residual)`.
+fn least_squares(xs: &[f64], ys: &[f64]) -> Result<(f64, f64, f64)> {
+    let n = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let sxx: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    let sxy: f64 = xs.iter().zip(ys).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    if sxx < 1e-9 {
+        return Err(anyhow!("Receptions span a single distance; cannot fit a path-loss exponent"));
+    }
+
+    let slope = sxy / sxx;
+    let intercept = mean_y - slope * mean_x;
+    let sse: f64 = xs
+        .iter()
+        .zip(ys)
+        .map(|(x, y)| (y - (intercept + slope * x)).powi(2))
+        .sum();
+    Ok((intercept, slope, (sse / n).sqrt()))
+}
+
+/// Negative log-likelihood (up to a constant) of losses `ys` under a normal distribution
+/// around `reference + exponent * x`, truncated above at each reception's `cutoffs`.
+fn truncated_nll(xs: &[f64], ys: &[f64], cutoffs: &[f64], reference: f64, exponent: f64, sigma: f64) -> f64 {
+    let sigma = sigma.max(MIN_SIGMA_DB);
+    xs.iter()
+        .zip(ys)
+        .zip(cutoffs)
+        .map(|((x, y), c)| {
+            let median = reference + exponent * x;
+            let z = (y - median) / sigma;
+            sigma.ln() + 0.5 * z * z + ln_normal_cdf((c - median) / sigma)
+        })
+        .sum()
+}
+
+/// Natural log of the standard normal CDF, accurate far into the lower tail.
+fn ln_normal_cdf(z: f64) -> f64 {
+    let x = z / std::f64::consts::SQRT_2;
+    if x < 0.0 {
+        0.5f64.ln() + ln_erfc(-x)
+    } else {
+        (-0.5 * ln_erfc(x).exp()).ln_1p()
+    }
+}
+
+/// Natural log of the complementary error function for `x >= 0`, via the Chebyshev fit
+/// from Numerical Recipes (relative error below 1.2e-7).
+fn ln_erfc(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.5 * x);
+    let poly = -1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    t.ln() - x * x + poly
+}
+
+/// Minimises `f` with the Nelder-Mead simplex method, from a simplex spanning `step`
+/// around `start`.
+fn nelder_mead(f: &impl Fn(&[f64; 3]) -> f64, start: [f64; 3], step: [f64; 3]) -> [f64; 3] {
+    let mut simplex: Vec<([f64; 3], f64)> = (0..=3)
+        .map(|i| {
+            let mut p = start;
+            if i > 0 {
+                p[i - 1] += step[i - 1];
+            }
+            (p, f(&p))
+        })
+        .collect();
+    let towards = |from: &[f64; 3], to: &[f64; 3], t: f64| -> [f64; 3] {
+        std::array::from_fn(|k| from[k] + t * (to[k] - from[k]))
+    };
+
+    for _ in 0..MAX_FIT_ITERATIONS {
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (best, worst) = (simplex[0].1, simplex[3].1);
+        let size = simplex[1..]
+            .iter()
+            .flat_map(|(p, _)| p.iter().zip(&simplex[0].0).map(|(a, b)| (a - b).abs()))
+            .fold(0.0, f64::max);
+        if worst - best <= 1e-12 * (1.0 + best.abs()) && size < 1e-9 {
+            break;
+        }
+
+        let centroid: [f64; 3] = std::array::from_fn(|k| simplex[..3].iter().map(|(p, _)| p[k]).sum::<f64>() / 3.0);
+        let reflected = towards(&centroid, &simplex[3].0, -1.0);
+        let reflected_value = f(&reflected);
+        if reflected_value < best {
+            let expanded = towards(&centroid, &simplex[3].0, -2.0);
+            let expanded_value = f(&expanded);
+            simplex[3] = if expanded_value < reflected_value {
+                (expanded, expanded_value)
+            } else {
+                (reflected, reflected_value)
+            };
+        } else if reflected_value < simplex[2].1 {
+            simplex[3] = (reflected, reflected_value);
+        } else {
+            let contracted = towards(&centroid, &simplex[3].0, 0.5);
+            let contracted_value = f(&contracted);
+            if contracted_value < worst {
+                simplex[3] = (contracted, contracted_value);
+            } else {
+                let anchor = simplex[0].0;
+                for vertex in simplex.iter_mut().skip(1) {
+                    let p = towards(&anchor, &vertex.0, 0.5);
+                    *vertex = (p, f(&p));
+                }
+            }
+        }
+    }
+
+    simplex
+        .into_iter()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(p, _)| p)
+        .unwrap_or(start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, lat: f64, lon: f64) -> Repeater {
+        Repeater {
+            id: id.to_string(),
+            name: "Test".to_string(),
+            lat,
+            lon,
+            ..Default::default()
+        }
+    }
+
+    /// Synthesises receptions from a known log-distance model with +/-`jitter_db` shadowing,
+    /// logging the RSSI as signal plus noise at a +5dB SNR.
+    fn synthetic_receptions(
+        repeaters: &[Repeater],
+        exponent: f64,
+        reference_db: f64,
+        jitter_db: f64,
+    ) -> Vec<Reception> {
+        let budget = LinkBudget::default();
+        let hub = &repeaters[0];
+        repeaters[1..]
+            .iter()
+            .enumerate()
+            .map(|(i, rx)| {
+                let d = haversine_distance(hub.lat, hub.lon, rx.lat, rx.lon);
+                let diffraction = excess_path_loss_db(hub.antenna(), rx.antenna(), None, &budget);
+                let jitter = if i % 2 == 0 { jitter_db } else { -jitter_db };
+                let loss = reference_db + 10.0 * exponent * d.log10() + diffraction + jitter;
+                Reception {
+                    tx_id: hub.id.clone(),
+                    rx_id: rx.id.clone(),
+                    rssi: budget.received_power_dbm(loss) + 10.0 * (1.0 + 10f64.powf(-0.5)).log10(),
+                    snr: 5.0,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fit_recovers_known_parameters() {
+        let mut repeaters = vec![node("0x00", 51.0, 0.0)];
+        for i in 1..=10 {
+            // 2km .. 20km, each distance twice so the +/- jitter cancels out
+            let lat = 51.0 + i as f64 * 0.018;
+            repeaters.push(node(&format!("0x{:02x}", 2 * i), lat, 0.0));
+            repeaters.push(node(&format!("0x{:02x}", 2 * i + 1), lat, 0.0));
+        }
+
+        let receptions = synthetic_receptions(&repeaters, 3.2, 95.0, 2.0);
+        let params = fit_path_loss(&receptions, &repeaters, None, &LinkBudget::default())
+            .expect("fit failed");
+
+        assert_eq!(params.sample_count, 20);
+        // Every link is far inside the cut-off, so the likelihood fit matches least squares
+        assert!((params.path_loss_exponent - 3.2).abs() < 1e-4);
+        assert!((params.reference_loss_db - 95.0).abs() < 1e-4);
+        assert!((params.shadowing_sigma_db - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_fit_corrects_for_sensitivity_truncation() {
+        let budget = LinkBudget::default();
+        let max_loss = budget.received_power_dbm(0.0) - budget.sensitivity_dbm();
+        let (exponent, reference, sigma) = (3.2, 95.0, 8.0);
+
+        // Deterministic xorshift + Box-Muller shadowing
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut uniform = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        let mut repeaters = vec![node("0x00", 51.0, 0.0)];
+        let mut receptions = Vec::new();
+        let (mut xs, mut ys) = (Vec::new(), Vec::new());
+        for i in 1..=12 {
+            // 5km .. 60km; beyond ~40km many links fall below the sensitivity
+            let rx = node(&format!("0x{:02x}", i), 51.0 + i as f64 * 5.0 / 111.195, 0.0);
+            let d = haversine_distance(51.0, 0.0, rx.lat, rx.lon);
+            let diffraction = excess_path_loss_db(repeaters[0].antenna(), rx.antenna(), None, &budget);
+            for _ in 0..300 {
+                let gauss = (-2.0 * (1.0 - uniform()).ln()).sqrt()
+                    * (2.0 * std::f64::consts::PI * uniform()).cos();
+                let loss = reference + 10.0 * exponent * d.log10() + diffraction + sigma * gauss;
+                if loss > max_loss {
+                    continue;
+                }
+                xs.push(10.0 * d.log10());
+                ys.push(loss - diffraction);
+                receptions.push(Reception {
+                    tx_id: "0x00".into(),
+                    rx_id: rx.id.clone(),
+                    rssi: budget.received_power_dbm(loss) + 10.0 * (1.0 + 10f64.powf(-0.5)).log10(),
+                    snr: 5.0,
+                });
+            }
+            repeaters.push(rx);
+        }
+        assert!(receptions.len() < 3000, "the sample should be truncated");
+
+        let params = fit_path_loss(&receptions, &repeaters, None, &budget).unwrap();
+        assert!((params.path_loss_exponent - exponent).abs() < 0.2, "{:?}", params);
+        assert!((params.shadowing_sigma_db - sigma).abs() < 0.5, "{:?}", params);
+
+        // Least squares on the same sample reads the missing weak links as a flatter, tighter fit
+        let (_, ols_exponent, ols_sigma) = least_squares(&xs, &ys).unwrap();
+        assert!(ols_exponent < exponent - 0.3, "OLS exponent {}", ols_exponent);
+        assert!(ols_sigma < sigma - 0.5, "OLS sigma {}", ols_sigma);
+    }
+
+    #[test]
+    fn test_noise_floor_estimate() {
+        // RSSI is signal + noise: -100dBm noise with a +5dB SNR signal
+        let signal = -95.0;
+        let rssi = 10.0 * (10f64.powf(signal / 10.0) + 10f64.powf(-100.0 / 10.0)).log10();
+        assert!((noise_from_rssi_snr(rssi, 5.0) - -100.0).abs() < 1e-9);
+        assert!((signal_from_rssi_snr(rssi, 5.0) - signal).abs() < 1e-9);
+
+        let repeaters = vec![node("0x01", 51.0, 0.0), node("0x02", 51.1, 0.0), node("0x03", 51.2, 0.0)];
+        let receptions = vec![
+            Reception { tx_id: "0x01".into(), rx_id: "0x02".into(), rssi, snr: 5.0 },
+            Reception { tx_id: "0x01".into(), rx_id: "0x03".into(), rssi: -110.0, snr: -10.0 },
+            Reception { tx_id: "0x01".into(), rx_id: "0xff".into(), rssi: -90.0, snr: 10.0 },
+        ];
+        let params = fit_path_loss(&receptions, &repeaters, None, &LinkBudget::default()).unwrap();
+
+        // The reception from an unknown repeater is skipped
+        assert_eq!(params.sample_count, 2);
+        assert!((params.noise_floor_by_repeater["0x02"] - -100.0).abs() < 1e-9);
+        // At negative SNR the noise dominates the RSSI
+        assert!((params.noise_floor_by_repeater["0x03"] - -110.0).abs() < 0.5);
+        assert_eq!(params.noise_floor_for(&repeaters[1]), params.noise_floor_by_repeater["0x02"]);
+    }
+
+    #[test]
+    fn test_fit_requires_distinct_distances() {
+        let repeaters = vec![node("0x01", 51.0, 0.0), node("0x02", 51.1, 0.0)];
+        let rec = Reception { tx_id: "0x01".into(), rx_id: "0x02".into(), rssi: -100.0, snr: 0.0 };
+        let single = vec![rec.clone()];
+        let same_distance = vec![rec.clone(), rec];
+        assert!(fit_path_loss(&single, &repeaters, None, &LinkBudget::default()).is_err());
+        assert!(fit_path_loss(&same_distance, &repeaters, None, &LinkBudget::default()).is_err());
+    }
+
+    #[test]
+    fn test_save_load_round_trip() {
+        let params = PathLossParams {
+            path_loss_exponent: 3.1,
+            reference_loss_db: 98.0,
+            shadowing_sigma_db: 6.5,
+            noise_floor_dbm: -118.0,
+            noise_floor_by_repeater: BTreeMap::from([("0x12".to_string(), -110.0)]),
+            sample_count: 42,
+            budget: LinkBudget::default(),
+        };
+        let path = std::env::temp_dir().join(format!("calibration_{}.yaml", std::process::id()));
+        params.save(&path).unwrap();
+        let loaded = PathLossParams::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded, params);
+        let spread = loaded.calibrated_budget().fading_spread_db;
+        assert!((spread * std::f64::consts::PI / 3f64.sqrt() - 6.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::models::{PathHash, Repeater};
+use crate::physics::LinkBudget;
+use anyhow::{Context, Result, anyhow};
+use std::fs::File;
+use std::path::Path;
+
+/// Splits `--flag value` options into pairs for the binaries to match on.
+///
+/// Every option takes exactly one value; a trailing flag without one is an error. Unknown
+/// flags are left to the caller.
+pub fn option_pairs(args: &[String]) -> Result<Vec<(&str, &str)>> {
+    let mut pairs = Vec::new();
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        let value = iter.next().ok_or_else(|| anyhow!("Missing value for {}", flag))?;
+        pairs.push((flag.as_str(), value.as_str()));
+    }
+    Ok(pairs)
+}
+
+/// Reads the repeater database CSV.
+///
+/// Example: ID,Name,Lat,Lon
+/// Example: 0x1234,RepeaterA,34.05,-118.25
+pub fn read_repeaters(path: &Path) -> Result<Vec<Repeater>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Failed to open repeaters {}", path.display()))?;
+    let mut repeaters = Vec::new();
+    for result in reader.deserialize() {
+        let repeater: Repeater = result?;
+        if repeater.path_hash(PathHash::MAX_WIDTH).is_none() {
+            return Err(anyhow!(
+                "Repeater {:?} in {} has a malformed ID; expected hex bytes such as 0x1234",
+                repeater.id,
+                path.display()
+            ));
+        }
+        repeaters.push(repeater);
+    }
+    Ok(repeaters)
+}
+
+/// Loads the `--radio-config` YAML, or the default EU/UK budget when none is given.
+pub fn load_link_budget(path: Option<&str>) -> Result<LinkBudget> {
+    match path {
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("Failed to open radio config {}", path))?;
+            Ok(serde_yaml::from_reader(file)?)
+        }
+        None => Ok(LinkBudget::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_option_pairs() {
+        let args: Vec<String> = ["--srtm-dir", "data", "--k-best", "3"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(option_pairs(&args).unwrap(), vec![("--srtm-dir", "data"), ("--k-best", "3")]);
+
+        let missing: Vec<String> = vec!["--srtm-dir".to_string()];
+        let err = option_pairs(&missing).unwrap_err();
+        assert_eq!(err.to_string(), "Missing value for --srtm-dir");
+    }
+
+    #[test]
+    fn test_read_repeaters_rejects_malformed_ids() {
+        let path = std::env::temp_dir().join(format!("repeaters_{}.csv", std::process::id()));
+        std::fs::write(&path, "ID,Name,Lat,Lon\n0x1234,Good,51.0,0.0\n").unwrap();
+        assert_eq!(read_repeaters(&path).unwrap()[0].id, "0x1234");
+
+        for bad in ["0xzz34", "0x123", "0xé1"] {
+            std::fs::write(&path, format!("ID,Name,Lat,Lon\n0x1234,Good,51.0,0.0\n{},Bad,51.1,0.0\n", bad)).unwrap();
+            let err = read_repeaters(&path).unwrap_err();
+            assert!(err.to_string().contains("malformed ID"), "{}", err);
+        }
+        std::fs::remove_file(&path).ok();
+    }
+}
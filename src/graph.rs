@@ -1,35 +1,145 @@
-use crate::models::{PathNode, Repeater};
-use crate::physics::link_cost;
+use crate::models::{PathHash, PathNode, Repeater};
+use crate::parallel::parallel_map;
+use crate::physics::{GROUND_ANTENNA_HEIGHT_M, MAX_LINK_RANGE_KM, haversine_distance};
+use crate::propagation::{PropagationModel, SigmoidModel};
 use crate::terrain::TerrainMap;
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
 use rstar::{AABB, PointDistance, RTree, RTreeObject};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
 
-// Constants
-const MAX_LINK_RANGE_KM: f64 = 150.0;
+/// Tunable penalties and limits for graph construction and decoding.
+///
+/// Loadable from YAML; missing fields keep their defaults, so a file only needs the
+/// values being changed. Costs are Negative Log Probabilities like the link costs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecoderConfig {
+    /// Nodes further apart than this are never linked: the graph's model treats longer links
+    /// as impossible (see `PropagationModel::with_max_range_km`), anchors included.
+    pub max_link_range_km: f64,
 
-/// Maximum allowable link cost to be considered feasible.
-/// Links with costs higher than this (e.g. due to terrain obstruction) are pruned from the sparse graph.
-const MAX_FEASIBLE_LINK_COST: f64 = 1000.0;
+    /// Maximum allowable link cost to be considered feasible.
+    /// Links with costs higher than this (e.g. due to terrain obstruction) are pruned from the sparse graph.
+    pub max_feasible_link_cost: f64,
 
-/// Cost for staying in the Unknown state (Unknown -> Unknown).
-/// This is the "base" penalty for missing information.
-const COST_TRANSITION_UNKNOWN_TO_UNKNOWN: f64 = 8.0;
+    /// Cost for staying in the Unknown state (Unknown -> Unknown).
+    /// This is the "base" penalty for missing information.
+    pub cost_unknown_to_unknown: f64,
 
-/// Cost for recovering from the Unknown state to a Known node (Unknown -> Known).
-/// Slightly cheaper than staying Unknown, to encourage the path to "snap back"
-/// to the known graph as soon as a valid node is observed.
-const COST_TRANSITION_UNKNOWN_TO_KNOWN: f64 = 8.0 - 1.0e-6;
+    /// Cost for recovering from the Unknown state to a Known node (Unknown -> Known).
+    /// Slightly cheaper than staying Unknown, to encourage the path to "snap back"
+    /// to the known graph as soon as a valid node is observed.
+    pub cost_unknown_to_known: f64,
 
-/// Cost for entering the Unknown state from a Known node (Known -> Unknown).
-/// Slightly cheaper than recovery, to favor "trusting the source" (Known start)
-/// in disconnected scenarios.
-const COST_TRANSITION_KNOWN_TO_UNKNOWN: f64 = 8.0 - 2.0e-6;
+    /// Cost for entering the Unknown state from a Known node (Known -> Unknown).
+    /// Slightly cheaper than recovery, to favor "trusting the source" (Known start)
+    /// in disconnected scenarios.
+    pub cost_known_to_unknown: f64,
 
-/// Initial cost for Known nodes at the start of the path (Step 0).
-/// A negative cost acts as a bonus, ensuring we overwhelmingly prefer starting
-/// with a Known node over an Unknown wildcard if both are options.
-const COST_START_KNOWN: f64 = -0.1;
+    /// How far one Unknown hop is assumed to reach. A Known node following a run of `n`
+    /// Unknown hops is free within `(n + 1)` times this of the last Known hop, so
+    /// K -> U -> K is only cheap within plausible two-hop range.
+    pub ghost_hop_range_km: f64,
+
+    /// Cost per `ghost_hop_range_km` by which a Known node lies beyond the reach of the
+    /// Unknown hops before it. The default charges about one more Unknown hop per extra
+    /// hop of distance; `.inf` makes the reach a hard cutoff.
+    pub ghost_overreach_cost: f64,
+
+    /// Extra cost per hop that revisits a repeater already on the path: a bounce
+    /// (A -> B -> A) or any longer loop (A -> B -> C -> A). MeshCore repeaters drop packets
+    /// they have already relayed, so by default (`.inf`) revisits are ruled out whenever one
+    /// of the `revisit_k` best paths avoids them; 0 disables the check.
+    pub revisit_penalty: f64,
+
+    /// Most alternative paths to consider when the best path has a revisit. The list is
+    /// grown from 2 and stops as soon as more paths could not change the outcome.
+    pub revisit_k: usize,
+
+    /// Probability that a logged path hash is corrupted. When non-zero, a hop may be decoded
+    /// as a node whose hash differs from the observed one, at `-ln(p / (256^width - 1))`
+    /// extra (any other value is equally likely): a neighbour of a Known hop, a node the
+    /// origin reaches (any node without an origin) on the first hop, or a node within an
+    /// Unknown run's free reach after it (any node for runs from the start). 0 disables.
+    /// The first-hop and after-Unknown candidates enlarge the trellis considerably; consider
+    /// `beam_width` on large graphs.
+    pub substitution_probability: f64,
+
+    /// Probability that a repeater relayed the packet without its hash appearing in the
+    /// path (e.g. a capture truncated at the 64-byte limit). When non-zero, a Known hop may
+    /// be followed by a Known node it has no link to, and the last hop may miss the observer,
+    /// at `-ln(p)` plus the link costs of both legs through an unlogged repeater assumed to
+    /// sit halfway between them. 0 disables.
+    pub skipped_hop_probability: f64,
+
+    /// Beam pruning: keep at most this many trellis states per step, cheapest first.
+    /// 0 keeps them all. Pruning trades exactness for speed on very large graphs, and
+    /// applies to the K-best and posterior passes as well as plain Viterbi.
+    pub beam_width: usize,
+
+    /// Beam pruning: drop trellis states costing more than the step's best plus this.
+    /// `.inf` keeps them all.
+    pub beam_margin: f64,
+
+    /// Initial cost for Known nodes at the start of the path (Step 0).
+    /// A negative cost acts as a bonus, ensuring we overwhelmingly prefer starting
+    /// with a Known node over an Unknown wildcard if both are options.
+    pub cost_start_known: f64,
+}
+
+impl Default for DecoderConfig {
+    fn default() -> Self {
+        DecoderConfig {
+            max_link_range_km: MAX_LINK_RANGE_KM,
+            max_feasible_link_cost: 1000.0,
+            cost_unknown_to_unknown: 8.0,
+            cost_unknown_to_known: 8.0 - 1.0e-6,
+            cost_known_to_unknown: 8.0 - 2.0e-6,
+            ghost_hop_range_km: 100.0,
+            ghost_overreach_cost: 8.0,
+            revisit_penalty: f64::INFINITY,
+            revisit_k: 16,
+            substitution_probability: 0.0,
+            skipped_hop_probability: 0.0,
+            beam_width: 0,
+            beam_margin: f64::INFINITY,
+            cost_start_known: -0.1,
+        }
+    }
+}
+
+impl DecoderConfig {
+    /// Reads a config from a YAML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open decoder config {}", path.display()))?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    /// Extra cost of decoding a hop whose hash differs from the observed `width`-byte one,
+    /// relative to an exact match. INFINITY when substitutions are disabled.
+    fn substitution_cost(&self, width: u8) -> f64 {
+        let p = self.substitution_probability;
+        if p <= 0.0 {
+            return f64::INFINITY;
+        }
+        let other_values = 256f64.powi(width as i32) - 1.0;
+        -(p / other_values).ln() + (1.0 - p).ln()
+    }
+
+    /// Cost of one repeater missing from the path. INFINITY when skips are disabled.
+    fn skipped_hop_cost(&self) -> f64 {
+        let p = self.skipped_hop_probability;
+        if p <= 0.0 {
+            return f64::INFINITY;
+        }
+        -p.ln()
+    }
+}
 
 /// A wrapper around a repeater index that can be stored in the R-Tree.
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -51,27 +161,174 @@ impl PointDistance for SpatialNode {
     fn distance_2(&self, point: &[f64; 2]) -> f64 {
         // Use Haversine distance squared for accurate spatial queries.
         // point is [lon, lat]
-        let dist = crate::physics::haversine_distance(self.lat, self.lon, point[1], point[0]);
+        let dist = haversine_distance(self.lat, self.lon, point[1], point[0]);
         dist * dist
     }
 }
 
+/// Half-widths (lat, lon) in degrees of a search box holding every point within `range_km`
+/// of latitude `lat`, with a 20% margin. A degree of longitude shrinks by cos(lat).
+fn search_radius_deg(lat: f64, range_km: f64) -> (f64, f64) {
+    let lat_radius_deg = (range_km / 111.0) * 1.2;
+    let lon_radius_deg = lat_radius_deg / lat.to_radians().cos().max(1e-6);
+    (lat_radius_deg, lon_radius_deg.min(360.0))
+}
+
+/// All hops of one packet are logged at the same width (see `PathHash`).
+fn check_widths(observations: &[PathHash]) -> Result<()> {
+    if observations.windows(2).any(|w| w[0].width != w[1].width) {
+        return Err(anyhow!("Mixed path hash widths in one path"));
+    }
+    Ok(())
+}
+
+/// A fixed point with known coordinates at one end of a packet's path,
+/// e.g. the originating node or the observer that logged the packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Anchor {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Anchor {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        Anchor { lat, lon }
+    }
+
+    /// A stand-in repeater at the anchor, so links to it can be costed by the propagation model.
+    /// Anchors are usually handhelds, so the antenna sits at `GROUND_ANTENNA_HEIGHT_M`
+    /// rather than on a repeater mast.
+    pub(crate) fn as_repeater(&self) -> Repeater {
+        Repeater {
+            id: "anchor".to_string(),
+            name: "Anchor".to_string(),
+            lat: self.lat,
+            lon: self.lon,
+            antenna_height_m: Some(GROUND_ANTENNA_HEIGHT_M),
+            ..Default::default()
+        }
+    }
+}
+
+/// A packet to decode: its logged path and the anchors at either end, if known.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PacketPath {
+    pub hashes: Vec<PathHash>,
+    pub origin: Option<Anchor>,
+    pub observer: Option<Anchor>,
+}
+
+/// Where a ghost (Unknown) state was last pinned to a known position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum GhostAnchor {
+    /// The last Known hop.
+    Node(usize),
+    /// Unknown since the start of the path. The origin, if any, only rules out Known first
+    /// hops it cannot reach: it is a handheld's fix, too loose to bound a run of Unknowns.
+    Start,
+}
+
+/// A state in the Viterbi trellis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum State {
+    Known(usize),
+    /// An unknown repeater, `hops` Unknown hops after its `anchor`. Carrying the anchor
+    /// stops a run of Unknowns from "teleporting" the path across the map.
+    Ghost { anchor: GhostAnchor, hops: usize },
+}
+
+impl State {
+    fn is_known(&self) -> bool {
+        matches!(self, State::Known(_))
+    }
+
+    fn known_index(&self) -> Option<usize> {
+        match self {
+            State::Known(idx) => Some(*idx),
+            State::Ghost { .. } => None,
+        }
+    }
+}
+
+/// The anchors of the packet being decoded.
+struct Ends {
+    origin: Option<Repeater>,
+    observer: Option<Repeater>,
+}
+
+impl Ends {
+    fn new(origin: Option<Anchor>, observer: Option<Anchor>) -> Self {
+        Ends {
+            origin: origin.map(|a| a.as_repeater()),
+            observer: observer.map(|a| a.as_repeater()),
+        }
+    }
+}
+
 pub struct NetworkGraph {
+    /// Database repeaters, followed by any inferred ones (see `set_inferred_nodes`).
     nodes: Vec<Repeater>,
-    /// Adjacency list: nodes[i] -> list of (neighbor_index, cost)
+    /// Number of database repeaters at the front of `nodes`.
+    database_count: usize,
+    /// Directed adjacency list: nodes[i] -> list of (neighbor_index, cost of i transmitting to neighbor).
+    /// Costs need not be symmetric, so j -> i is stored separately in adjacency[j].
     adjacency: Vec<Vec<(usize, f64)>>,
-    /// Lookup: prefix (0-255) -> list of node indices
-    nodes_by_prefix: Vec<Vec<usize>>,
+    /// Path hash of each node at `PathHash::MAX_WIDTH`, so hops of any width can be matched.
+    node_hashes: Vec<Option<PathHash>>,
+    /// Lookup: path hash (every width from 1 to `PathHash::MAX_WIDTH`) -> list of node indices
+    nodes_by_prefix: HashMap<PathHash, Vec<usize>>,
+    /// Spatial index over `nodes`, for substitution candidates near a position.
+    rtree: RTree<SpatialNode>,
+    config: DecoderConfig,
+    /// Kept to cost links to packet anchors at decode time.
+    model: Box<dyn PropagationModel>,
+    terrain: Option<Arc<TerrainMap>>,
 }
 
 impl NetworkGraph {
-    /// Creates a new NetworkGraph.
+    /// Creates a new NetworkGraph using the default `SigmoidModel` for link costs.
+    pub fn new(nodes: Vec<Repeater>, terrain: Option<Arc<TerrainMap>>) -> Self {
+        Self::with_model(nodes, terrain, &SigmoidModel::default())
+    }
+
+    /// Creates a new NetworkGraph with link costs from the given propagation model
+    /// and the default `DecoderConfig`.
+    pub fn with_model(
+        nodes: Vec<Repeater>,
+        terrain: Option<Arc<TerrainMap>>,
+        model: &dyn PropagationModel,
+    ) -> Self {
+        Self::with_config(nodes, terrain, model, DecoderConfig::default())
+    }
+
+    /// Creates a new NetworkGraph with the given propagation model and decoder config.
+    ///
+    /// The graph keeps a copy of the model (see `PropagationModel::box_clone`) and shares
+    /// the terrain, to cost links to packet anchors at decode time.
+    pub fn with_config(
+        nodes: Vec<Repeater>,
+        terrain: Option<Arc<TerrainMap>>,
+        model: &dyn PropagationModel,
+        config: DecoderConfig,
+    ) -> Self {
+        Self::with_shared_terrain(nodes, terrain, model.box_clone(), config)
+    }
+
+    /// Creates a new NetworkGraph that shares `terrain` and takes ownership of `model`.
     /// * Builds the R-Tree for spatial indexing.
-    /// * Pre-calculates the sparse adjacency matrix by finding neighbors within MAX_LINK_RANGE_KM
+    /// * Limits the model to `max_link_range_km`, for anchor links at decode time as well.
+    /// * Pre-calculates the sparse adjacency matrix by finding neighbors within `max_link_range_km`
     ///   and pruning links that are blocked by terrain or physically infeasible.
-    pub fn new(nodes: Vec<Repeater>, terrain: Option<&TerrainMap>) -> Self {
+    pub fn with_shared_terrain(
+        nodes: Vec<Repeater>,
+        terrain: Option<Arc<TerrainMap>>,
+        model: Box<dyn PropagationModel>,
+        config: DecoderConfig,
+    ) -> Self {
+        let model = model.with_max_range_km(config.max_link_range_km);
         let mut rtree_nodes = Vec::with_capacity(nodes.len());
-        let mut nodes_by_prefix = vec![Vec::new(); 256];
+        let mut node_hashes = Vec::with_capacity(nodes.len());
+        let mut nodes_by_prefix: HashMap<PathHash, Vec<usize>> = HashMap::new();
 
         for (i, node) in nodes.iter().enumerate() {
             rtree_nodes.push(SpatialNode {
@@ -79,7 +336,12 @@ impl NetworkGraph {
                 lat: node.lat,
                 lon: node.lon,
             });
-            nodes_by_prefix[node.prefix() as usize].push(i);
+            // A malformed ID has no hash and never matches a hop
+            let hash = node.path_hash(PathHash::MAX_WIDTH);
+            for prefix in hash.iter().flat_map(|h| (1..=h.width).map(|width| h.truncate(width))) {
+                nodes_by_prefix.entry(prefix).or_default().push(i);
+            }
+            node_hashes.push(hash);
         }
 
         let rtree = RTree::bulk_load(rtree_nodes);
@@ -87,11 +349,11 @@ impl NetworkGraph {
         let mut adjacency: Vec<Vec<(usize, f64)>> = vec![Vec::new(); nodes.len()];
 
         for (i, node) in nodes.iter().enumerate() {
-            let search_radius_deg = (MAX_LINK_RANGE_KM / 111.0) * 1.2;
-            let lat_min = node.lat - search_radius_deg;
-            let lat_max = node.lat + search_radius_deg;
-            let lon_min = node.lon - search_radius_deg;
-            let lon_max = node.lon + search_radius_deg;
+            let (lat_radius_deg, lon_radius_deg) = search_radius_deg(node.lat, config.max_link_range_km);
+            let lat_min = node.lat - lat_radius_deg;
+            let lat_max = node.lat + lat_radius_deg;
+            let lon_min = node.lon - lon_radius_deg;
+            let lon_max = node.lon + lon_radius_deg;
 
             let envelope = AABB::from_corners([lon_min, lat_min], [lon_max, lat_max]);
 
@@ -100,116 +362,493 @@ impl NetworkGraph {
                 if i == j {
                     continue;
                 }
-                let neighbor_node = &nodes[j];
-                let cost = link_cost(
-                    node.lat,
-                    node.lon,
-                    neighbor_node.lat,
-                    neighbor_node.lon,
-                    terrain,
-                );
-                 if cost.is_finite() && cost < MAX_FEASIBLE_LINK_COST {
+                let cost = model.link_cost(node, &nodes[j], terrain.as_deref());
+                 if cost.is_finite() && cost < config.max_feasible_link_cost {
                     adjacency[i].push((j, cost));
                 }
             }
         }
 
         NetworkGraph {
+            database_count: nodes.len(),
             nodes,
             adjacency,
+            node_hashes,
             nodes_by_prefix,
+            rtree,
+            config,
+            model,
+            terrain,
+        }
+    }
+
+    pub fn config(&self) -> &DecoderConfig {
+        &self.config
+    }
+
+    /// All nodes, indexed like `PathNode::Known`: database repeaters first, then inferred ones.
+    pub fn nodes(&self) -> &[Repeater] {
+        &self.nodes
+    }
+
+    /// Number of inferred repeaters at the end of `nodes()`.
+    pub fn inferred_count(&self) -> usize {
+        self.nodes.len() - self.database_count
+    }
+
+    /// Terrain the graph's links are costed over, if any.
+    pub fn terrain(&self) -> Option<&TerrainMap> {
+        self.terrain.as_deref()
+    }
+
+    /// The propagation model the graph's links are costed with.
+    pub fn model(&self) -> &dyn PropagationModel {
+        self.model.as_ref()
+    }
+
+    /// Cost of a transmission from node `from` being heard by node `to`,
+    /// or `None` if the link was pruned as infeasible.
+    pub fn link_cost(&self, from: usize, to: usize) -> Option<f64> {
+        self.adjacency
+            .get(from)?
+            .iter()
+            .find(|&&(j, _)| j == to)
+            .map(|&(_, cost)| cost)
+    }
+
+    /// Total number of directed edges kept in the sparse adjacency list.
+    pub fn edge_count(&self) -> usize {
+        self.adjacency.iter().map(Vec::len).sum()
+    }
+
+    /// Number of directed edges into or out of an inferred repeater.
+    pub fn inferred_edge_count(&self) -> usize {
+        let base = self.database_count;
+        self.adjacency[base..].iter().map(Vec::len).sum::<usize>()
+            + self.adjacency[..base]
+                .iter()
+                .map(|links| links.iter().filter(|&&(j, _)| j >= base).count())
+                .sum::<usize>()
+    }
+
+    /// Replaces the graph's inferred repeaters with `inferred`, flagging each one as
+    /// `Repeater::inferred`. They are appended after the database repeaters and linked like
+    /// any other node, so the decoder can resolve hops to them.
+    pub fn set_inferred_nodes(&mut self, inferred: Vec<Repeater>) {
+        // Drop the previous inferred nodes and every link to them
+        let base = self.database_count;
+        self.nodes.truncate(base);
+        self.node_hashes.truncate(base);
+        self.adjacency.truncate(base);
+        for links in &mut self.adjacency {
+            links.retain(|&(j, _)| j < base);
+        }
+        for indices in self.nodes_by_prefix.values_mut() {
+            indices.retain(|&i| i < base);
+        }
+        self.nodes_by_prefix.retain(|_, indices| !indices.is_empty());
+
+        for mut node in inferred {
+            node.inferred = true;
+            let i = self.nodes.len();
+            let hash = node.path_hash(PathHash::MAX_WIDTH);
+            for prefix in hash.iter().flat_map(|h| (1..=h.width).map(|width| h.truncate(width))) {
+                self.nodes_by_prefix.entry(prefix).or_default().push(i);
+            }
+            self.node_hashes.push(hash);
+            self.nodes.push(node);
+            self.adjacency.push(Vec::new());
+        }
+
+        // Link the new nodes in both directions, with the same search box as `with_config`
+        for i in base..self.nodes.len() {
+            let (lat_radius_deg, lon_radius_deg) =
+                search_radius_deg(self.nodes[i].lat, self.config.max_link_range_km);
+            for j in 0..self.nodes.len() {
+                let (node, other) = (&self.nodes[i], &self.nodes[j]);
+                if i == j
+                    || (node.lat - other.lat).abs() > lat_radius_deg
+                    || (node.lon - other.lon).abs() > lon_radius_deg
+                {
+                    continue;
+                }
+                let cost = self.model.link_cost(node, other, self.terrain());
+                if cost.is_finite() && cost < self.config.max_feasible_link_cost {
+                    self.adjacency[i].push((j, cost));
+                }
+                // Links between two inferred nodes are added from both ends of this loop
+                if j < base {
+                    let cost = self.model.link_cost(other, node, self.terrain());
+                    if cost.is_finite() && cost < self.config.max_feasible_link_cost {
+                        self.adjacency[j].push((i, cost));
+                    }
+                }
+            }
+        }
+
+        self.rtree = RTree::bulk_load(
+            self.nodes
+                .iter()
+                .enumerate()
+                .map(|(index, node)| SpatialNode { index, lat: node.lat, lon: node.lon })
+                .collect(),
+        );
+    }
+
+    /// Nodes within `range_km` of (lat, lon).
+    fn nodes_within(&self, lat: f64, lon: f64, range_km: f64) -> Vec<usize> {
+        let (lat_radius_deg, lon_radius_deg) = search_radius_deg(lat, range_km);
+        let envelope = AABB::from_corners(
+            [lon - lon_radius_deg, lat - lat_radius_deg],
+            [lon + lon_radius_deg, lat + lat_radius_deg],
+        );
+        self.rtree
+            .locate_in_envelope(&envelope)
+            .filter(|n| haversine_distance(lat, lon, n.lat, n.lon) <= range_km)
+            .map(|n| n.index)
+            .collect()
+    }
+
+    /// Known nodes whose path hash matches the observed hop.
+    fn nodes_with_hash(&self, obs: PathHash) -> &[usize] {
+        self.nodes_by_prefix.get(&obs).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether node `idx` would log the observed hop.
+    fn node_matches(&self, idx: usize, obs: PathHash) -> bool {
+        self.node_hashes[idx].is_some_and(|hash| obs.matches(hash))
+    }
+
+    /// Link cost between an anchor and a known node, or INFINITY if infeasible.
+    /// `to_anchor` selects the direction: node -> anchor (observer) or anchor -> node (origin).
+    fn anchor_link_cost(&self, anchor: &Repeater, node_idx: usize, to_anchor: bool) -> f64 {
+        let node = &self.nodes[node_idx];
+        let cost = if to_anchor {
+            self.model.link_cost(node, anchor, self.terrain())
+        } else {
+            self.model.link_cost(anchor, node, self.terrain())
+        };
+        if cost.is_finite() && cost < self.config.max_feasible_link_cost {
+            cost
+        } else {
+            f64::INFINITY
+        }
+    }
+
+    /// Beam pruning of one trellis step (see `DecoderConfig::beam_width` and `beam_margin`).
+    /// Returns the number of states dropped and the cheapest dropped cost (INFINITY if none).
+    fn prune_beam<V>(&self, states: &mut HashMap<State, V>, cost: impl Fn(&V) -> f64) -> (usize, f64) {
+        let width = self.config.beam_width;
+        let margin = self.config.beam_margin;
+        if states.is_empty() || ((width == 0 || states.len() <= width) && margin.is_infinite()) {
+            return (0, f64::INFINITY);
+        }
+
+        // Sort by cost, then state, so ties are pruned the same way every run
+        let mut ranked: Vec<(f64, State)> = states.iter().map(|(&state, v)| (cost(v), state)).collect();
+        ranked.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let limit = ranked[0].0 + margin;
+        let keep = ranked
+            .iter()
+            .enumerate()
+            .position(|(rank, &(c, _))| (width > 0 && rank >= width) || c > limit)
+            .unwrap_or(ranked.len())
+            .max(1);
+        for (_, state) in &ranked[keep..] {
+            states.remove(state);
+        }
+        (ranked.len() - keep, ranked.get(keep).map_or(f64::INFINITY, |r| r.0))
+    }
+
+    /// Cost of `from` reaching `to` through one repeater missing from the path, taken to
+    /// sit halfway between them: the two legs' link costs, or INFINITY if either is infeasible.
+    fn relay_legs_cost(&self, from: &Repeater, to: &Repeater) -> f64 {
+        let relay = Repeater {
+            id: "relay".to_string(),
+            name: "Relay".to_string(),
+            lat: (from.lat + to.lat) / 2.0,
+            lon: (from.lon + to.lon) / 2.0,
+            ..Default::default()
+        };
+        let mut cost = 0.0;
+        for (tx, rx) in [(from, &relay), (&relay, to)] {
+            let leg = self.model.link_cost(tx, rx, self.terrain());
+            if !leg.is_finite() || leg >= self.config.max_feasible_link_cost {
+                return f64::INFINITY;
+            }
+            cost += leg;
+        }
+        cost
+    }
+
+    /// Step 0 of the trellis: initial costs of the states matching the first prefix,
+    /// plus, when substitutions are enabled, the states that would have to misreport it.
+    fn initial_costs(&self, first_obs: PathHash, ends: &Ends) -> HashMap<State, f64> {
+        let mut costs = HashMap::new();
+        let substitution_c = self.config.substitution_cost(first_obs.width);
+
+        match &ends.origin {
+            None => {
+                // Initialize Known nodes matching first prefix
+                for &node_idx in self.nodes_with_hash(first_obs) {
+                    costs.insert(State::Known(node_idx), self.config.cost_start_known);
+                }
+
+                // Without an origin any node could have sent a corrupted first hash
+                if substitution_c.is_finite() {
+                    for node_idx in 0..self.nodes.len() {
+                        if !self.node_matches(node_idx, first_obs) {
+                            costs.insert(State::Known(node_idx), self.config.cost_start_known + substitution_c);
+                        }
+                    }
+                }
+
+                // Initialize Unknown state, with nothing to pin it down
+                costs.insert(State::Ghost { anchor: GhostAnchor::Start, hops: 1 }, 0.0);
+            }
+            Some(origin) => {
+                // Origin -> Known, only for candidates the origin can actually reach
+                for &node_idx in self.nodes_with_hash(first_obs) {
+                    let cost = self.anchor_link_cost(origin, node_idx, false);
+                    if cost.is_finite() {
+                        costs.insert(State::Known(node_idx), cost);
+                    }
+                }
+                if substitution_c.is_finite() {
+                    let range_km = self.config.max_link_range_km;
+                    for node_idx in self.nodes_within(origin.lat, origin.lon, range_km) {
+                        if self.node_matches(node_idx, first_obs) {
+                            continue;
+                        }
+                        let cost = self.anchor_link_cost(origin, node_idx, false) + substitution_c;
+                        if cost.is_finite() {
+                            costs.insert(State::Known(node_idx), cost);
+                        }
+                    }
+                }
+
+                // Origin -> Unknown
+                costs.insert(
+                    State::Ghost { anchor: GhostAnchor::Start, hops: 1 },
+                    self.config.cost_known_to_unknown,
+                );
+            }
+        }
+
+        costs
+    }
+
+    /// All transitions out of `prev` into states matching the prefix `obs`,
+    /// as (next_state, transition_cost).
+    fn transitions(&self, prev: State, obs: PathHash) -> Vec<(State, f64)> {
+        let mut out = Vec::new();
+
+        match prev {
+            // Case 1: Previous state was Known
+            State::Known(prev_idx) => {
+                // 1a. Transition: Known -> Known
+                // Use sparse adjacency list to find reachable neighbors.
+                // Observations are in travel order, so the link is scored prev -> next.
+                let substitution_c = self.config.substitution_cost(obs.width);
+                if let Some(neighbors) = self.adjacency.get(prev_idx) {
+                    for &(neighbor_idx, link_c) in neighbors {
+                        // Filter: Neighbor must match observation prefix,
+                        // unless the observed hash may have been corrupted
+                        if self.node_matches(neighbor_idx, obs) {
+                            out.push((State::Known(neighbor_idx), link_c));
+                        } else if substitution_c.is_finite() {
+                            out.push((State::Known(neighbor_idx), link_c + substitution_c));
+                        }
+                    }
+                }
+
+                // 1a'. Transition: Known -> (unlogged repeater) -> Known
+                // Only for nodes with no direct link, so each target appears once
+                let skip_c = self.config.skipped_hop_cost();
+                if skip_c.is_finite() {
+                    for &curr_idx in self.nodes_with_hash(obs) {
+                        if curr_idx == prev_idx || self.link_cost(prev_idx, curr_idx).is_some() {
+                            continue;
+                        }
+                        let cost = skip_c + self.relay_legs_cost(&self.nodes[prev_idx], &self.nodes[curr_idx]);
+                        if cost.is_finite() {
+                            out.push((State::Known(curr_idx), cost));
+                        }
+                    }
+                }
+
+                // 1b. Transition: Known -> Unknown, remembering where we left the known graph
+                let ghost = State::Ghost { anchor: GhostAnchor::Node(prev_idx), hops: 1 };
+                out.push((ghost, self.config.cost_known_to_unknown));
+            }
+            // Case 2: Previous state was Unknown
+            State::Ghost { anchor, hops } => {
+                // 2a. Transition: Unknown -> Known
+                // Snap to any Known node matching the prefix, paying for however far it lies
+                // beyond what the chain of unknown hops could plausibly have reached
+                for &curr_idx in self.nodes_with_hash(obs) {
+                    let node = &self.nodes[curr_idx];
+                    let cost = self.config.cost_unknown_to_known
+                        + self.ghost_reach_cost(anchor, hops, node.lat, node.lon);
+                    if cost.is_finite() {
+                        out.push((State::Known(curr_idx), cost));
+                    }
+                }
+
+                // 2a'. Transition: Unknown -> Known, with the observed hash corrupted.
+                // Only nodes the ghost reaches for free, so the candidates stay local.
+                let substitution_c = self.config.substitution_cost(obs.width);
+                if substitution_c.is_finite() {
+                    let candidates = match anchor {
+                        GhostAnchor::Node(idx) => {
+                            let node = &self.nodes[idx];
+                            let range_km = (hops + 1) as f64 * self.config.ghost_hop_range_km;
+                            self.nodes_within(node.lat, node.lon, range_km)
+                        }
+                        GhostAnchor::Start => (0..self.nodes.len()).collect(),
+                    };
+                    for curr_idx in candidates {
+                        if !self.node_matches(curr_idx, obs) {
+                            let cost = self.config.cost_unknown_to_known + substitution_c;
+                            out.push((State::Known(curr_idx), cost));
+                        }
+                    }
+                }
+
+                // 2b. Transition: Unknown -> Unknown
+                let ghost = State::Ghost { anchor, hops: hops + 1 };
+                out.push((ghost, self.config.cost_unknown_to_unknown));
+            }
+        }
+
+        out
+    }
+
+    /// Extra cost of a node at (lat, lon) following a ghost `hops` unknown hops after `anchor`.
+    /// One more hop must cover the rest, so it is free within `(hops + 1) * ghost_hop_range_km`
+    /// of the anchor and costs `ghost_overreach_cost` per `ghost_hop_range_km` beyond that.
+    /// Ghosts anchored at the start reach anywhere.
+    fn ghost_reach_cost(&self, anchor: GhostAnchor, hops: usize, lat: f64, lon: f64) -> f64 {
+        let GhostAnchor::Node(idx) = anchor else {
+            return 0.0;
+        };
+        let range_km = self.config.ghost_hop_range_km;
+        let dist_km = haversine_distance(self.nodes[idx].lat, self.nodes[idx].lon, lat, lon);
+        let overreach_km = dist_km - (hops + 1) as f64 * range_km;
+        if overreach_km <= 0.0 {
+            return 0.0;
+        }
+        self.config.ghost_overreach_cost * overreach_km / range_km
+    }
+
+    /// Cost of leaving the trellis from `state` after the last hop:
+    /// the link to the observer if there is one, otherwise nothing.
+    fn final_cost(&self, state: State, ends: &Ends) -> f64 {
+        let Some(observer) = &ends.observer else {
+            return 0.0;
+        };
+        match state {
+            // Last hop -> Observer, possibly via one unlogged repeater
+            State::Known(idx) => {
+                let direct = self.anchor_link_cost(observer, idx, true);
+                let skip_c = self.config.skipped_hop_cost();
+                if direct.is_infinite() && skip_c.is_finite() {
+                    skip_c + self.relay_legs_cost(&self.nodes[idx], observer)
+                } else {
+                    direct
+                }
+            }
+            State::Ghost { anchor, hops } => {
+                self.config.cost_unknown_to_known
+                    + self.ghost_reach_cost(anchor, hops, observer.lat, observer.lon)
+            }
+        }
+    }
+
+    fn to_path_node(&self, state: State, obs: PathHash) -> PathNode {
+        match state {
+            State::Known(idx) => PathNode::Known(idx),
+            State::Ghost { .. } => PathNode::Unknown(obs),
         }
     }
 
     /// Decodes a path using the sparse graph and dynamic trellis expansion.
     /// Uses HashMaps to track only reachable states at each step.
-    pub fn decode_path(&self, observations: &[u8]) -> Result<Vec<PathNode>> {
+    pub fn decode_path(&self, observations: &[PathHash]) -> Result<DecodedPath> {
+        self.decode_path_with_anchors(observations, None, None)
+    }
+
+    /// Decodes a path whose ends are pinned by the packet's `origin` and `observer`.
+    ///
+    /// The anchors act as virtual Known nodes before the first hop and after the last:
+    /// a Known first hop costs its link from the origin (instead of `cost_start_known`)
+    /// and a Known last hop its link to the observer, so candidates out of range of
+    /// either end are ruled out. Unknown hops pay the usual Known <-> Unknown transitions.
+    ///
+    /// If the best path visits a repeater twice, the K best are re-ranked with
+    /// `DecoderConfig::revisit_penalty` (the Viterbi state has no memory of earlier hops).
+    ///
+    /// With `substitution_probability` or `skipped_hop_probability` set, hops decoded
+    /// despite a mismatched byte or a missing link are flagged in the result.
+    ///
+    /// All hops of a packet share one hash width; mixed widths are an error.
+    pub fn decode_path_with_anchors(
+        &self,
+        observations: &[PathHash],
+        origin: Option<Anchor>,
+        observer: Option<Anchor>,
+    ) -> Result<DecodedPath> {
+        check_widths(observations)?;
         if observations.is_empty() {
-            return Ok(Vec::new());
+            return Ok(DecodedPath::default());
         }
 
         let t_steps = observations.len();
-        let unknown_state_idx = self.nodes.len(); // Special index for Unknown state
 
-        // Trellis: [step] -> { state_idx -> (cost, prev_state_idx) }
-        // prev_state_idx is stored to allow backtracking.
+        let ends = Ends::new(origin, observer);
+
+        // Trellis: [step] -> { state -> (cost, prev_state) }
+        // prev_state is stored to allow backtracking.
         // We only need the current 'cost' map to compute next step,
         // but we need the FULL history of backpointers to reconstruct the path.
         // So:
-        // `current_costs`: HashMap<usize, f64> (Active states and their costs)
-        // `backpointers`: Vec<HashMap<usize, usize>> (History of transitions)
+        // `current_costs`: HashMap<State, f64> (Active states and their costs)
+        // `backpointers`: Vec<HashMap<State, State>> (History of transitions)
 
-        let mut backpointers: Vec<HashMap<usize, usize>> = Vec::with_capacity(t_steps);
+        let mut backpointers: Vec<HashMap<State, State>> = Vec::with_capacity(t_steps);
         // Step 0 initialization (no backpointers)
         backpointers.push(HashMap::new());
 
-        let mut current_costs: HashMap<usize, f64> = HashMap::new();
+        let start_costs = self.initial_costs(observations[0], &ends);
+        let mut current_costs = start_costs.clone();
 
-        let first_obs = observations[0];
+        // Beam pruning bookkeeping: how many states were dropped, and the cheapest of them
+        let (mut pruned_states, mut min_pruned_cost) = self.prune_beam(&mut current_costs, |&c| c);
 
-        // Initialize Known nodes matching first prefix
-        for &node_idx in &self.nodes_by_prefix[first_obs as usize] {
-            current_costs.insert(node_idx, COST_START_KNOWN);
-        }
-
-        // Initialize Unknown state
-        current_costs.insert(unknown_state_idx, 0.0);
+        // Known candidates alive at each step, for diagnostics
+        let count_known = |costs: &HashMap<State, f64>| costs.keys().filter(|s| s.is_known()).count();
+        let mut candidates = Vec::with_capacity(t_steps);
+        candidates.push(count_known(&current_costs));
 
         // Forward Pass
-        for t in 1..t_steps {
-            let obs = observations[t];
-            let mut next_costs: HashMap<usize, f64> = HashMap::new();
-            let mut step_backpointers: HashMap<usize, usize> = HashMap::new();
+        for (t, &obs) in observations.iter().enumerate().skip(1) {
+            let mut next_costs: HashMap<State, f64> = HashMap::new();
+            let mut step_backpointers: HashMap<State, State> = HashMap::new();
 
             // Iterate over all active states from previous step
             for (&prev_idx, &prev_cost) in &current_costs {
-                // Case 1: Previous state was Known
-                if prev_idx < unknown_state_idx {
-                    // 1a. Transition: Known -> Known
-                    // Use sparse adjacency list to find reachable neighbors
-                    if let Some(neighbors) = self.adjacency.get(prev_idx) {
-                        for &(neighbor_idx, link_c) in neighbors {
-                            // Filter: Neighbor must match observation prefix
-                            if self.nodes[neighbor_idx].prefix() == obs {
-                                let total_c = prev_cost + link_c;
-
-                                // Update if this path is cheaper
-                                let entry = next_costs.entry(neighbor_idx).or_insert(f64::INFINITY);
-                                if total_c < *entry {
-                                    *entry = total_c;
-                                    step_backpointers.insert(neighbor_idx, prev_idx);
-                                }
-                            }
-                        }
-                    }
-
-                    // 1b. Transition: Known -> Unknown
-                    let unknown_cost = prev_cost + COST_TRANSITION_KNOWN_TO_UNKNOWN;
-                    let entry = next_costs.entry(unknown_state_idx).or_insert(f64::INFINITY);
-                    if unknown_cost < *entry {
-                        *entry = unknown_cost;
-                        step_backpointers.insert(unknown_state_idx, prev_idx);
-                    }
-                } else {
-                    // Case 2: Previous state was Unknown
-
-                    // 2a. Transition: Unknown -> Known
-                    // Try to snap to ANY Known node matching the prefix
-                    for &curr_idx in &self.nodes_by_prefix[obs as usize] {
-                        let total_c = prev_cost + COST_TRANSITION_UNKNOWN_TO_KNOWN;
-                        let entry = next_costs.entry(curr_idx).or_insert(f64::INFINITY);
-                        if total_c < *entry {
-                            *entry = total_c;
-                            step_backpointers.insert(curr_idx, prev_idx);
-                        }
-                    }
+                for (next_idx, trans_c) in self.transitions(prev_idx, obs) {
+                    let total_c = prev_cost + trans_c;
 
-                    // 2b. Transition: Unknown -> Unknown
-                    let unknown_unknown_cost = prev_cost + COST_TRANSITION_UNKNOWN_TO_UNKNOWN;
-                    let entry = next_costs.entry(unknown_state_idx).or_insert(f64::INFINITY);
-                    if unknown_unknown_cost < *entry {
-                        *entry = unknown_unknown_cost;
-                        step_backpointers.insert(unknown_state_idx, prev_idx);
+                    // Update if this path is cheaper. Ties go to the lowest previous state,
+                    // so the result doesn't depend on HashMap iteration order.
+                    let entry = next_costs.entry(next_idx).or_insert(f64::INFINITY);
+                    let tie_wins = total_c == *entry
+                        && step_backpointers.get(&next_idx).is_some_and(|&best| prev_idx < best);
+                    if total_c < *entry || tie_wins {
+                        *entry = total_c;
+                        step_backpointers.insert(next_idx, prev_idx);
                     }
                 }
             }
@@ -218,6 +857,11 @@ impl NetworkGraph {
                 return Err(anyhow!("Viterbi stuck at step {}: no reachable states", t));
             }
 
+            let (dropped, cheapest) = self.prune_beam(&mut next_costs, |&c| c);
+            pruned_states += dropped;
+            min_pruned_cost = min_pruned_cost.min(cheapest);
+
+            candidates.push(count_known(&next_costs));
             current_costs = next_costs;
             backpointers.push(step_backpointers);
         }
@@ -226,12 +870,12 @@ impl NetworkGraph {
         let mut best_final_cost = f64::INFINITY;
         let mut best_final_state = None;
 
-        // Ensure deterministic results by iterating over sorted state indices.
-        let mut final_states: Vec<usize> = current_costs.keys().cloned().collect();
-        final_states.sort_unstable(); // Deterministic order: 0, 1, 2, ..., Unknown(MAX)
+        // Ensure deterministic results by iterating over sorted states.
+        let mut final_states: Vec<State> = current_costs.keys().cloned().collect();
+        final_states.sort_unstable(); // Deterministic order: Known(0), Known(1), ..., then ghosts
 
         for &state_idx in &final_states {
-            let cost = current_costs[&state_idx];
+            let cost = current_costs[&state_idx] + self.final_cost(state_idx, &ends);
             if cost < best_final_cost {
                 best_final_cost = cost;
                 best_final_state = Some(state_idx);
@@ -239,32 +883,543 @@ impl NetworkGraph {
         }
 
         // Backtrack from the best final state to reconstruct the most likely path.
-        if let Some(mut curr_idx) = best_final_state {
-            let mut path = Vec::new();
-            let last_t = t_steps - 1;
+        let Some(mut curr_idx) = best_final_state else {
+            return Err(anyhow!("No valid path found (final state unreachable)"));
+        };
+        let mut states = vec![curr_idx];
+        for t in (1..t_steps).rev() {
+            if let Some(&prev_idx) = backpointers[t].get(&curr_idx) {
+                states.push(prev_idx);
+                curr_idx = prev_idx;
+            } else {
+                return Err(anyhow!("Broken path during backtracking at step {}", t));
+            }
+        }
+        states.reverse();
 
-            let to_path_node = |idx: usize, step_idx: usize| -> PathNode {
-                if idx < unknown_state_idx {
-                    PathNode::Known(idx)
-                } else {
-                    PathNode::Unknown(observations[step_idx])
-                }
+        // Flood routing suppresses duplicates, so a path through the same repeater twice is
+        // almost never real. If the best path does that, re-rank the K best with a penalty.
+        let mut revisits = count_revisits(&states);
+        let mut revisit_unavoidable = false;
+        if revisits > 0 && self.config.revisit_penalty > 0.0 {
+            let (cost, alt_states, avoidable) = self.rerank_revisits(observations, &ends)?;
+            if count_revisits(&alt_states) < revisits {
+                revisits = count_revisits(&alt_states);
+                best_final_cost = cost;
+                states = alt_states;
+            }
+            revisit_unavoidable = !avoidable;
+        }
+
+        // Diagnostics along the chosen path
+        let mut step_costs = Vec::with_capacity(t_steps);
+        let mut unknown_kinds = Vec::with_capacity(t_steps);
+        let mut corrupted = Vec::with_capacity(t_steps);
+        let mut skipped_before = Vec::with_capacity(t_steps);
+        for (t, &state_idx) in states.iter().enumerate() {
+            corrupted.push(matches!(state_idx, State::Known(idx) if !self.node_matches(idx, observations[t])));
+            skipped_before.push(match (t.checked_sub(1).map(|p| states[p]), state_idx) {
+                (Some(State::Known(prev)), State::Known(idx)) => self.link_cost(prev, idx).is_none(),
+                _ => false,
+            });
+
+            let reachable = if t == 0 {
+                step_costs.push(start_costs[&state_idx]);
+                start_costs.keys().map(|&idx| (idx, 0.0)).collect()
+            } else {
+                let out = self.transitions(states[t - 1], observations[t]);
+                let cost = out.iter().find(|&&(idx, _)| idx == state_idx).map_or(f64::INFINITY, |&(_, c)| c);
+                step_costs.push(cost);
+                out
             };
 
-            path.push(to_path_node(curr_idx, last_t));
+            unknown_kinds.push(if state_idx.is_known() {
+                None
+            } else if reachable.iter().any(|(idx, _)| idx.is_known()) {
+                Some(UnknownKind::Chosen)
+            } else {
+                Some(UnknownKind::Forced)
+            });
+        }
 
-            for t in (1..t_steps).rev() {
-                if let Some(&prev_idx) = backpointers[t].get(&curr_idx) {
-                    path.push(to_path_node(prev_idx, t - 1));
-                    curr_idx = prev_idx;
-                } else {
-                    return Err(anyhow!("Broken path during backtracking at step {}", t));
+        let end_cost = self.final_cost(states[t_steps - 1], &ends);
+        let skipped_before_observer = match (states[t_steps - 1], &ends.observer) {
+            (State::Known(idx), Some(observer)) => {
+                end_cost.is_finite() && self.anchor_link_cost(observer, idx, true).is_infinite()
+            }
+            _ => false,
+        };
+        Ok(DecodedPath {
+            hops: states
+                .iter()
+                .zip(observations)
+                .map(|(&state_idx, &obs)| self.to_path_node(state_idx, obs))
+                .collect(),
+            step_costs,
+            end_cost,
+            total_cost: best_final_cost,
+            candidates,
+            unknown_kinds,
+            revisits,
+            bounces: count_bounces(&states),
+            revisit_unavoidable,
+            corrupted,
+            skipped_before,
+            skipped_before_observer,
+            pruned_states,
+            // Later steps can only add cost, so a dropped partial path costing more
+            // than the answer could never have beaten it
+            pruning_may_have_changed: min_pruned_cost < best_final_cost,
+        })
+    }
+
+    /// Decodes many packets on `threads` worker threads (0 = one per available core).
+    ///
+    /// The graph is read-only once built, so packets decode independently. Results are in
+    /// the same order as `packets` whatever the thread count, and a packet that fails to
+    /// decode only fails its own entry.
+    pub fn decode_batch(&self, packets: &[PacketPath], threads: usize) -> Vec<Result<DecodedPath>> {
+        parallel_map(packets, threads, |packet| {
+            self.decode_path_with_anchors(&packet.hashes, packet.origin, packet.observer)
+        })
+    }
+
+    /// Returns up to `k` distinct paths in order of increasing total cost (list Viterbi).
+    /// See `decode_k_best_with_anchors`.
+    pub fn decode_k_best(&self, observations: &[PathHash], k: usize) -> Result<Vec<RankedPath>> {
+        self.decode_k_best_with_anchors(observations, None, None, k)
+    }
+
+    /// Returns up to `k` distinct paths in order of increasing total cost, each with its
+    /// gap to the best one. A small gap means the decode is close to a coin flip.
+    ///
+    /// Each state keeps its `k` cheapest partial paths instead of just one, so the first
+    /// entry is the same path Viterbi finds (up to ties, and before any revisit penalty).
+    pub fn decode_k_best_with_anchors(
+        &self,
+        observations: &[PathHash],
+        origin: Option<Anchor>,
+        observer: Option<Anchor>,
+        k: usize,
+    ) -> Result<Vec<RankedPath>> {
+        check_widths(observations)?;
+        if observations.is_empty() || k == 0 {
+            return Ok(Vec::new());
+        }
+
+        let ends = Ends::new(origin, observer);
+        let best = self.k_best_states(observations, &ends, k)?;
+        let best_cost = best[0].0;
+
+        Ok(best
+            .into_iter()
+            .map(|(cost, states)| RankedPath {
+                path: states
+                    .iter()
+                    .zip(observations)
+                    .map(|(&state, &obs)| self.to_path_node(state, obs))
+                    .collect(),
+                cost,
+                gap_to_best: cost - best_cost,
+            })
+            .collect())
+    }
+
+    /// The best of the K best paths once revisits pay `DecoderConfig::revisit_penalty`, as
+    /// (total cost, state sequence, whether any of the paths considered avoids revisits).
+    ///
+    /// K starts at 2 and doubles up to `revisit_k`. It stops growing once the winner's
+    /// penalised cost is no more than the K-th path's raw cost, since no cheaper path is left
+    /// to beat it, and a revisit-free path has been seen (or the list is exhausted).
+    fn rerank_revisits(&self, observations: &[PathHash], ends: &Ends) -> Result<(f64, Vec<State>, bool)> {
+        let penalised = |cost: f64, revisits: usize| {
+            if revisits == 0 { cost } else { cost + self.config.revisit_penalty * revisits as f64 }
+        };
+        let max_k = self.config.revisit_k.max(1);
+        let mut k = max_k.min(2);
+        loop {
+            let alternatives = self.k_best_states(observations, ends, k)?;
+            let exhausted = alternatives.len() < k || k == max_k;
+            let kth_cost = alternatives.last().map_or(f64::INFINITY, |a| a.0);
+            let avoidable = alternatives.iter().any(|a| count_revisits(&a.1) == 0);
+            let (cost, states) = alternatives
+                .into_iter()
+                .min_by(|a, b| {
+                    let (ra, rb) = (count_revisits(&a.1), count_revisits(&b.1));
+                    penalised(a.0, ra)
+                        .total_cmp(&penalised(b.0, rb))
+                        .then(ra.cmp(&rb))
+                        .then(a.0.total_cmp(&b.0))
+                })
+                .ok_or_else(|| anyhow!("No valid path found (final state unreachable)"))?;
+            let settled = penalised(cost, count_revisits(&states)) <= kth_cost && avoidable;
+            if exhausted || settled {
+                return Ok((cost, states, avoidable));
+            }
+            k = (k * 2).min(max_k);
+        }
+    }
+
+    /// List Viterbi over trellis states: up to `k` (total cost, state sequence) pairs,
+    /// cheapest first. Never empty on success.
+    fn k_best_states(&self, observations: &[PathHash], ends: &Ends, k: usize) -> Result<Vec<(f64, Vec<State>)>> {
+        let t_steps = observations.len();
+
+        // Trellis: [step] -> { state -> up to k partial paths, cheapest first }
+        let mut trellis: Vec<HashMap<State, Vec<RankedEntry>>> = Vec::with_capacity(t_steps);
+        let mut start: HashMap<State, Vec<RankedEntry>> = self
+            .initial_costs(observations[0], ends)
+            .into_iter()
+            .map(|(state_idx, cost)| {
+                let start = RankedEntry { cost, prev_idx: None, prev_rank: 0 };
+                (state_idx, vec![start])
+            })
+            .collect();
+        self.prune_beam(&mut start, |entries| entries[0].cost);
+        trellis.push(start);
+
+        // Forward Pass
+        for (t, &obs) in observations.iter().enumerate().skip(1) {
+            let mut next: HashMap<State, Vec<RankedEntry>> = HashMap::new();
+
+            for (&prev_idx, entries) in &trellis[t - 1] {
+                for (next_idx, trans_c) in self.transitions(prev_idx, obs) {
+                    let list = next.entry(next_idx).or_default();
+                    for (rank, entry) in entries.iter().enumerate() {
+                        list.push(RankedEntry {
+                            cost: entry.cost + trans_c,
+                            prev_idx: Some(prev_idx),
+                            prev_rank: rank,
+                        });
+                    }
                 }
             }
-            path.reverse();
-            Ok(path)
-        } else {
-            Err(anyhow!("No valid path found (final state unreachable)"))
+
+            if next.is_empty() {
+                return Err(anyhow!("Viterbi stuck at step {}: no reachable states", t));
+            }
+
+            for list in next.values_mut() {
+                list.sort_by(|a, b| {
+                    a.cost
+                        .total_cmp(&b.cost)
+                        .then(a.prev_idx.cmp(&b.prev_idx))
+                        .then(a.prev_rank.cmp(&b.prev_rank))
+                });
+                list.truncate(k);
+            }
+            self.prune_beam(&mut next, |entries| entries[0].cost);
+            trellis.push(next);
+        }
+
+        // Termination: the k cheapest (state, rank) pairs over all final states
+        let mut finals: Vec<(f64, State, usize)> = Vec::new();
+        for (&state_idx, entries) in &trellis[t_steps - 1] {
+            let end_c = self.final_cost(state_idx, ends);
+            for (rank, entry) in entries.iter().enumerate() {
+                if (entry.cost + end_c).is_finite() {
+                    finals.push((entry.cost + end_c, state_idx, rank));
+                }
+            }
+        }
+        finals.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        finals.truncate(k);
+
+        if finals.is_empty() {
+            return Err(anyhow!("No valid path found (final state unreachable)"));
+        }
+
+        // Backtrack each (state, rank) through its chain of (prev_state, prev_rank)
+        let mut ranked = Vec::with_capacity(finals.len());
+        for (cost, mut state_idx, mut rank) in finals {
+            let mut states = Vec::with_capacity(t_steps);
+            for t in (0..t_steps).rev() {
+                states.push(state_idx);
+                let entry = &trellis[t][&state_idx][rank];
+                if let Some(prev_idx) = entry.prev_idx {
+                    state_idx = prev_idx;
+                    rank = entry.prev_rank;
+                }
+            }
+            states.reverse();
+            ranked.push((cost, states));
+        }
+
+        Ok(ranked)
+    }
+
+    /// Per-hop posterior probabilities over the candidate repeaters and the Unknown state.
+    /// See `hop_posteriors_with_anchors`.
+    pub fn hop_posteriors(&self, observations: &[PathHash]) -> Result<Vec<Vec<HopCandidate>>> {
+        self.hop_posteriors_with_anchors(observations, None, None)
+    }
+
+    /// Per-hop posterior probabilities from a forward-backward pass over the same sparse
+    /// trellis as `decode_path_with_anchors`, reading each cost as `-ln(weight)`.
+    ///
+    /// For every observation position, returns the states with non-zero mass, most probable
+    /// first. Where Viterbi only says which state is best, this says how sure it is.
+    pub fn hop_posteriors_with_anchors(
+        &self,
+        observations: &[PathHash],
+        origin: Option<Anchor>,
+        observer: Option<Anchor>,
+    ) -> Result<Vec<Vec<HopCandidate>>> {
+        check_widths(observations)?;
+        if observations.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let t_steps = observations.len();
+        let ends = Ends::new(origin, observer);
+
+        // Forward Pass: log alpha[t][state] = ln(sum of weights of all paths ending in state at t)
+        // Beam pruning treats -log alpha as the state's cost
+        let mut alpha: Vec<HashMap<State, f64>> = Vec::with_capacity(t_steps);
+        let mut start: HashMap<State, f64> = self
+            .initial_costs(observations[0], &ends)
+            .into_iter()
+            .map(|(state_idx, cost)| (state_idx, -cost))
+            .collect();
+        self.prune_beam(&mut start, |&a| -a);
+        alpha.push(start);
+        for (t, &obs) in observations.iter().enumerate().skip(1) {
+            let mut next: HashMap<State, f64> = HashMap::new();
+            for (&prev_idx, &prev_log) in &alpha[t - 1] {
+                for (next_idx, trans_c) in self.transitions(prev_idx, obs) {
+                    let entry = next.entry(next_idx).or_insert(f64::NEG_INFINITY);
+                    *entry = log_add(*entry, prev_log - trans_c);
+                }
+            }
+            if next.is_empty() {
+                return Err(anyhow!("Viterbi stuck at step {}: no reachable states", t));
+            }
+            self.prune_beam(&mut next, |&a| -a);
+            alpha.push(next);
+        }
+
+        // Backward Pass: log beta[t][state] = ln(sum of weights of all completions from state at t)
+        let mut beta: Vec<HashMap<State, f64>> = vec![HashMap::new(); t_steps];
+        beta[t_steps - 1] = alpha[t_steps - 1]
+            .keys()
+            .map(|&state_idx| (state_idx, -self.final_cost(state_idx, &ends)))
+            .collect();
+        for t in (0..t_steps - 1).rev() {
+            let mut step = HashMap::new();
+            for &state_idx in alpha[t].keys() {
+                let mut log_sum = f64::NEG_INFINITY;
+                for (next_idx, trans_c) in self.transitions(state_idx, observations[t + 1]) {
+                    if let Some(&next_log) = beta[t + 1].get(&next_idx) {
+                        log_sum = log_add(log_sum, next_log - trans_c);
+                    }
+                }
+                step.insert(state_idx, log_sum);
+            }
+            beta[t] = step;
         }
+
+        // Normalise by the total weight of all complete paths
+        let log_total = alpha[t_steps - 1]
+            .iter()
+            .map(|(state_idx, &a)| a + beta[t_steps - 1][state_idx])
+            .fold(f64::NEG_INFINITY, log_add);
+        if !log_total.is_finite() {
+            return Err(anyhow!("No valid path found (final state unreachable)"));
+        }
+
+        let mut posteriors = Vec::with_capacity(t_steps);
+        for t in 0..t_steps {
+            // Ghosts with different anchors are all the same Unknown hop, so their mass is pooled
+            let mut by_node: BTreeMap<Option<usize>, f64> = BTreeMap::new();
+            for (&state_idx, &a) in &alpha[t] {
+                let p = (a + beta[t][&state_idx] - log_total).exp();
+                *by_node.entry(state_idx.known_index()).or_insert(0.0) += p;
+            }
+            let mut hop: Vec<(Option<usize>, f64)> = by_node
+                .into_iter()
+                .map(|(node, p)| (node, p.min(1.0)))
+                .filter(|&(_, p)| p > 0.0)
+                .collect();
+            hop.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            posteriors.push(
+                hop.into_iter()
+                    .map(|(node, probability)| HopCandidate {
+                        node: match node {
+                            Some(idx) => PathNode::Known(idx),
+                            None => PathNode::Unknown(observations[t]),
+                        },
+                        probability,
+                    })
+                    .collect(),
+            );
+        }
+
+        Ok(posteriors)
+    }
+}
+
+/// Why a hop was decoded as Unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownKind {
+    /// No known repeater with this prefix could follow the previous hop.
+    Forced,
+    /// Known candidates were reachable, but every path through them cost more.
+    Chosen,
+}
+
+/// The most likely path for a packet, with the costs behind it (see `NetworkGraph::decode_path`).
+///
+/// All vectors are indexed by hop.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct DecodedPath {
+    pub hops: Vec<PathNode>,
+    /// Cost of reaching each hop: the start (or origin link) cost for the first,
+    /// the transition cost from the previous hop for the rest.
+    pub step_costs: Vec<f64>,
+    /// Cost of the link from the last hop to the observer (0 without an observer).
+    pub end_cost: f64,
+    /// Sum of `step_costs` and `end_cost`.
+    pub total_cost: f64,
+    /// Known repeaters still in the trellis at each step, i.e. the candidates considered.
+    pub candidates: Vec<usize>,
+    /// `None` for Known hops; for Unknown hops, whether they were forced or chosen.
+    pub unknown_kinds: Vec<Option<UnknownKind>>,
+    /// Hops that return to a repeater already on the path (see `DecoderConfig::revisit_penalty`).
+    pub revisits: usize,
+    /// Of `revisits`, the bounces straight back to the repeater two hops earlier (A -> B -> A).
+    pub bounces: usize,
+    /// Set when the path revisits a repeater because none of the `revisit_k` best paths
+    /// avoids it, i.e. the revisit could not be decoded away.
+    pub revisit_unavoidable: bool,
+    /// Known hops whose prefix differs from the observed byte, i.e. decoded as a corrupted
+    /// byte (see `DecoderConfig::substitution_probability`).
+    pub corrupted: Vec<bool>,
+    /// Known hops with no direct link from the previous hop, i.e. decoded as following
+    /// a repeater missing from the path (see `DecoderConfig::skipped_hop_probability`).
+    pub skipped_before: Vec<bool>,
+    /// Whether a repeater is assumed missing between the last hop and the observer.
+    pub skipped_before_observer: bool,
+    /// Trellis states dropped by beam pruning (see `DecoderConfig::beam_width`).
+    pub pruned_states: usize,
+    /// Set when a dropped state was already cheaper than the final answer,
+    /// so the exact decode might have been different.
+    pub pruning_may_have_changed: bool,
+}
+
+/// Posterior probability of one state at one hop (see `NetworkGraph::hop_posteriors`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HopCandidate {
+    pub node: PathNode,
+    pub probability: f64,
+}
+
+/// Number of Known hops that repeat a repeater seen earlier in the path.
+fn count_revisits(states: &[State]) -> usize {
+    let mut seen = std::collections::HashSet::new();
+    states
+        .iter()
+        .filter_map(State::known_index)
+        .filter(|&idx| !seen.insert(idx))
+        .count()
+}
+
+/// Number of Known hops equal to the hop two steps earlier (A -> B -> A).
+fn count_bounces(states: &[State]) -> usize {
+    states
+        .windows(3)
+        .filter(|w| w[0].is_known() && w[0] == w[2])
+        .count()
+}
+
+/// Adds two probabilities given as natural logs, without underflow.
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
     }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+    let max = a.max(b);
+    max + ((a - max).exp() + (b - max).exp()).ln()
+}
+
+/// A partial path in the list-Viterbi trellis: its cost so far and the
+/// (state, rank) entry it extends at the previous step.
+#[derive(Debug, Clone, Copy)]
+struct RankedEntry {
+    cost: f64,
+    prev_idx: Option<State>,
+    prev_rank: usize,
+}
+
+/// One of the K best decodes of a packet (see `NetworkGraph::decode_k_best`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RankedPath {
+    pub path: Vec<PathNode>,
+    /// Total path cost (Negative Log Probability).
+    pub cost: f64,
+    /// `cost` minus the cost of the best path; 0 for the best path itself.
+    pub gap_to_best: f64,
+}
+
+/// How the graph and decoded paths respond to one refraction setting (see `k_factor_sweep`).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KFactorSweepResult {
+    pub k_factor: f64,
+    /// Number of feasible directed edges in the graph.
+    pub edge_count: usize,
+    /// Hops resolved to a known repeater across all packets.
+    pub known_hops: usize,
+    /// Hops left as Unknown across all packets.
+    pub unknown_hops: usize,
+    /// Packets that could not be decoded at all.
+    pub failed_packets: usize,
+}
+
+/// Rebuilds the graph for each effective earth radius factor in `k_values` and decodes
+/// every observation sequence, to show how sensitive the results are to refraction.
+pub fn k_factor_sweep(
+    nodes: &[Repeater],
+    terrain: Option<Arc<TerrainMap>>,
+    model: &dyn PropagationModel,
+    config: &DecoderConfig,
+    k_values: &[f64],
+    observations: &[Vec<PathHash>],
+) -> Vec<KFactorSweepResult> {
+    k_values
+        .iter()
+        .map(|&k_factor| {
+            let graph = NetworkGraph::with_shared_terrain(
+                nodes.to_vec(),
+                terrain.clone(),
+                model.with_k_factor(k_factor),
+                config.clone(),
+            );
+
+            let mut result = KFactorSweepResult {
+                k_factor,
+                edge_count: graph.edge_count(),
+                known_hops: 0,
+                unknown_hops: 0,
+                failed_packets: 0,
+            };
+
+            for obs in observations {
+                match graph.decode_path(obs) {
+                    Ok(path) => {
+                        for node in path.hops {
+                            match node {
+                                PathNode::Known(_) => result.known_hops += 1,
+                                PathNode::Unknown(_) => result.unknown_hops += 1,
+                            }
+                        }
+                    }
+                    Err(_) => result.failed_packets += 1,
+                }
+            }
+
+            result
+        })
+        .collect()
 }
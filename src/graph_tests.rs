@@ -1,8 +1,11 @@
 #[cfg(test)]
 mod tests {
-    use crate::graph::NetworkGraph;
-    use crate::models::{PathNode, Repeater};
+    use crate::graph::{Anchor, DecoderConfig, NetworkGraph, PacketPath, UnknownKind, k_factor_sweep};
+    use crate::models::{PathHash, PathNode, Repeater};
+    use crate::physics::{GROUND_ANTENNA_HEIGHT_M, haversine_distance};
+    use crate::propagation::{FreeSpaceModel, PropagationModel, SigmoidModel, TerrainAwareModel};
     use crate::terrain::TerrainMap;
+    use std::sync::Arc;
 
     // Helper to create a dummy node
     fn create_node(id: &str, lat: f64, lon: f64) -> Repeater {
@@ -11,9 +14,15 @@ mod tests {
             name: "TestNode".to_string(),
             lat,
             lon,
+            ..Default::default()
         }
     }
 
+    // Helper to turn 1-byte prefixes into path hashes
+    fn hashes(prefixes: &[u8]) -> Vec<PathHash> {
+        prefixes.iter().map(|&p| PathHash::byte(p)).collect()
+    }
+
     #[test]
     fn test_winding_path_reconstruction() {
         // Scenario: A -> B -> C -> D -> E
@@ -38,10 +47,10 @@ mod tests {
         ];
 
         // We observe the full sequence
-        let obs = vec![0xA0, 0xB0, 0xC0, 0xD0, 0xE0];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0, 0xD0, 0xE0]);
 
         let graph = NetworkGraph::new(nodes, None);
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         assert_eq!(result.len(), 5);
         assert_eq!(result[0], PathNode::Known(0));
@@ -95,10 +104,10 @@ mod tests {
         // Indices: A=0, B=1, C=2, D=3
 
         // Obs: A -> B -> C -> D
-        let obs = vec![0xA0, 0xB0, 0xC0, 0xD0];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0, 0xD0]);
 
-        let graph = NetworkGraph::new(nodes, Some(&map));
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let graph = NetworkGraph::new(nodes, Some(Arc::new(map)));
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         assert_eq!(result.len(), 4);
         assert_eq!(result[0], PathNode::Known(0)); // A
@@ -118,10 +127,10 @@ mod tests {
             create_node("B00000", 0.0, 10.0),
         ];
 
-        let obs = vec![0xA0, 0xB0];
+        let obs = hashes(&[0xA0, 0xB0]);
         let graph = NetworkGraph::new(nodes, None);
 
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         // We expect A -> Unknown(B0).
         if result[0] != PathNode::Known(0) {
@@ -129,7 +138,7 @@ mod tests {
         }
 
         assert_eq!(result[0], PathNode::Known(0), "Should start with Known node A");
-        assert_eq!(result[1], PathNode::Unknown(0xB0), "Should fallback to Unknown for B");
+        assert_eq!(result[1], PathNode::Unknown(PathHash::byte(0xB0)), "Should fallback to Unknown for B");
     }
 
     #[test]
@@ -143,14 +152,14 @@ mod tests {
         let nodes = vec![node_a, node_c];
 
         // Observations: A0, B0, C0
-        let obs = vec![0xA0, 0xB0, 0xC0];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
 
         let graph = NetworkGraph::new(nodes, None);
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], PathNode::Known(0)); // Node A
-        assert_eq!(result[1], PathNode::Unknown(0xB0)); // Unknown
+        assert_eq!(result[1], PathNode::Unknown(PathHash::byte(0xB0))); // Unknown
         assert_eq!(result[2], PathNode::Known(1)); // Node C
     }
 
@@ -161,15 +170,15 @@ mod tests {
         let node_c = create_node("C00000", 1.0, 0.0); // 111km away
         let nodes = vec![node_a, node_c];
 
-        let obs = vec![0xA0, 0xB0, 0xB1, 0xC0];
+        let obs = hashes(&[0xA0, 0xB0, 0xB1, 0xC0]);
 
         let graph = NetworkGraph::new(nodes, None);
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         assert_eq!(result.len(), 4);
         assert_eq!(result[0], PathNode::Known(0));
-        assert_eq!(result[1], PathNode::Unknown(0xB0));
-        assert_eq!(result[2], PathNode::Unknown(0xB1));
+        assert_eq!(result[1], PathNode::Unknown(PathHash::byte(0xB0)));
+        assert_eq!(result[2], PathNode::Unknown(PathHash::byte(0xB1)));
         assert_eq!(result[3], PathNode::Known(1));
     }
 
@@ -187,16 +196,16 @@ mod tests {
         let nodes = vec![node_a, node_c, node_b_bad];
 
         // Observations: A0, B0, C0
-        let obs = vec![0xA0, 0xB0, 0xC0];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
 
         let graph = NetworkGraph::new(nodes, None);
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         // Should pick A -> Unknown(B0) -> C
         // Not A -> D0 -> C (impossible due to emission)
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], PathNode::Known(0)); // A
-        assert_eq!(result[1], PathNode::Unknown(0xB0)); // Unknown B0
+        assert_eq!(result[1], PathNode::Unknown(PathHash::byte(0xB0))); // Unknown B0
         assert_eq!(result[2], PathNode::Known(1)); // C
     }
 
@@ -215,14 +224,721 @@ mod tests {
 
         let nodes = vec![node_a, node_c, node_b_far];
 
-        let obs = vec![0xA0, 0xB0, 0xC0];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
 
         let graph = NetworkGraph::new(nodes, None);
-        let result = graph.decode_path(&obs).expect("Viterbi failed");
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], PathNode::Known(0));
-        assert_eq!(result[1], PathNode::Unknown(0xB0)); // Should prefer Unknown due to lower cost
+        assert_eq!(result[1], PathNode::Unknown(PathHash::byte(0xB0))); // Should prefer Unknown due to lower cost
         assert_eq!(result[2], PathNode::Known(1));
     }
+
+    #[test]
+    fn test_decoded_path_diagnostics() {
+        // A -> B0 -> C -> D0. B_weak is in range of A but only just (~60km) and out of
+        // range of C, so the Unknown detour is cheaper; no D0 exists at all.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 0.0, -0.2),
+            create_node("B00000", 0.0, 0.55), // B_weak
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+        let decoded = graph.decode_path(&hashes(&[0xA0, 0xB0, 0xC0, 0xD0])).expect("Viterbi failed");
+
+        assert_eq!(
+            decoded.hops,
+            vec![PathNode::Known(0), PathNode::Unknown(PathHash::byte(0xB0)), PathNode::Known(1), PathNode::Unknown(PathHash::byte(0xD0))]
+        );
+        assert_eq!(decoded.candidates, vec![1, 1, 1, 0]);
+        assert_eq!(
+            decoded.unknown_kinds,
+            vec![None, Some(UnknownKind::Chosen), None, Some(UnknownKind::Forced)]
+        );
+
+        let sum: f64 = decoded.step_costs.iter().sum::<f64>() + decoded.end_cost;
+        assert!((decoded.total_cost - sum).abs() < 1e-9);
+        assert_eq!(decoded.end_cost, 0.0);
+    }
+
+    #[test]
+    fn test_decoder_config_changes_decoding() {
+        // A -> B0 -> C where the A -> B_weak link (~60km) is poor but B_weak -> C is good.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 0.0, 0.2),
+            create_node("B00000", 0.0, 0.55), // B_weak
+        ];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+
+        // Default: cheaper to claim the packet started at an unknown A0 than to use the weak link
+        let graph = NetworkGraph::new(nodes.clone(), None);
+        let hops = graph.decode_path(&obs).unwrap().hops;
+        assert_eq!(hops, vec![PathNode::Unknown(PathHash::byte(0xA0)), PathNode::Known(2), PathNode::Known(1)]);
+
+        // Harsher Unknown penalties make the weak known link the better explanation
+        let config: DecoderConfig = serde_yaml::from_str(
+            "cost_known_to_unknown: 40.0\ncost_unknown_to_known: 40.0\ncost_unknown_to_unknown: 40.0",
+        )
+        .unwrap();
+        assert_eq!(config.cost_start_known, DecoderConfig::default().cost_start_known);
+        let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config);
+        let hops = graph.decode_path(&obs).unwrap().hops;
+        assert_eq!(hops, vec![PathNode::Known(0), PathNode::Known(2), PathNode::Known(1)]);
+
+        // A shorter range drops the link from the graph entirely
+        let config = DecoderConfig {
+            max_link_range_km: 30.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        assert_eq!(graph.link_cost(0, 2), None);
+        assert_eq!(graph.edge_count(), 2); // A <-> C only (~22km)
+    }
+
+    #[test]
+    fn test_max_link_range_reaches_the_model() {
+        // ~167km apart: free space would link them, but not beyond the default range
+        let nodes = vec![create_node("A00000", 0.0, 0.0), create_node("B00000", 0.0, 1.5)];
+        let graph = NetworkGraph::with_model(nodes.clone(), None, &FreeSpaceModel::default());
+        assert_eq!(graph.link_cost(0, 1), None);
+
+        let config = DecoderConfig {
+            max_link_range_km: 200.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &FreeSpaceModel::default(), config);
+        assert!(graph.link_cost(0, 1).is_some());
+        assert!(graph.model().link_cost(&create_node("X", 0.0, 0.0), &create_node("Y", 0.0, 1.5), None).is_finite());
+    }
+
+    #[test]
+    fn test_search_box_widens_with_latitude() {
+        // At 60N a degree of longitude is ~56km, so a node ~100km east is 1.8 degrees away
+        let a = create_node("A00000", 60.0, 0.0);
+        let b = create_node("B00000", 60.0, 1.8);
+        let graph = NetworkGraph::with_model(vec![a.clone(), b.clone()], None, &FreeSpaceModel::default());
+        assert!(graph.link_cost(0, 1).is_some());
+
+        // The same for inferred nodes
+        let mut graph = NetworkGraph::with_model(vec![a], None, &FreeSpaceModel::default());
+        graph.set_inferred_nodes(vec![b]);
+        assert!(graph.link_cost(0, 1).is_some());
+        assert!(graph.link_cost(1, 0).is_some());
+    }
+
+    #[test]
+    fn test_link_budget_graph_prefers_closer_candidate() {
+        // Scenario: A -> (B0) -> C where two B0 repeaters exist.
+        // B_near sits between A and C, B_far is ~90km off to the side and well beyond
+        // the radio horizon, so its link budget leaves little or no margin.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 0.0, 0.4),
+            create_node("B00001", 0.8, 0.2), // B_far
+            create_node("B00002", 0.0, 0.2), // B_near
+        ];
+
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+
+        let graph = NetworkGraph::with_model(nodes, None, &TerrainAwareModel::default());
+        let result = graph.decode_path(&obs).expect("Viterbi failed").hops;
+
+        assert_eq!(result, vec![PathNode::Known(0), PathNode::Known(3), PathNode::Known(1)]);
+    }
+
+    #[test]
+    fn test_directed_costs_follow_travel_direction() {
+        // Two B0 candidates either side of A. B_deaf is slightly closer but sits under a
+        // high noise floor: it is heard fine but hears A poorly.
+        let mut b_deaf = create_node("B00001", 0.0, 0.3);
+        b_deaf.noise_floor_dbm = Some(-100.0);
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            b_deaf,
+            create_node("B00002", 0.0, -0.32),
+        ];
+
+        let graph = NetworkGraph::with_model(nodes, None, &TerrainAwareModel::default());
+        assert!(graph.link_cost(0, 1).unwrap() > graph.link_cost(1, 0).unwrap());
+
+        // A -> B0: the packet had to be received by B, so the quiet one is more likely
+        let forward = graph.decode_path(&hashes(&[0xA0, 0xB0])).expect("Viterbi failed").hops;
+        assert_eq!(forward, vec![PathNode::Known(0), PathNode::Known(2)]);
+
+        // B0 -> A: A is the receiver, so B_deaf's noise floor is irrelevant
+        let reverse = graph.decode_path(&hashes(&[0xB0, 0xA0])).expect("Viterbi failed").hops;
+        assert_eq!(reverse, vec![PathNode::Known(1), PathNode::Known(0)]);
+    }
+
+    #[test]
+    fn test_anchors_pick_the_right_endpoints() {
+        // Two identical A0 -> B0 pairs ~110km apart. Without anchors they are
+        // indistinguishable; the packet's origin and observer decide.
+        let nodes = vec![
+            create_node("A00001", 0.0, 0.0),
+            create_node("B00001", 0.0, 0.3),
+            create_node("A00002", 1.0, 0.0),
+            create_node("B00002", 1.0, 0.3),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+        let obs = hashes(&[0xA0, 0xB0]);
+
+        let north = graph
+            .decode_path_with_anchors(&obs, Some(Anchor::new(1.0, -0.1)), Some(Anchor::new(1.0, 0.4)))
+            .expect("Viterbi failed")
+            .hops;
+        assert_eq!(north, vec![PathNode::Known(2), PathNode::Known(3)]);
+
+        let south = graph
+            .decode_path_with_anchors(&obs, Some(Anchor::new(0.0, -0.1)), Some(Anchor::new(0.0, 0.4)))
+            .expect("Viterbi failed")
+            .hops;
+        assert_eq!(south, vec![PathNode::Known(0), PathNode::Known(1)]);
+
+        // The observer alone is enough to pin the whole path
+        let observed = graph
+            .decode_path_with_anchors(&obs, None, Some(Anchor::new(1.0, 0.4)))
+            .expect("Viterbi failed")
+            .hops;
+        assert_eq!(observed, vec![PathNode::Known(2), PathNode::Known(3)]);
+    }
+
+    #[test]
+    fn test_anchors_are_ground_level() {
+        // Origins and observers are handhelds, not repeaters on a mast
+        let anchor = Anchor::new(0.0, 0.0).as_repeater();
+        assert_eq!(anchor.antenna_height(), GROUND_ANTENNA_HEIGHT_M);
+
+        let mast = create_node("A00000", 0.0, 0.3);
+        let model = SigmoidModel::default();
+        let repeater = Repeater { lat: 0.0, lon: 0.0, ..mast.clone() };
+        assert!(model.link_cost(&anchor, &mast, None) > model.link_cost(&repeater, &mast, None));
+    }
+
+    #[test]
+    fn test_unreachable_origin_forces_unknown_first_hop() {
+        // The only A0 is ~550km from the origin, so the first hop must be someone else.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+
+        let path = graph
+            .decode_path_with_anchors(&hashes(&[0xA0, 0xB0]), Some(Anchor::new(5.0, 0.0)), None)
+            .expect("Viterbi failed")
+            .hops;
+        assert_eq!(path, vec![PathNode::Unknown(PathHash::byte(0xA0)), PathNode::Known(1)]);
+    }
+
+    #[test]
+    fn test_ghost_cannot_teleport() {
+        // A -> ?? -> C0, with the only C0 ~550km away. A flat Unknown -> Known cost would
+        // happily snap to it; a ghost anchored at A can only reach ~2 hops.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 5.0, 0.0),
+            create_node("C00001", 0.0, 1.5), // ~167km: two hops away, fine
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+
+        let hops = graph.decode_path(&hashes(&[0xA0, 0xB0, 0xC0])).expect("Viterbi failed").hops;
+        assert_eq!(hops, vec![PathNode::Known(0), PathNode::Unknown(PathHash::byte(0xB0)), PathNode::Known(2)]);
+
+        // With only the far C0, the last hop stays Unknown too
+        let nodes = vec![create_node("A00000", 0.0, 0.0), create_node("C00000", 5.0, 0.0)];
+        let graph = NetworkGraph::new(nodes.clone(), None);
+        let decoded = graph.decode_path(&hashes(&[0xA0, 0xB0, 0xC0])).expect("Viterbi failed");
+        assert_eq!(decoded.hops[2], PathNode::Unknown(PathHash::byte(0xC0)));
+        assert_eq!(decoded.unknown_kinds[2], Some(UnknownKind::Chosen));
+
+        // Longer runs of Unknowns reach further: six hops covers the distance
+        let hops = graph
+            .decode_path(&hashes(&[0xA0, 0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xC0]))
+            .expect("Viterbi failed")
+            .hops;
+        assert_eq!(hops[6], PathNode::Known(1));
+    }
+
+    #[test]
+    fn test_ghost_reach_is_a_cost() {
+        // A -> ?? -> C0 with C0 ~250km from A, 50km beyond two hops' reach
+        let nodes = vec![create_node("A00000", 0.0, 0.0), create_node("C00000", 0.0, 2.25)];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+        let overreach = (haversine_distance(0.0, 0.0, 0.0, 2.25) - 200.0) / 100.0 * 8.0;
+
+        // By default the overreach costs more than staying Unknown...
+        let graph = NetworkGraph::new(nodes.clone(), None);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[2], PathNode::Unknown(PathHash::byte(0xC0)));
+        assert_eq!(decoded.unknown_kinds[2], Some(UnknownKind::Chosen));
+
+        // ...but not once Unknown hops are expensive enough
+        let config = DecoderConfig {
+            cost_unknown_to_unknown: 20.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config.clone());
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[2], PathNode::Known(1));
+        assert!((decoded.step_costs[2] - (config.cost_unknown_to_known + overreach)).abs() < 1e-9);
+
+        // An infinite overreach cost makes the reach a hard cutoff
+        let config = DecoderConfig {
+            ghost_overreach_cost: f64::INFINITY,
+            ..config
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[2], PathNode::Unknown(PathHash::byte(0xC0)));
+        assert_eq!(decoded.unknown_kinds[2], Some(UnknownKind::Forced));
+    }
+
+    #[test]
+    fn test_origin_bounds_only_the_first_hop() {
+        // The origin rules out the A0 it cannot reach, but a run of Unknowns after it may
+        // still end at a B0 far away: ~550km, as in test_unreachable_origin_forces_unknown_first_hop
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+        let decoded = graph
+            .decode_path_with_anchors(&hashes(&[0xA0, 0xB0]), Some(Anchor::new(5.0, 0.0)), None)
+            .expect("Viterbi failed");
+        assert_eq!(decoded.unknown_kinds[0], Some(UnknownKind::Forced));
+        assert_eq!(decoded.step_costs[1], DecoderConfig::default().cost_unknown_to_known);
+    }
+
+    #[test]
+    fn test_revisit_penalty() {
+        // A0 -> B0 -> A0: bouncing straight back to A1 is cheapest, but floods never
+        // return to a repeater that already relayed the packet. A2 is further but distinct.
+        let nodes = vec![
+            create_node("A00001", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("A00002", 0.0, 0.75),
+        ];
+        let obs = hashes(&[0xA0, 0xB0, 0xA0]);
+
+        let graph = NetworkGraph::new(nodes.clone(), None);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[1], PathNode::Known(1));
+        assert_ne!(decoded.hops[0], decoded.hops[2]);
+        assert_eq!(decoded.revisits, 0);
+
+        // The unconstrained Viterbi path is the bounce
+        let ranked = graph.decode_k_best(&obs, 1).unwrap();
+        assert_eq!(ranked[0].path[0], ranked[0].path[2]);
+
+        // Disabled: the bounce is allowed
+        let config = DecoderConfig {
+            revisit_penalty: 0.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[0], decoded.hops[2]);
+        assert_eq!(decoded.revisits, 1);
+
+        // A small penalty is not enough to justify the longer B -> A2 link
+        let config = DecoderConfig {
+            revisit_penalty: 1.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&obs).unwrap();
+        assert_eq!((decoded.revisits, decoded.bounces), (1, 1));
+        assert!(!decoded.revisit_unavoidable);
+
+        // With only the bounce itself to choose from, it is kept and flagged
+        let config = DecoderConfig {
+            revisit_k: 1,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&obs).unwrap();
+        assert_eq!(decoded.hops[0], decoded.hops[2]);
+        assert!(decoded.revisit_unavoidable);
+    }
+
+    #[test]
+    fn test_loops_count_as_revisits_but_not_bounces() {
+        // A -> B -> C -> A around a triangle: a loop, but no bounce
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("C00000", 0.25, 0.15),
+        ];
+        let config = DecoderConfig {
+            revisit_penalty: 0.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&hashes(&[0xA0, 0xB0, 0xC0, 0xA0])).unwrap();
+        assert_eq!(decoded.hops[0], decoded.hops[3]);
+        assert_eq!((decoded.revisits, decoded.bounces), (1, 0));
+    }
+
+    #[test]
+    fn test_corrupted_byte_substitution() {
+        // A0 -> B0 -> C0 in a line, but the middle byte was logged as B7
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("C00000", 0.0, 0.6),
+        ];
+        let obs = hashes(&[0xA0, 0xB7, 0xC0]);
+
+        // Exact matching only: the bad byte has to be an Unknown hop
+        let graph = NetworkGraph::new(nodes.clone(), None);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops[1], PathNode::Unknown(PathHash::byte(0xB7)));
+        assert_eq!(decoded.corrupted, vec![false; 3]);
+
+        let config = DecoderConfig {
+            substitution_probability: 0.01,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path(&obs).expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(0), PathNode::Known(1), PathNode::Known(2)]);
+        assert_eq!(decoded.corrupted, vec![false, true, false]);
+        assert_eq!(decoded.skipped_before, vec![false; 3]);
+
+        // Intact bytes are never flagged
+        let decoded = graph.decode_path(&hashes(&[0xA0, 0xB0, 0xC0])).unwrap();
+        assert_eq!(decoded.corrupted, vec![false; 3]);
+    }
+
+    #[test]
+    fn test_substitution_on_first_hop_and_after_unknown() {
+        let nodes = vec![create_node("A00000", 0.0, 0.0), create_node("B00000", 0.0, 0.3)];
+        let config = DecoderConfig {
+            substitution_probability: 0.01,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+
+        // The origin sits next to A0, whose byte was logged as A7
+        let origin = Some(Anchor::new(0.0, -0.01));
+        let decoded = graph
+            .decode_path_with_anchors(&hashes(&[0xA7, 0xB0]), origin, None)
+            .expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(0), PathNode::Known(1)]);
+        assert_eq!(decoded.corrupted, vec![true, false]);
+
+        // A0 -> ? -> C0, too far apart to link directly; C0's byte was logged as C7
+        let nodes = vec![create_node("A00000", 0.0, 0.0), create_node("C00000", 0.0, 1.2)];
+        let config = DecoderConfig {
+            substitution_probability: 0.5,
+            max_feasible_link_cost: 3.0,
+            ..DecoderConfig::default()
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        assert_eq!(graph.link_cost(0, 1), None);
+        let observer = Some(Anchor::new(0.0, 1.21));
+        let decoded = graph
+            .decode_path_with_anchors(&hashes(&[0xA0, 0x77, 0xC7]), None, observer)
+            .expect("Viterbi failed");
+        assert_eq!(
+            decoded.hops,
+            vec![PathNode::Known(0), PathNode::Unknown(PathHash::byte(0x77)), PathNode::Known(1)]
+        );
+        assert_eq!(decoded.corrupted, vec![false, false, true]);
+    }
+
+    #[test]
+    fn test_skipped_hop() {
+        // A0 and C0 are out of each other's range; the B0 between them is missing from the path
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("C00000", 0.0, 0.6),
+        ];
+        let obs = hashes(&[0xA0, 0xC0]);
+        let origin = Some(Anchor::new(0.0, -0.1));
+        let observer = Some(Anchor::new(0.0, 0.9));
+        let short_links = DecoderConfig {
+            max_feasible_link_cost: 3.0,
+            ..DecoderConfig::default()
+        };
+
+        let graph = NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), short_links.clone());
+        assert_eq!(graph.link_cost(0, 2), None);
+        let decoded = graph.decode_path_with_anchors(&obs, origin, observer).expect("Viterbi failed");
+        assert!(decoded.hops.contains(&PathNode::Unknown(PathHash::byte(0xA0))) || decoded.hops.contains(&PathNode::Unknown(PathHash::byte(0xC0))));
+        assert!(!decoded.skipped_before_observer);
+
+        let config = DecoderConfig {
+            skipped_hop_probability: 0.05,
+            ..short_links
+        };
+        let graph = NetworkGraph::with_config(nodes, None, &SigmoidModel::default(), config);
+        let decoded = graph.decode_path_with_anchors(&obs, origin, observer).expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(0), PathNode::Known(2)]);
+        assert_eq!(decoded.skipped_before, vec![false, true]);
+        // One unlogged relay halfway between A0 and C0 sits where B0 is
+        let legs = graph.link_cost(0, 1).unwrap() + graph.link_cost(1, 2).unwrap();
+        assert!((decoded.step_costs[1] - (-(0.05f64).ln() + legs)).abs() < 1e-9);
+        assert_eq!(decoded.corrupted, vec![false, false]);
+
+        // Truncated tail: the path stops at B0, out of the observer's range
+        let decoded = graph
+            .decode_path_with_anchors(&hashes(&[0xA0, 0xB0]), origin, observer)
+            .expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(0), PathNode::Known(1)]);
+        assert!(decoded.skipped_before_observer);
+    }
+
+    #[test]
+    fn test_multi_byte_path_hashes() {
+        // Two repeaters share the first byte A0. One byte can't tell them apart,
+        // two bytes can, and both packet forms decode against the same graph.
+        let nodes = vec![
+            create_node("A01100", 0.0, 0.0),
+            create_node("A02200", 0.0, 0.6),
+            create_node("B03300", 0.0, 0.3),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+
+        let wide = |v: u32| PathHash { value: v, width: 2 };
+        let decoded = graph.decode_path(&[wide(0xA022), wide(0xB033)]).expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(1), PathNode::Known(2)]);
+
+        // An unmatched 2-byte hash keeps its width
+        let decoded = graph.decode_path(&[wide(0xA011), wide(0xB099)]).expect("Viterbi failed");
+        assert_eq!(decoded.hops, vec![PathNode::Known(0), PathNode::Unknown(wide(0xB099))]);
+
+        let posteriors = graph.hop_posteriors(&hashes(&[0xA0, 0xB0])).unwrap();
+        assert_eq!(posteriors[0].iter().filter(|c| matches!(c.node, PathNode::Known(_))).count(), 2);
+    }
+
+    #[test]
+    fn test_decode_batch_matches_sequential() {
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("C00000", 0.0, 0.6),
+            create_node("B10000", 0.3, 0.3),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+
+        let mut packets: Vec<PacketPath> = [
+            vec![0xA0, 0xB0, 0xC0],
+            vec![0xC0, 0xB1],
+            vec![0xA0, 0xD0, 0xC0],
+            vec![],
+            vec![0xB1, 0xB0, 0xA0, 0xB0],
+        ]
+        .iter()
+        .map(|p| PacketPath { hashes: hashes(p), ..PacketPath::default() })
+        .collect();
+        // Mixed widths can't come from one packet
+        packets.push(PacketPath {
+            hashes: vec![PathHash::byte(0xA0), PathHash::parse("b000").unwrap()],
+            ..PacketPath::default()
+        });
+        // Heard 2000km away: only an Unknown hop can end there
+        packets.push(PacketPath {
+            hashes: hashes(&[0xA0]),
+            origin: Some(Anchor::new(0.0, -0.1)),
+            observer: Some(Anchor::new(20.0, 0.0)),
+        });
+
+        let sequential: Vec<_> = packets
+            .iter()
+            .map(|p| graph.decode_path_with_anchors(&p.hashes, p.origin, p.observer).ok())
+            .collect();
+        assert!(sequential[5].is_none());
+        assert_eq!(sequential[6].as_ref().unwrap().hops, vec![PathNode::Unknown(PathHash::byte(0xA0))]);
+
+        for threads in [0, 1, 3, 16] {
+            let batch: Vec<_> = graph.decode_batch(&packets, threads).into_iter().map(Result::ok).collect();
+            assert_eq!(batch, sequential, "threads = {}", threads);
+        }
+    }
+
+    #[test]
+    fn test_beam_pruning() {
+        // Two A0 candidates tie at the start; the first is stranded 1000km away,
+        // the second leads on to B0 and C0.
+        let nodes = vec![
+            create_node("A00001", 9.0, 0.0),
+            create_node("A00002", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.3),
+            create_node("C00000", 0.0, 0.6),
+        ];
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+        let beam = |beam_width: usize, beam_margin: f64| {
+            let config = DecoderConfig { beam_width, beam_margin, ..DecoderConfig::default() };
+            NetworkGraph::with_config(nodes.clone(), None, &SigmoidModel::default(), config)
+        };
+        let exact = vec![PathNode::Known(1), PathNode::Known(2), PathNode::Known(3)];
+
+        let decoded = NetworkGraph::new(nodes.clone(), None).decode_path(&obs).unwrap();
+        assert_eq!(decoded.hops, exact);
+        assert_eq!(decoded.pruned_states, 0);
+        assert!(!decoded.pruning_may_have_changed);
+
+        // Too narrow: only the stranded A0 survives step 0, and the decoder knows it may be wrong
+        let decoded = beam(1, f64::INFINITY).decode_path(&obs).unwrap();
+        assert_ne!(decoded.hops, exact);
+        assert!(decoded.pruned_states > 0);
+        assert!(decoded.pruning_may_have_changed);
+
+        // Wide enough, or a margin that only drops the expensive ghosts: exact and known to be
+        for graph in [beam(3, f64::INFINITY), beam(0, 5.0)] {
+            let decoded = graph.decode_path(&obs).unwrap();
+            assert_eq!(decoded.hops, exact);
+            assert!(decoded.pruned_states > 0);
+            assert!(!decoded.pruning_may_have_changed);
+            assert_eq!(graph.decode_k_best(&obs, 1).unwrap()[0].path, exact);
+            let posteriors = graph.hop_posteriors(&obs).unwrap();
+            assert_eq!(posteriors[2][0].node, PathNode::Known(3));
+        }
+    }
+
+    #[test]
+    fn test_k_best_paths() {
+        // A -> B0 -> C with two B0 repeaters: one on the direct line, one slightly off it.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 0.0, 0.4),
+            create_node("B00001", 0.0, 0.2),  // on the line
+            create_node("B00002", 0.05, 0.2), // ~5.5km off the line
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+
+        let ranked = graph.decode_k_best(&obs, 5).expect("list Viterbi failed");
+        let best = graph.decode_path(&obs).expect("Viterbi failed").hops;
+
+        assert_eq!(ranked[0].path, best);
+        assert_eq!(ranked[0].gap_to_best, 0.0);
+        assert_eq!(ranked[1].path, vec![PathNode::Known(0), PathNode::Known(3), PathNode::Known(1)]);
+
+        // Runner-up is close: a coin flip, not a confident answer
+        assert!(ranked[1].gap_to_best > 0.0 && ranked[1].gap_to_best < 1.0);
+
+        // Distinct paths, in order of cost
+        for pair in ranked.windows(2) {
+            assert!(pair[0].cost <= pair[1].cost);
+            assert_ne!(pair[0].path, pair[1].path);
+        }
+
+        // The remaining alternatives route through the Unknown state
+        assert!(ranked[2..].iter().all(|r| r.path.iter().any(|n| matches!(n, PathNode::Unknown(_)))));
+        assert!(ranked[2].gap_to_best > 7.0);
+    }
+
+    #[test]
+    fn test_k_best_runs_out_of_paths() {
+        let graph = NetworkGraph::new(vec![create_node("A00000", 0.0, 0.0)], None);
+
+        // A single hop has only two interpretations: the known A0, or an unknown one
+        let ranked = graph.decode_k_best(&hashes(&[0xA0]), 10).expect("list Viterbi failed");
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].path, vec![PathNode::Known(0)]);
+        assert_eq!(ranked[1].path, vec![PathNode::Unknown(PathHash::byte(0xA0))]);
+        assert!(graph.decode_k_best(&hashes(&[0xA0]), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_hop_posteriors() {
+        // Same layout as test_k_best_paths: B0 is ambiguous, A0 and C0 are not.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("C00000", 0.0, 0.4),
+            create_node("B00001", 0.0, 0.2),
+            create_node("B00002", 0.05, 0.2),
+        ];
+        let graph = NetworkGraph::new(nodes, None);
+        let obs = hashes(&[0xA0, 0xB0, 0xC0]);
+
+        let posteriors = graph.hop_posteriors(&obs).expect("forward-backward failed");
+        assert_eq!(posteriors.len(), 3);
+
+        for hop in &posteriors {
+            let total: f64 = hop.iter().map(|c| c.probability).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+        }
+
+        // The ends are near certain
+        assert_eq!(posteriors[0][0].node, PathNode::Known(0));
+        assert!(posteriors[0][0].probability > 0.99);
+        assert_eq!(posteriors[2][0].node, PathNode::Known(1));
+        assert!(posteriors[2][0].probability > 0.99);
+
+        // The middle hop is split between the two B0s, favouring the one on the line
+        let middle = &posteriors[1];
+        assert_eq!(middle[0].node, PathNode::Known(2));
+        assert_eq!(middle[1].node, PathNode::Known(3));
+        assert!(middle[0].probability > 0.5 && middle[0].probability < 0.9);
+        assert!(middle[1].probability > 0.1);
+        assert!(middle.iter().any(|c| c.node == PathNode::Unknown(PathHash::byte(0xB0))));
+    }
+
+    #[test]
+    fn test_k_factor_sweep() {
+        // A -> B is ~67km: beyond the geometric horizon for 30m masts, but reachable
+        // once refraction flattens the earth bulge.
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.6),
+        ];
+        let observations = vec![hashes(&[0xA0, 0xB0])];
+
+        let results = k_factor_sweep(
+            &nodes,
+            None,
+            &SigmoidModel::default(),
+            &DecoderConfig::default(),
+            &[1.0, 4.0 / 3.0, 2.0],
+            &observations,
+        );
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].edge_count, 0);
+        assert_eq!(results[0].unknown_hops, 1);
+        assert_eq!(results[2].edge_count, 2);
+        assert_eq!(results[2].known_hops, 2);
+        assert!(results.iter().all(|r| r.failed_packets == 0));
+    }
+
+    #[test]
+    fn test_set_inferred_nodes() {
+        // A (0,0) -> ? (CC) -> B (0,0.4): nothing in the database has prefix CC
+        let nodes = vec![
+            create_node("A00000", 0.0, 0.0),
+            create_node("B00000", 0.0, 0.4),
+        ];
+        let mut graph = NetworkGraph::new(nodes, None);
+        let base_edges = graph.edge_count();
+        let path = graph.decode_path(&hashes(&[0xA0, 0xCC, 0xB0])).unwrap();
+        assert!(matches!(path.hops[1], PathNode::Unknown(_)));
+
+        // Promote a provisional CC halfway along: it gets linked and resolves the hop
+        graph.set_inferred_nodes(vec![create_node("CC", 0.0, 0.2)]);
+        assert_eq!(graph.inferred_count(), 1);
+        assert!(graph.nodes()[2].inferred);
+        assert!(graph.link_cost(0, 2).is_some() && graph.link_cost(2, 1).is_some());
+        assert_eq!(graph.inferred_edge_count(), 4);
+        assert_eq!(graph.edge_count(), base_edges + 4);
+        let path = graph.decode_path(&hashes(&[0xA0, 0xCC, 0xB0])).unwrap();
+        assert_eq!(path.hops, vec![PathNode::Known(0), PathNode::Known(2), PathNode::Known(1)]);
+
+        // A 2-byte CC hop is a different repeater, which the 1-byte ID can't claim
+        let wide = [PathHash::parse("a000").unwrap(), PathHash::parse("cc01").unwrap(), PathHash::parse("b000").unwrap()];
+        assert!(matches!(graph.decode_path(&wide).unwrap().hops[1], PathNode::Unknown(_)));
+
+        // Replacing the inferred nodes removes the old ones and their links
+        graph.set_inferred_nodes(Vec::new());
+        assert_eq!(graph.inferred_count(), 0);
+        assert_eq!(graph.edge_count(), base_edges);
+        let path = graph.decode_path(&hashes(&[0xA0, 0xCC, 0xB0])).unwrap();
+        assert!(matches!(path.hops[1], PathNode::Unknown(_)));
+    }
 }
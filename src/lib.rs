@@ -1,9 +1,15 @@
+pub mod calibration;
+pub mod cli;
 pub mod graph;
 #[cfg(test)]
 mod graph_tests;
 pub mod localization;
 pub mod models;
+pub mod parallel;
 pub mod pathfinding;
 pub mod physics;
+pub mod promotion;
+pub mod propagation;
+pub mod srtm;
 pub mod terrain;
 pub mod test_utils;
@@ -1,104 +1,990 @@
-use crate::models::{PathNode, Repeater};
+use crate::graph::Anchor;
+use crate::models::{PathHash, PathNode, Repeater};
+use crate::parallel::parallel_map;
 use crate::physics::haversine_distance;
-use serde::Serialize;
+use crate::propagation::PropagationModel;
+use crate::terrain::TerrainMap;
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
 
 const DBSCAN_EPSILON_KM: f64 = 50.0;
 const DBSCAN_MIN_POINTS: usize = 1;
 
+/// Meters per degree of latitude (and of longitude at the equator) on the haversine sphere.
+const METERS_PER_DEG: f64 = 111_195.0;
+
+/// The smallest area a cluster is taken to cover when checking it against known repeaters.
+const OVERLAP_MIN_RADIUS_KM: f64 = 10.0;
+
+/// Observations (and distinct witnesses) at which each confidence factor reaches one half.
+const CONFIDENCE_HALF_SUPPORT: f64 = 2.0;
+
+/// A search grid edge cell within this much negative log-likelihood of the best (a
+/// likelihood ratio of 1/20) means the likelihood has not fallen off inside the grid.
+const FLAT_EDGE_NLL: f64 = 3.0;
+
+/// Points a run of Unknowns may sit at along its gap: the gap is cut into this many steps.
+const RUN_FIT_STEPS: usize = 20;
+
+/// Largest search grid a config may ask for, in cells either side of the center.
+const MAX_HALF_CELLS: f64 = 2_000.0;
+
+/// Scale from 1-sigma axes to the 95% ellipse of a 2D Gaussian: sqrt(chi2(2 dof, 0.95)).
+const ELLIPSE_95_SCALE: f64 = 2.447_746_830_680_816;
+
 /// Represents an inferred unknown repeater location.
 ///
-/// **Note:** The `lat` and `lon` fields are currently calculated as the centroid
-/// of all `LinkMidpoint`s in the cluster. This is a first-order approximation
-/// and may not be highly accurate, especially for geometries where the
-/// repeater is not near the path midpoint.
+/// **Note:** With the default `LocationEstimator::Centroid`, the `lat` and `lon` fields are
+/// the centroid of all `LinkMidpoint`s in the cluster (Pass 1). This is a first-order
+/// approximation and may not be highly accurate, especially for geometries where the
+/// repeater is not near the path midpoint; `LocationEstimator::Multilateration` places it
+/// at the maximum-likelihood position instead, and fills in `multilateration`.
+/// `candidates` holds the terrain-aware refinement when Pass 2 was run.
+///
+/// The spread, overlap and confidence fields describe the cluster around the reported
+/// position: the best Pass 2 candidate when the heatmap ran, otherwise (`lat`, `lon`).
+/// Together with the witnesses and timing they help decide which sites are worth visiting.
 #[derive(Debug, Serialize, Clone, PartialEq)]
 pub struct InferredRepeater {
     pub prefix: String,
     pub lat: f64,
     pub lon: f64,
     pub observation_count: usize,
+    /// Root mean square distance of the cluster's midpoints from the reported position.
+    pub std_dev_km: f64,
+    /// Distance of the farthest midpoint from the reported position.
+    pub radius_km: f64,
+    /// IDs of the distinct known repeaters either side of the Unknown (or of its run of
+    /// Unknowns), sorted. Packet end points are witnesses too, but have no ID.
+    pub witnesses: Vec<String>,
+    /// Earliest and latest timestamp of the packets in the cluster, compared as strings
+    /// (so ISO 8601 in a single time zone), when the observations had them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+    /// Ranking score in (0, 1): more observations, more distinct witnesses and a tighter
+    /// cluster all raise it. A heuristic for ordering sites, not a probability.
+    pub confidence: f64,
+    /// A known repeater with a matching ID lies within the cluster's radius (at least
+    /// 10km), so the hops may really be that repeater.
+    pub overlaps_known: bool,
+    /// Multilateration fit with its uncertainty, when that estimator was used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multilateration: Option<MultilaterationFit>,
+    /// Pass 2 candidate locations, best (cheapest mean link) first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<CandidateLocation>,
+}
+
+/// How `localize_unknowns_with_model` places each cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationEstimator {
+    /// Centroid of the K->U->K midpoints.
+    #[default]
+    Centroid,
+    /// Maximum-likelihood position given every witness link (see `MultilaterationFit`).
+    Multilateration,
+}
+
+/// Options for `localize_unknowns_with_model`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LocalizationConfig {
+    pub estimator: LocationEstimator,
+    pub multilateration: MultilaterationConfig,
+    /// Pass 2 search grid; `None` skips the heatmap.
+    pub heatmap: Option<HeatmapConfig>,
+}
+
+impl LocalizationConfig {
+    /// Validates the multilateration and heatmap grids.
+    pub fn validate(&self) -> Result<()> {
+        self.multilateration.validate().context("Invalid multilateration config")?;
+        if let Some(heatmap) = &self.heatmap {
+            heatmap.validate().context("Invalid heatmap config")?;
+        }
+        Ok(())
+    }
+}
+
+/// Search grid for the multilateration likelihood.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MultilaterationConfig {
+    /// Half-width of the square area searched around the cluster centroid.
+    pub radius_km: f64,
+    /// Grid step for the coarse search; the best cell is then refined ten times finer.
+    pub cell_size_m: f64,
+}
+
+impl Default for MultilaterationConfig {
+    fn default() -> Self {
+        MultilaterationConfig {
+            radius_km: 60.0,
+            cell_size_m: 1000.0,
+        }
+    }
+}
+
+impl MultilaterationConfig {
+    /// Reads a config from a YAML file; see `LocalizationConfig::validate`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open multilateration config {}", path.display()))?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    /// Checks that the search grid is non-empty and of a sane size.
+    pub fn validate(&self) -> Result<()> {
+        validate_grid(self.radius_km, self.cell_size_m)
+    }
+}
+
+/// Maximum-likelihood position of a hidden repeater from its witness links.
+///
+/// Every observation says the Unknown heard the witness before it and was heard by the one
+/// after it, so each link cost acts as a soft range constraint and the likelihood of a
+/// position is `exp(-sum of link costs)`. The covariance is the normalised likelihood's
+/// spread about the reported position.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct MultilaterationFit {
+    pub lat: f64,
+    pub lon: f64,
+    /// Sum of the witness link costs at (`lat`, `lon`).
+    pub neg_log_likelihood: f64,
+    /// Covariance of the position in m², as [[east, east-north], [east-north, north]].
+    pub covariance_m2: [[f64; 2]; 2],
+    /// 95% uncertainty ellipse around (`lat`, `lon`).
+    pub ellipse: UncertaintyEllipse,
+    /// The likelihood is still high at the edge of the search grid, so the witnesses barely
+    /// constrain the position and the ellipse is clipped to `MultilaterationConfig::radius_km`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub flat_likelihood: bool,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct UncertaintyEllipse {
+    pub semi_major_m: f64,
+    pub semi_minor_m: f64,
+    /// Bearing of the major axis, degrees clockwise from north in [0, 180).
+    pub orientation_deg: f64,
+}
+
+/// One connected blob of top-scoring cells from the Pass 2 reachability heatmap.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct CandidateLocation {
+    /// Center of mass of the blob.
+    pub lat: f64,
+    pub lon: f64,
+    pub area_m2: f64,
+    /// Mean cost of the links to the witnesses reached, over the blob (a proxy for RSSI).
+    pub mean_link_cost: f64,
+    /// Witnesses (repeaters, packet end points or neighbouring Unknowns) reachable from
+    /// every cell of the blob.
+    pub witnesses_reached: usize,
+}
+
+/// Search grid for Pass 2 (see `localize_unknowns_with_model`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeatmapConfig {
+    /// Half-width of the square grid searched around each cluster centroid.
+    pub radius_km: f64,
+    /// Grid step. SRTM resolution (30m) is exact but slow over a whole cluster.
+    pub cell_size_m: f64,
+    /// A link costing more than this does not count as reaching a witness.
+    pub max_link_cost: f64,
+}
+
+impl Default for HeatmapConfig {
+    fn default() -> Self {
+        HeatmapConfig {
+            radius_km: 10.0,
+            cell_size_m: 250.0,
+            max_link_cost: 5.0,
+        }
+    }
+}
+
+impl HeatmapConfig {
+    /// Reads a config from a YAML file; see `LocalizationConfig::validate`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open heatmap config {}", path.display()))?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    /// Checks that the search grid is non-empty and of a sane size, and that
+    /// `max_link_cost` is a number.
+    pub fn validate(&self) -> Result<()> {
+        validate_grid(self.radius_km, self.cell_size_m)?;
+        if self.max_link_cost.is_nan() {
+            return Err(anyhow!("max_link_cost must be a number"));
+        }
+        Ok(())
+    }
 }
 
-/// Represents the geometric midpoint between two Known nodes in a
-/// `Known -> Unknown -> Known` path sequence.
+/// A search grid needs a finite, non-negative radius, a positive cell size, and at most
+/// `MAX_HALF_CELLS` cells either side of the center.
+fn validate_grid(radius_km: f64, cell_size_m: f64) -> Result<()> {
+    if !(radius_km.is_finite() && radius_km >= 0.0) {
+        return Err(anyhow!("radius_km must be finite and non-negative, got {}", radius_km));
+    }
+    if !(cell_size_m.is_finite() && cell_size_m > 0.0) {
+        return Err(anyhow!("cell_size_m must be finite and positive, got {}", cell_size_m));
+    }
+    let half_cells = (radius_km * 1000.0 / cell_size_m).ceil();
+    if half_cells > MAX_HALF_CELLS {
+        return Err(anyhow!(
+            "radius_km {} over cell_size_m {} gives {} cells either side, more than {}",
+            radius_km, cell_size_m, half_cells, MAX_HALF_CELLS
+        ));
+    }
+    Ok(())
+}
+
+/// A decoded path with the coordinates of its ends, for localization.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PathObservation {
+    pub path: Vec<PathNode>,
+    /// Where the packet started: a witness for an Unknown first hop.
+    pub origin: Option<Anchor>,
+    /// Where the packet was heard: a witness for an Unknown last hop.
+    pub observer: Option<Anchor>,
+    /// When the packet was logged, for `InferredRepeater::first_seen` and `last_seen`.
+    pub timestamp: Option<String>,
+}
+
+impl From<Vec<PathNode>> for PathObservation {
+    fn from(path: Vec<PathNode>) -> Self {
+        PathObservation {
+            path,
+            ..Default::default()
+        }
+    }
+}
+
+/// A position an Unknown was linked to: a Known repeater, a packet end point, or the
+/// neighbouring Unknown of a run at its fitted position (see `fit_run`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WitnessSite {
+    Node(usize),
+    Point(Anchor),
+    Unknown { hash: PathHash, lat: f64, lon: f64 },
+}
+
+/// Represents where an Unknown is expected on the straight line between the known
+/// positions either side of it: the midpoint for `Known -> Unknown -> Known`, and the
+/// joint fit of the whole run (see `fit_run`) for `Known -> Unknown -> Unknown -> Known`.
 ///
 /// This serves as a rough proxy for the location of the Unknown node.
 #[derive(Debug, Clone)]
 struct LinkMidpoint {
+    /// The Unknown's logged hash.
+    hash: PathHash,
     lat: f64,
     lon: f64,
+    /// The witnesses either side: `from` transmitted towards the Unknown, `to` heard from it.
+    from: WitnessSite,
+    to: WitnessSite,
+    /// The known positions either side of the run, i.e. `from` and `to` for a lone Unknown.
+    run_ends: [WitnessSite; 2],
+    /// Timestamp of the packet it came from.
+    timestamp: Option<String>,
 }
 
-/// Identifies unknown repeaters by finding K->U->K triplets in paths,
-/// calculating midpoints, and clustering them.
+/// Midpoints of compatible hashes that DBSCAN grouped together, i.e. one hidden repeater.
+struct Cluster {
+    /// The widest hash among the points; every point's hash is a prefix of it.
+    prefix: PathHash,
+    points: Vec<LinkMidpoint>,
+}
+
+/// Identifies unknown repeaters by finding runs of Unknowns between Known hops in paths
+/// (K->U->K, K->U->U->K, ...), placing them along the gap, and clustering them (Pass 1).
+///
+/// Without a propagation model there is nothing to fit a run against, so its Unknowns are
+/// spaced evenly along the gap; `localize_unknowns_with_model` fits them jointly.
 pub fn localize_unknowns(
     paths: &[Vec<PathNode>],
     known_nodes: &[Repeater],
 ) -> Vec<InferredRepeater> {
-    let mut observations_by_prefix: HashMap<u8, Vec<LinkMidpoint>> = HashMap::new();
+    let observations: Vec<PathObservation> = paths.iter().cloned().map(PathObservation::from).collect();
+    let mut results: Vec<InferredRepeater> = find_clusters(&observations, known_nodes, None, None)
+        .iter()
+        .map(|cluster| {
+            let (lat, lon) = centroid(cluster);
+            describe_cluster(cluster, lat, lon, lat, lon, known_nodes)
+        })
+        .collect();
+    sort_results(&mut results);
+    results
+}
 
-    // 1. Extract Midpoints from K->U->K triplets
-    for path in paths {
-        if path.len() < 3 {
-            continue;
-        }
+/// Clusters Unknowns like `localize_unknowns`, then places each cluster with
+/// `config.estimator` and, if `config.heatmap` is set, refines it (Pass 2), with link
+/// costs from `model`.
+///
+/// The packet's origin and observer count as known positions too, so Unknowns at either
+/// end of a path are located against them.
+///
+/// The Unknowns of a run (K->U->U->K) are placed together along the gap, by the joint
+/// likelihood of the legs from the known positions and the links between neighbouring
+/// Unknowns (see `fit_run`). Each neighbour then counts as a witness at its fitted
+/// position, so those U->U links also enter the multilateration and heatmap scores.
+///
+/// Pass 2 scores a grid of `cell_size_m` cells around the estimate by how many witnesses
+/// (the known positions either side of the Unknown) a repeater in that cell could
+/// reach. The cells sharing the best score are grouped into connected components, and each
+/// one becomes a `CandidateLocation`. Midpoints tend to fall in valleys, while real
+/// repeaters sit on hills that can see both witnesses.
+///
+/// A multilateration or heatmap grid that fails `LocalizationConfig::validate` is skipped.
+pub fn localize_unknowns_with_model(
+    observations: &[PathObservation],
+    known_nodes: &[Repeater],
+    model: &dyn PropagationModel,
+    terrain: Option<&TerrainMap>,
+    config: &LocalizationConfig,
+) -> Vec<InferredRepeater> {
+    let mut results: Vec<InferredRepeater> = find_clusters(observations, known_nodes, Some(model), terrain)
+        .iter()
+        .map(|cluster| {
+            let (mut lat, mut lon) = centroid(cluster);
+            let mut multilateration = None;
+            if config.estimator == LocationEstimator::Multilateration {
+                multilateration = multilaterate(
+                    cluster,
+                    lat,
+                    lon,
+                    known_nodes,
+                    model,
+                    terrain,
+                    &config.multilateration,
+                );
+                if let Some(fit) = &multilateration {
+                    (lat, lon) = (fit.lat, fit.lon);
+                }
+            }
+            let candidates = match &config.heatmap {
+                Some(heatmap) => {
+                    reachability_candidates(cluster, lat, lon, known_nodes, model, terrain, heatmap)
+                }
+                None => Vec::new(),
+            };
 
-        for window in path.windows(3) {
-            if let [PathNode::Known(k1_idx), PathNode::Unknown(u_prefix), PathNode::Known(k2_idx)] =
-                window
-            {
-                let k1 = &known_nodes[*k1_idx];
-                let k2 = &known_nodes[*k2_idx];
-
-                // Simple midpoint calculation (flat earth approximation is sufficient for local midpoints)
-                // or just average lat/lon.
-                let mid_lat = (k1.lat + k2.lat) / 2.0;
-                let mid_lon = (k1.lon + k2.lon) / 2.0;
-
-                observations_by_prefix
-                    .entry(*u_prefix)
-                    .or_default()
-                    .push(LinkMidpoint {
-                        lat: mid_lat,
-                        lon: mid_lon,
-                    });
+            // Describe the cluster around the position finally reported
+            let (site_lat, site_lon) = candidates.first().map_or((lat, lon), |c| (c.lat, c.lon));
+            InferredRepeater {
+                multilateration,
+                candidates,
+                ..describe_cluster(cluster, lat, lon, site_lat, site_lon, known_nodes)
             }
-        }
-    }
+        })
+        .collect();
+    sort_results(&mut results);
+    results
+}
 
-    let mut results = Vec::new();
+fn find_clusters(
+    observations: &[PathObservation],
+    known_nodes: &[Repeater],
+    model: Option<&dyn PropagationModel>,
+    terrain: Option<&TerrainMap>,
+) -> Vec<Cluster> {
+    // 1. Place every run of Unknowns between two known positions along the gap
+    let midpoints = parallel_map(observations, 0, |observation| {
+        place_unknowns(observation, known_nodes, model, terrain)
+    });
 
-    // 2. Cluster observations for each prefix
-    for (prefix, obs_list) in observations_by_prefix {
-        let clusters = dbscan(&obs_list, DBSCAN_EPSILON_KM, DBSCAN_MIN_POINTS);
+    // Keyed by the first byte: a 1-byte `a1` may be the same repeater as a 2-byte `a1b2`
+    let mut midpoints_by_family: HashMap<PathHash, Vec<LinkMidpoint>> = HashMap::new();
+    for point in midpoints.into_iter().flatten() {
+        midpoints_by_family.entry(point.hash.truncate(1)).or_default().push(point);
+    }
 
-        for cluster in clusters {
+    // 2. Cluster observations for each family, then split clusters holding incompatible hashes
+    let mut clusters = Vec::new();
+    for obs_list in midpoints_by_family.into_values() {
+        for cluster in dbscan(&obs_list, DBSCAN_EPSILON_KM, DBSCAN_MIN_POINTS) {
             if cluster.is_empty() {
                 continue;
             }
+            clusters.extend(split_by_hash(cluster.into_iter().cloned().collect()));
+        }
+    }
+    clusters
+}
 
-            // Calculate centroid
-            let count = cluster.len();
-            let sum_lat: f64 = cluster.iter().map(|p| p.lat).sum();
-            let sum_lon: f64 = cluster.iter().map(|p| p.lon).sum();
+/// The Unknowns of one observation, each placed on the straight line between the known
+/// positions either side of its run: a lone Unknown on the midpoint, a longer run by
+/// `fit_run` (or evenly spaced without a `model`).
+fn place_unknowns(
+    observation: &PathObservation,
+    known_nodes: &[Repeater],
+    model: Option<&dyn PropagationModel>,
+    terrain: Option<&TerrainMap>,
+) -> Vec<LinkMidpoint> {
+    // The path with its end points: a known position, or the Unknown's hash
+    let mut stops: Vec<Result<(WitnessSite, f64, f64), PathHash>> = Vec::new();
+    if let Some(origin) = observation.origin {
+        stops.push(Ok((WitnessSite::Point(origin), origin.lat, origin.lon)));
+    }
+    for node in &observation.path {
+        stops.push(match node {
+            PathNode::Known(idx) => Ok((WitnessSite::Node(*idx), known_nodes[*idx].lat, known_nodes[*idx].lon)),
+            PathNode::Unknown(hash) => Err(*hash),
+        });
+    }
+    if let Some(observer) = observation.observer {
+        stops.push(Ok((WitnessSite::Point(observer), observer.lat, observer.lon)));
+    }
 
-            results.push(InferredRepeater {
-                prefix: format!("{:02x}", prefix),
-                lat: sum_lat / count as f64,
-                lon: sum_lon / count as f64,
-                observation_count: count,
+    let mut midpoints = Vec::new();
+    let fixed: Vec<usize> = (0..stops.len()).filter(|&i| stops[i].is_ok()).collect();
+    for pair in fixed.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let (Ok((site1, lat1, lon1)), Ok((site2, lat2, lon2))) = (stops[start], stops[end]) else {
+            continue;
+        };
+        let hashes: Vec<PathHash> = stops[start + 1..end].iter().filter_map(|stop| stop.err()).collect();
+        if hashes.is_empty() {
+            continue;
+        }
+
+        // Fractions of the way along the gap (flat earth approximation is sufficient locally)
+        let fractions = match model {
+            Some(model) if hashes.len() > 1 => fit_run(
+                &witness_repeater(site1, known_nodes),
+                &witness_repeater(site2, known_nodes),
+                &hashes,
+                model,
+                terrain,
+            ),
+            _ => even_spacing(hashes.len()),
+        };
+        let positions: Vec<(f64, f64)> = fractions
+            .iter()
+            .map(|t| (lat1 + (lat2 - lat1) * t, lon1 + (lon2 - lon1) * t))
+            .collect();
+        let neighbour = |k: usize| WitnessSite::Unknown {
+            hash: hashes[k],
+            lat: positions[k].0,
+            lon: positions[k].1,
+        };
+
+        for (k, &hash) in hashes.iter().enumerate() {
+            midpoints.push(LinkMidpoint {
+                hash,
+                lat: positions[k].0,
+                lon: positions[k].1,
+                from: if k == 0 { site1 } else { neighbour(k - 1) },
+                to: if k + 1 == hashes.len() { site2 } else { neighbour(k + 1) },
+                run_ends: [site1, site2],
+                timestamp: observation.timestamp.clone(),
             });
         }
     }
+    midpoints
+}
+
+/// `count` Unknowns evenly spaced along a gap, as fractions of the way; one lands on the midpoint.
+fn even_spacing(count: usize) -> Vec<f64> {
+    (1..=count).map(|k| k as f64 / (count + 1) as f64).collect()
+}
 
-    // Sort for deterministic output
+/// Fits the run of Unknowns `hashes` between `first` and `last` (the known positions either
+/// side, in the direction of travel) jointly, as fractions of the way along the gap.
+///
+/// Each Unknown sits at one of the points cutting the gap into `RUN_FIT_STEPS` steps, in path
+/// order, and a placement of the whole run has likelihood `exp(-sum of link costs)` over the
+/// chain: the leg from `first`, the links between neighbouring Unknowns and the leg to `last`.
+/// Forward-backward over the chain gives each Unknown's marginal, whose mean is returned, so
+/// where every link is easy the run settles at even spacing rather than an arbitrary point.
+/// An uneven chain (a weak handheld leg, a link through a gap in the hills) pulls its
+/// Unknowns off even spacing. Falls back to even spacing when no placement has a finite cost.
+fn fit_run(
+    first: &Repeater,
+    last: &Repeater,
+    hashes: &[PathHash],
+    model: &dyn PropagationModel,
+    terrain: Option<&TerrainMap>,
+) -> Vec<f64> {
+    let fractions: Vec<f64> = (1..RUN_FIT_STEPS).map(|j| j as f64 / RUN_FIT_STEPS as f64).collect();
+    // Stand-ins for the run's repeaters; the ID does not affect the link costs
+    let sites: Vec<Repeater> = fractions
+        .iter()
+        .map(|t| {
+            let lat = first.lat + (last.lat - first.lat) * t;
+            let lon = first.lon + (last.lon - first.lon) * t;
+            candidate_site(hashes[0], lat, lon)
+        })
+        .collect();
+    let points = sites.len();
+    // Relays hand the packet on along the gap, so each Unknown is past the one before
+    let links: Vec<Vec<f64>> = (0..points)
+        .map(|a| {
+            (0..points)
+                .map(|b| if b > a { model.link_cost(&sites[a], &sites[b], terrain) } else { f64::INFINITY })
+                .collect()
+        })
+        .collect();
+
+    // forward[i][b]: cost of the chain from `first` with Unknown i at point b;
+    // backward[i][a]: cost of the rest of the chain to `last` with Unknown i at point a
+    let mut forward = vec![sites.iter().map(|site| model.link_cost(first, site, terrain)).collect::<Vec<f64>>()];
+    let mut backward = vec![sites.iter().map(|site| model.link_cost(site, last, terrain)).collect::<Vec<f64>>()];
+    for _ in 1..hashes.len() {
+        let prev = &forward[forward.len() - 1];
+        let step: Vec<f64> = (0..points)
+            .map(|b| neg_log_sum_exp((0..points).map(|a| prev[a] + links[a][b])))
+            .collect();
+        forward.push(step);
+
+        let next = &backward[backward.len() - 1];
+        let step: Vec<f64> = (0..points)
+            .map(|a| neg_log_sum_exp((0..points).map(|b| links[a][b] + next[b])))
+            .collect();
+        backward.push(step);
+    }
+    backward.reverse();
+
+    let mut means = Vec::with_capacity(hashes.len());
+    for (before, after) in forward.iter().zip(&backward) {
+        let costs: Vec<f64> = before.iter().zip(after).map(|(f, b)| f + b).collect();
+        let best = costs.iter().copied().fold(f64::INFINITY, f64::min);
+        if !best.is_finite() {
+            return even_spacing(hashes.len());
+        }
+        let weights: Vec<f64> = costs.iter().map(|c| (best - c).exp()).collect();
+        let total: f64 = weights.iter().sum();
+        means.push(weights.iter().zip(&fractions).map(|(w, t)| w * t).sum::<f64>() / total);
+    }
+    means
+}
+
+/// `-ln(sum(exp(-cost)))`: the cost of either of several alternatives, without underflow.
+fn neg_log_sum_exp(costs: impl Iterator<Item = f64> + Clone) -> f64 {
+    let best = costs.clone().fold(f64::INFINITY, f64::min);
+    if best == f64::INFINITY {
+        return best;
+    }
+    best - costs.map(|c| (best - c).exp()).sum::<f64>().ln()
+}
+
+/// Splits one spatial cluster into hidden repeaters whose hashes are compatible.
+///
+/// Each hash that no wider hash in the cluster extends names one repeater, and the points
+/// logged with a prefix of it join it. A narrower hash shared by several (`a1` among `a1b2`
+/// and `a1c3` points) joins the one whose other points' centroid is nearest.
+fn split_by_hash(points: Vec<LinkMidpoint>) -> Vec<Cluster> {
+    let mut hashes: Vec<PathHash> = points.iter().map(|p| p.hash).collect();
+    hashes.sort();
+    hashes.dedup();
+    let mut clusters: Vec<Cluster> = hashes
+        .iter()
+        .filter(|&&h| !hashes.iter().any(|&other| other != h && h.matches(other)))
+        .map(|&prefix| Cluster { prefix, points: Vec::new() })
+        .collect();
+
+    let mut ambiguous = Vec::new();
+    for point in points {
+        let mut matching = clusters.iter_mut().filter(|c| point.hash.matches(c.prefix));
+        match (matching.next(), matching.next()) {
+            (Some(cluster), None) => cluster.points.push(point),
+            _ => ambiguous.push(point),
+        }
+    }
+
+    // Every cluster holds the points of its own hash, so each has a centroid
+    let centroids: Vec<(f64, f64)> = clusters
+        .iter()
+        .map(|c| {
+            let n = c.points.len() as f64;
+            (
+                c.points.iter().map(|p| p.lat).sum::<f64>() / n,
+                c.points.iter().map(|p| p.lon).sum::<f64>() / n,
+            )
+        })
+        .collect();
+    for point in ambiguous {
+        let nearest = (0..clusters.len())
+            .filter(|&i| point.hash.matches(clusters[i].prefix))
+            .min_by(|&i, &j| {
+                let di = haversine_distance(point.lat, point.lon, centroids[i].0, centroids[i].1);
+                let dj = haversine_distance(point.lat, point.lon, centroids[j].0, centroids[j].1);
+                di.total_cmp(&dj)
+            });
+        if let Some(i) = nearest {
+            clusters[i].points.push(point);
+        }
+    }
+    clusters
+}
+
+/// Pass 1 estimate: the centroid of the cluster's midpoints.
+fn centroid(cluster: &Cluster) -> (f64, f64) {
+    let count = cluster.points.len() as f64;
+    let sum_lat: f64 = cluster.points.iter().map(|p| p.lat).sum();
+    let sum_lon: f64 = cluster.points.iter().map(|p| p.lon).sum();
+    (sum_lat / count, sum_lon / count)
+}
+
+/// The cluster placed at (`lat`, `lon`), with its metadata measured around the reported
+/// position (`site_lat`, `site_lon`).
+fn describe_cluster(
+    cluster: &Cluster,
+    lat: f64,
+    lon: f64,
+    site_lat: f64,
+    site_lon: f64,
+    known_nodes: &[Repeater],
+) -> InferredRepeater {
+    let count = cluster.points.len();
+
+    // Spread of the midpoints around the reported position
+    let distances: Vec<f64> = cluster
+        .points
+        .iter()
+        .map(|p| haversine_distance(site_lat, site_lon, p.lat, p.lon))
+        .collect();
+    let std_dev_km = (distances.iter().map(|d| d * d).sum::<f64>() / count as f64).sqrt();
+    let radius_km = distances.iter().cloned().fold(0.0, f64::max);
+
+    let mut witnesses: Vec<String> = cluster
+        .points
+        .iter()
+        .flat_map(|p| p.run_ends)
+        .filter_map(|site| match site {
+            WitnessSite::Node(idx) => Some(known_nodes[idx].id.clone()),
+            WitnessSite::Point(_) | WitnessSite::Unknown { .. } => None,
+        })
+        .collect();
+    witnesses.sort();
+    witnesses.dedup();
+
+    let timestamps = cluster.points.iter().filter_map(|p| p.timestamp.as_ref());
+    let first_seen = timestamps.clone().min().cloned();
+    let last_seen = timestamps.max().cloned();
+
+    // Each factor saturates towards 1; the spread is measured against the clustering radius
+    let support = count as f64 / (count as f64 + CONFIDENCE_HALF_SUPPORT);
+    let diversity = witnesses.len() as f64 / (witnesses.len() as f64 + CONFIDENCE_HALF_SUPPORT);
+    let compactness = (-std_dev_km / DBSCAN_EPSILON_KM).exp();
+
+    let overlap_km = radius_km.max(OVERLAP_MIN_RADIUS_KM);
+    let overlaps_known = known_nodes.iter().any(|node| {
+        !node.inferred
+            && node.path_hash(PathHash::MAX_WIDTH).is_some_and(|hash| cluster.prefix.matches(hash))
+            && haversine_distance(site_lat, site_lon, node.lat, node.lon) <= overlap_km
+    });
+
+    InferredRepeater {
+        prefix: cluster.prefix.to_string(),
+        lat,
+        lon,
+        observation_count: count,
+        std_dev_km,
+        radius_km,
+        witnesses,
+        first_seen,
+        last_seen,
+        confidence: support * diversity * compactness,
+        overlaps_known,
+        multilateration: None,
+        candidates: Vec::new(),
+    }
+}
+
+/// Sort for deterministic output
+fn sort_results(results: &mut [InferredRepeater]) {
     results.sort_by(|a, b| {
         a.prefix.cmp(&b.prefix)
             .then(a.lat.partial_cmp(&b.lat).unwrap_or(std::cmp::Ordering::Equal))
     });
+}
 
-    results
+/// Witness links of a cluster, as (witness, whether it transmitted towards the Unknown,
+/// number of observations), in order of first appearance.
+fn witness_links(cluster: &Cluster) -> Vec<(WitnessSite, bool, usize)> {
+    let mut links: Vec<(WitnessSite, bool, usize)> = Vec::new();
+    for p in &cluster.points {
+        for (witness, transmits) in [(p.from, true), (p.to, false)] {
+            match links.iter_mut().find(|(w, t, _)| *w == witness && *t == transmits) {
+                Some(link) => link.2 += 1,
+                None => links.push((witness, transmits, 1)),
+            }
+        }
+    }
+    links
+}
+
+/// A stand-in repeater at a candidate position, so links to it can be costed.
+fn candidate_site(prefix: PathHash, lat: f64, lon: f64) -> Repeater {
+    Repeater {
+        id: prefix.to_string(),
+        name: "Candidate".to_string(),
+        lat,
+        lon,
+        ..Default::default()
+    }
+}
+
+/// A repeater standing for `site`, so links to it can be costed by the propagation model.
+fn witness_repeater(site: WitnessSite, known_nodes: &[Repeater]) -> Cow<'_, Repeater> {
+    match site {
+        WitnessSite::Node(idx) => Cow::Borrowed(&known_nodes[idx]),
+        WitnessSite::Point(anchor) => Cow::Owned(anchor.as_repeater()),
+        WitnessSite::Unknown { hash, lat, lon } => Cow::Owned(candidate_site(hash, lat, lon)),
+    }
+}
+
+/// Cost of the link between `site` and `witness`, in the direction of travel.
+fn witness_link_cost(
+    site: &Repeater,
+    witness: &WitnessSite,
+    transmits: bool,
+    known_nodes: &[Repeater],
+    model: &dyn PropagationModel,
+    terrain: Option<&TerrainMap>,
+) -> f64 {
+    let witness = witness_repeater(*witness, known_nodes);
+    if transmits {
+        model.link_cost(&witness, site, terrain)
+    } else {
+        model.link_cost(site, &witness, terrain)
+    }
+}
+
+/// Maximum-likelihood position of the cluster's Unknown within `config.radius_km` of
+/// (`center_lat`, `center_lon`), or `None` if no position can reach its witnesses or the
+/// config does not validate.
+///
+/// The negative log-likelihood (sum of witness link costs, one per observation) is
+/// evaluated on a coarse grid, whose best cell is refined on a grid ten times finer.
+/// The covariance is taken over the coarse grid about the refined position, weighting each
+/// cell by its likelihood, plus the cell's own quantisation variance so a sharp peak never
+/// collapses to zero.
+fn multilaterate(
+    cluster: &Cluster,
+    center_lat: f64,
+    center_lon: f64,
+    known_nodes: &[Repeater],
+    model: &dyn PropagationModel,
+    terrain: Option<&TerrainMap>,
+    config: &MultilaterationConfig,
+) -> Option<MultilaterationFit> {
+    config.validate().ok()?;
+    let links = witness_links(cluster);
+    let meters_per_deg_lon = METERS_PER_DEG * center_lat.to_radians().cos();
+    let to_lat_lon = |east: f64, north: f64| {
+        (center_lat + north / METERS_PER_DEG, center_lon + east / meters_per_deg_lon)
+    };
+    let neg_log_likelihood = |east: f64, north: f64| {
+        let (lat, lon) = to_lat_lon(east, north);
+        let site = candidate_site(cluster.prefix, lat, lon);
+        links
+            .iter()
+            .map(|(witness, transmits, count)| {
+                *count as f64 * witness_link_cost(&site, witness, *transmits, known_nodes, model, terrain)
+            })
+            .sum::<f64>()
+    };
+
+    // Coarse grid, as (east, north, nll) in meters from the center
+    let half_cells = (config.radius_km * 1000.0 / config.cell_size_m).ceil() as i64;
+    let offsets: Vec<f64> = (-half_cells..=half_cells).map(|i| i as f64 * config.cell_size_m).collect();
+    let grid: Vec<(f64, f64, f64)> = parallel_map(&offsets, 0, |&north| {
+        offsets
+            .iter()
+            .map(|&east| (east, north, neg_log_likelihood(east, north)))
+            .collect::<Vec<_>>()
+    })
+    .into_iter()
+    .flatten()
+    .filter(|&(_, _, nll)| nll.is_finite())
+    .collect();
+
+    let &(coarse_east, coarse_north, min_nll) = grid.iter().min_by(|a, b| a.2.total_cmp(&b.2))?;
+
+    // Refine the best coarse cell
+    let fine_step = config.cell_size_m / 10.0;
+    let (mut best_east, mut best_north, mut best_nll) = (coarse_east, coarse_north, min_nll);
+    for i in -10..=10 {
+        for j in -10..=10 {
+            let (east, north) = (coarse_east + j as f64 * fine_step, coarse_north + i as f64 * fine_step);
+            let nll = neg_log_likelihood(east, north);
+            if nll < best_nll {
+                (best_east, best_north, best_nll) = (east, north, nll);
+            }
+        }
+    }
+
+    // Likelihood-weighted covariance over the coarse grid, about the refined position
+    let weights: Vec<f64> = grid.iter().map(|&(_, _, nll)| (min_nll - nll).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    let mut cov = [[0.0; 2]; 2];
+    for (&(east, north, _), w) in grid.iter().zip(&weights) {
+        let (de, dn) = (east - best_east, north - best_north);
+        cov[0][0] += w * de * de;
+        cov[0][1] += w * de * dn;
+        cov[1][1] += w * dn * dn;
+    }
+    let quantisation = config.cell_size_m * config.cell_size_m / 12.0;
+    cov[0][0] = cov[0][0] / total + quantisation;
+    cov[1][1] = cov[1][1] / total + quantisation;
+    cov[0][1] /= total;
+    cov[1][0] = cov[0][1];
+
+    let edge = half_cells as f64 * config.cell_size_m;
+    let flat_likelihood = grid.iter().any(|&(east, north, nll)| {
+        (east.abs() >= edge || north.abs() >= edge) && nll - min_nll < FLAT_EDGE_NLL
+    });
+
+    let (lat, lon) = to_lat_lon(best_east, best_north);
+    Some(MultilaterationFit {
+        lat,
+        lon,
+        neg_log_likelihood: best_nll,
+        covariance_m2: cov,
+        ellipse: uncertainty_ellipse(cov),
+        flat_likelihood,
+    })
+}
+
+/// 95% ellipse of a 2x2 (east, north) covariance, from its eigenvalues and eigenvectors.
+fn uncertainty_ellipse(cov: [[f64; 2]; 2]) -> UncertaintyEllipse {
+    let (a, b, d) = (cov[0][0], cov[0][1], cov[1][1]);
+    let mean = (a + d) / 2.0;
+    let spread = (((a - d) / 2.0).powi(2) + b * b).sqrt();
+    let (major, minor) = (mean + spread, (mean - spread).max(0.0));
+
+    // Angle of the major axis from east, counter-clockwise; as a bearing from north
+    let from_east = 0.5 * (2.0 * b).atan2(a - d);
+    let orientation_deg = (90.0 - from_east.to_degrees()).rem_euclid(180.0);
+
+    UncertaintyEllipse {
+        semi_major_m: ELLIPSE_95_SCALE * major.sqrt(),
+        semi_minor_m: ELLIPSE_95_SCALE * minor.sqrt(),
+        orientation_deg,
+    }
+}
+
+/// Pass 2: scores a grid around (`center_lat`, `center_lon`) by witness reachability and
+/// returns the connected components of the best-scoring cells (none if the config does
+/// not validate).
+fn reachability_candidates(
+    cluster: &Cluster,
+    center_lat: f64,
+    center_lon: f64,
+    known_nodes: &[Repeater],
+    model: &dyn PropagationModel,
+    terrain: Option<&TerrainMap>,
+    config: &HeatmapConfig,
+) -> Vec<CandidateLocation> {
+    if config.validate().is_err() {
+        return Vec::new();
+    }
+    let witnesses = witness_links(cluster);
+
+    let half_cells = (config.radius_km * 1000.0 / config.cell_size_m).ceil() as i64;
+    let side = (2 * half_cells + 1) as usize;
+    let lat_step = config.cell_size_m / METERS_PER_DEG;
+    let lon_step = config.cell_size_m / (METERS_PER_DEG * center_lat.to_radians().cos());
+    let cell_position = |row: usize, col: usize| {
+        (
+            center_lat + (row as i64 - half_cells) as f64 * lat_step,
+            center_lon + (col as i64 - half_cells) as f64 * lon_step,
+        )
+    };
+
+    // Score every cell: (distinct witnesses reached, mean cost of those links)
+    let rows: Vec<usize> = (0..side).collect();
+    let scores: Vec<Vec<(usize, f64)>> = parallel_map(&rows, 0, |&row| {
+        (0..side)
+            .map(|col| {
+                let (lat, lon) = cell_position(row, col);
+                let site = candidate_site(cluster.prefix, lat, lon);
+                let mut best_by_witness: Vec<(WitnessSite, f64)> = Vec::new();
+                for (witness, transmits, _) in &witnesses {
+                    let cost = witness_link_cost(&site, witness, *transmits, known_nodes, model, terrain);
+                    if cost.is_finite() && cost <= config.max_link_cost {
+                        match best_by_witness.iter_mut().find(|(w, _)| w == witness) {
+                            Some(best) => best.1 = best.1.min(cost),
+                            None => best_by_witness.push((*witness, cost)),
+                        }
+                    }
+                }
+                let reached = best_by_witness.len();
+                let mean_cost = if reached == 0 {
+                    f64::INFINITY
+                } else {
+                    best_by_witness.iter().map(|(_, c)| c).sum::<f64>() / reached as f64
+                };
+                (reached, mean_cost)
+            })
+            .collect()
+    });
+
+    let max_score = scores.iter().flatten().map(|&(reached, _)| reached).max().unwrap_or(0);
+    if max_score == 0 {
+        return Vec::new();
+    }
+
+    // Connected components (4-neighbourhood) of the cells with the best score
+    let mut visited = vec![vec![false; side]; side];
+    let mut candidates = Vec::new();
+    for start_row in 0..side {
+        for start_col in 0..side {
+            if visited[start_row][start_col] || scores[start_row][start_col].0 != max_score {
+                continue;
+            }
+            visited[start_row][start_col] = true;
+            let mut stack = vec![(start_row, start_col)];
+            let (mut cells, mut sum_lat, mut sum_lon, mut sum_cost) = (0usize, 0.0, 0.0, 0.0);
+            while let Some((row, col)) = stack.pop() {
+                let (lat, lon) = cell_position(row, col);
+                cells += 1;
+                sum_lat += lat;
+                sum_lon += lon;
+                sum_cost += scores[row][col].1;
+
+                let neighbours = [
+                    (row.wrapping_sub(1), col),
+                    (row + 1, col),
+                    (row, col.wrapping_sub(1)),
+                    (row, col + 1),
+                ];
+                for (r, c) in neighbours {
+                    if r < side && c < side && !visited[r][c] && scores[r][c].0 == max_score {
+                        visited[r][c] = true;
+                        stack.push((r, c));
+                    }
+                }
+            }
+
+            candidates.push(CandidateLocation {
+                lat: sum_lat / cells as f64,
+                lon: sum_lon / cells as f64,
+                area_m2: cells as f64 * config.cell_size_m * config.cell_size_m,
+                mean_link_cost: sum_cost / cells as f64,
+                witnesses_reached: max_score,
+            });
+        }
+    }
+
+    candidates.sort_by(|a, b| {
+        a.mean_link_cost
+            .total_cmp(&b.mean_link_cost)
+            .then(b.area_m2.total_cmp(&a.area_m2))
+    });
+    candidates
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -213,9 +1099,9 @@ mod tests {
         // Cluster 1: (0,0), (0, 0.1)
         // Cluster 2: (10, 10)
         let points = vec![
-            LinkMidpoint { lat: 0.0, lon: 0.0 },
-            LinkMidpoint { lat: 0.0, lon: 0.1 }, // ~11km away
-            LinkMidpoint { lat: 10.0, lon: 10.0 }, // far away
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 0.0, lon: 0.0, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None },
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 0.0, lon: 0.1, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None }, // ~11km away
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 10.0, lon: 10.0, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None }, // far away
         ];
 
         let epsilon = 20.0;
@@ -225,14 +1111,40 @@ mod tests {
         assert_eq!(clusters.len(), 2);
     }
 
+    #[test]
+    fn test_grid_configs_are_validated() {
+        assert!(HeatmapConfig::default().validate().is_ok());
+        assert!(MultilaterationConfig::default().validate().is_ok());
+
+        let bad_heatmaps = [
+            HeatmapConfig { cell_size_m: 0.0, ..HeatmapConfig::default() },
+            HeatmapConfig { cell_size_m: -30.0, ..HeatmapConfig::default() },
+            HeatmapConfig { radius_km: f64::NAN, ..HeatmapConfig::default() },
+            HeatmapConfig { radius_km: f64::INFINITY, ..HeatmapConfig::default() },
+            HeatmapConfig { radius_km: 1000.0, cell_size_m: 30.0, ..HeatmapConfig::default() },
+            HeatmapConfig { max_link_cost: f64::NAN, ..HeatmapConfig::default() },
+        ];
+        for config in &bad_heatmaps {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+        let bad_multilateration = MultilaterationConfig { cell_size_m: 0.0, ..MultilaterationConfig::default() };
+        assert!(bad_multilateration.validate().is_err());
+
+        let config = LocalizationConfig {
+            heatmap: Some(bad_heatmaps[0].clone()),
+            ..LocalizationConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
     #[test]
     fn test_dbscan_noise_filtering() {
         // With min_points = 2, isolated points should be noise
         // P1, P2 are close. P3 is isolated.
         let points = vec![
-            LinkMidpoint { lat: 0.0, lon: 0.0 },
-            LinkMidpoint { lat: 0.0, lon: 0.0001 }, // very close
-            LinkMidpoint { lat: 10.0, lon: 10.0 }, // far away
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 0.0, lon: 0.0, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None },
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 0.0, lon: 0.0001, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None }, // very close
+            LinkMidpoint { hash: PathHash::byte(0xA1), lat: 10.0, lon: 10.0, from: WitnessSite::Node(0), to: WitnessSite::Node(1), run_ends: [WitnessSite::Node(0), WitnessSite::Node(1)], timestamp: None }, // far away
         ];
 
         let epsilon = 1.0;
@@ -1,9 +1,21 @@
 use std::env;
 use std::fs::File;
 use std::error::Error;
-use app::models::{Repeater, PathNode};
-use app::graph::NetworkGraph;
-use app::localization;
+use app::calibration::PathLossParams;
+use app::cli;
+use app::models::{PathHash, Repeater, PathNode};
+use app::graph::{Anchor, DecodedPath, DecoderConfig, NetworkGraph, PacketPath, UnknownKind, k_factor_sweep};
+use app::parallel::parallel_map;
+use app::localization::{
+    self, HeatmapConfig, LocalizationConfig, LocationEstimator, MultilaterationConfig, PathObservation,
+};
+use app::promotion::{self, PromotionConfig};
+use app::propagation::{
+    FreeSpaceModel, LogDistanceModel, PropagationModel, SigmoidModel, TerrainAwareModel,
+};
+use app::srtm;
+use std::path::Path;
+use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
@@ -24,12 +36,226 @@ struct PathOutput {
     end_lat: f64,
     end_lon: f64,
     path: Vec<String>,
+    /// Posterior probability of each hop in `path` (forward-backward over all decodes).
+    /// Empty if the posteriors could not be computed for this packet.
+    confidence: Vec<f64>,
+    total_cost: f64,
+    /// Cost of the link from the last hop to the observer.
+    end_cost: f64,
+    /// Repeaters the chosen path passes through more than once (normally 0).
+    #[serde(skip_serializing_if = "is_zero")]
+    revisits: usize,
+    /// Of `revisits`, bounces straight back to the repeater two hops earlier.
+    #[serde(skip_serializing_if = "is_zero")]
+    bounces: usize,
+    /// None of the K best paths avoided the revisit, so it was kept.
+    #[serde(skip_serializing_if = "is_false")]
+    revisit_unavoidable: bool,
+    /// A repeater is assumed missing between the last hop and the observer.
+    #[serde(skip_serializing_if = "is_false")]
+    skipped_before_observer: bool,
+    /// Beam pruning dropped a state that might have led to a better path.
+    #[serde(skip_serializing_if = "is_false")]
+    pruning_may_have_changed: bool,
+    /// Per-hop decoding diagnostics, in `path` order.
+    hops: Vec<HopOutput>,
+    /// Runner-up decodes, when `--k-best` is given.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    alternatives: Vec<AlternativeOutput>,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+#[derive(Debug, Serialize)]
+struct HopOutput {
+    /// Cost of reaching this hop from the previous one (or from the origin).
+    step_cost: f64,
+    /// Known repeaters considered for this hop.
+    candidates: usize,
+    /// Set for Unknown hops: `forced` or `chosen`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unknown: Option<UnknownKind>,
+    /// Decoded as a corrupted byte: the repeater's prefix differs from the logged one.
+    #[serde(skip_serializing_if = "is_false")]
+    corrupted: bool,
+    /// A repeater is assumed missing from the path right before this hop.
+    #[serde(skip_serializing_if = "is_false")]
+    skipped_before: bool,
+    /// Resolved to a repeater inferred by `--promotion-config`, not one from the database.
+    #[serde(skip_serializing_if = "is_false")]
+    inferred: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct AlternativeOutput {
+    path: Vec<String>,
+    cost: f64,
+    gap_to_best: f64,
+}
+
+/// Optional `--flag value` arguments that follow the positional ones.
+#[derive(Debug, Default)]
+struct Options {
+    srtm_dir: Option<String>,
+    model: Option<String>,
+    radio_config: Option<String>,
+    calibration: Option<String>,
+    decoder_config: Option<String>,
+    heatmap_config: Option<String>,
+    multilateration_config: Option<String>,
+    promotion_config: Option<String>,
+    estimator: Option<String>,
+    k_factor: Option<f64>,
+    k_sweep: Vec<f64>,
+    k_best: usize,
+    /// Decoding threads; 0 uses one per available core.
+    threads: usize,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, Box<dyn Error>> {
+    let mut options = Options::default();
+    for (flag, value) in cli::option_pairs(args)? {
+        let value = value.to_string();
+        match flag {
+            "--srtm-dir" => options.srtm_dir = Some(value),
+            "--model" => options.model = Some(value),
+            "--radio-config" => options.radio_config = Some(value),
+            "--calibration" => options.calibration = Some(value),
+            "--decoder-config" => options.decoder_config = Some(value),
+            "--heatmap-config" => options.heatmap_config = Some(value),
+            "--multilateration-config" => options.multilateration_config = Some(value),
+            "--promotion-config" => options.promotion_config = Some(value),
+            "--estimator" => options.estimator = Some(value),
+            "--k-factor" => options.k_factor = Some(value.parse()?),
+            "--k-best" => options.k_best = value.parse()?,
+            "--threads" => options.threads = value.parse()?,
+            "--k-sweep" => {
+                options.k_sweep = value
+                    .split(',')
+                    .map(|k| k.trim().parse())
+                    .collect::<Result<_, _>>()?
+            }
+            other => return Err(format!("Unknown option: {}", other).into()),
+        }
+    }
+    Ok(options)
+}
+
+fn path_to_strings(path: &[PathNode], nodes: &[Repeater]) -> Vec<String> {
+    path.iter().map(|node| {
+        match node {
+            PathNode::Known(idx) => nodes[*idx].id.clone(),
+            PathNode::Unknown(hash) => hash.to_string(),
+        }
+    }).collect()
+}
+
+/// Parses colon-separated hex hops, which must all have the same width.
+fn parse_path_hashes(prefixes: &str) -> Result<Vec<PathHash>, Box<dyn Error>> {
+    let hashes = prefixes
+        .split(':')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(PathHash::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if hashes.windows(2).any(|w| w[0].width != w[1].width) {
+        return Err(format!("Mixed path hash widths in '{}'", prefixes).into());
+    }
+    Ok(hashes)
+}
+
+/// Output for one decoded packet, with its diagnostics, confidences and (with `k_best > 1`)
+/// runner-up paths. Confidences and runner-ups are optional: a failed pass only loses them.
+fn decode_packet(
+    graph: &NetworkGraph,
+    packet: &PacketInput,
+    prefixes_vec: &[PathHash],
+    decoded: &DecodedPath,
+    lookup_nodes: &[Repeater],
+    k_best: usize,
+) -> (PathOutput, Vec<PathNode>) {
+    let origin = Anchor::new(packet.start_lat, packet.start_lon);
+    let observer = Anchor::new(packet.end_lat, packet.end_lon);
+
+    let path_nodes = &decoded.hops;
+    let path_strings = path_to_strings(path_nodes, lookup_nodes);
+    let hops: Vec<HopOutput> = (0..path_nodes.len()).map(|t| HopOutput {
+        step_cost: decoded.step_costs[t],
+        candidates: decoded.candidates[t],
+        unknown: decoded.unknown_kinds[t],
+        corrupted: decoded.corrupted[t],
+        skipped_before: decoded.skipped_before[t],
+        inferred: matches!(path_nodes[t], PathNode::Known(idx) if lookup_nodes[idx].inferred),
+    }).collect();
+
+    // A failed posterior pass only loses the confidences, not the packet
+    let confidence: Vec<f64> = match graph.hop_posteriors_with_anchors(
+        prefixes_vec,
+        Some(origin),
+        Some(observer),
+    ) {
+        Ok(posteriors) => path_nodes.iter().zip(&posteriors).map(|(node, hop)| {
+            hop.iter().find(|c| c.node == *node).map_or(0.0, |c| c.probability)
+        }).collect(),
+        Err(e) => {
+            eprintln!("No confidences for packet at {}: {}", packet.timestamp, e);
+            Vec::new()
+        }
+    };
+
+    // A failed K-best pass only loses the runner-ups, not the packet
+    let mut alternatives = Vec::new();
+    if k_best > 1 {
+        match graph.decode_k_best_with_anchors(
+            prefixes_vec,
+            Some(origin),
+            Some(observer),
+            k_best,
+        ) {
+            Ok(ranked) => {
+                for r in ranked.iter().filter(|r| r.path != *path_nodes) {
+                    alternatives.push(AlternativeOutput {
+                        path: path_to_strings(&r.path, lookup_nodes),
+                        cost: r.cost,
+                        gap_to_best: r.gap_to_best,
+                    });
+                }
+            }
+            Err(e) => eprintln!("No alternatives for packet at {}: {}", packet.timestamp, e),
+        }
+    }
+
+    let output = PathOutput {
+        timestamp: packet.timestamp.clone(),
+        start_lat: packet.start_lat,
+        start_lon: packet.start_lon,
+        end_lat: packet.end_lat,
+        end_lon: packet.end_lon,
+        path: path_strings,
+        confidence,
+        total_cost: decoded.total_cost,
+        end_cost: decoded.end_cost,
+        revisits: decoded.revisits,
+        bounces: decoded.bounces,
+        revisit_unavoidable: decoded.revisit_unavoidable,
+        skipped_before_observer: decoded.skipped_before_observer,
+        pruning_may_have_changed: decoded.pruning_may_have_changed,
+        hops,
+        alternatives,
+    };
+    (output, decoded.hops.clone())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
-        eprintln!("Usage: {} <repeaters_csv> <packets_csv> <output_yaml> <inferred_unknowns_json>", args[0]);
+    if args.len() < 5 {
+        eprintln!("Usage: {} <repeaters_csv> <packets_csv> <output_yaml> <inferred_unknowns_json> [--srtm-dir <dir>] [--model sigmoid|free-space|terrain-aware|log-distance] [--radio-config <yaml>] [--calibration <yaml>] [--decoder-config <yaml>] [--heatmap-config <yaml>] [--promotion-config <yaml>] [--estimator centroid|multilateration] [--multilateration-config <yaml>] [--k-factor <k>] [--k-sweep <k1,k2,...>] [--k-best <k>] [--threads <n>]", args[0]);
         std::process::exit(1);
     }
 
@@ -37,65 +263,167 @@ fn main() -> Result<(), Box<dyn Error>> {
     let packets_path = &args[2];
     let output_path = &args[3];
     let inferred_json_path = &args[4];
+    let options = parse_options(&args[5..])?;
 
     // Read Repeaters
-    // Example: ID,Name,Lat,Lon
-    // Example: 0x1234,RepeaterA,34.05,-118.25
-    let mut repeater_reader = csv::Reader::from_path(repeaters_path)?;
-    let mut repeaters: Vec<Repeater> = Vec::new();
-    for result in repeater_reader.deserialize() {
-        let record: Repeater = result?;
-        repeaters.push(record);
-    }
+    let repeaters = cli::read_repeaters(Path::new(repeaters_path))?;
 
-    // Clone for lookup since graph takes ownership
-    let lookup_nodes = repeaters.clone();
+    // Load Terrain (optional)
+    let terrain = match &options.srtm_dir {
+        Some(dir) if !repeaters.is_empty() => Some(Arc::new(srtm::load_terrain_around(
+            Path::new(dir),
+            &repeaters,
+            srtm::DEFAULT_TERRAIN_MARGIN_DEG,
+        )?)),
+        _ => None,
+    };
 
     // Initialize Graph
-    // Terrain is None for now as per instructions
-    let graph = NetworkGraph::new(repeaters, None);
+    let budget = cli::load_link_budget(options.radio_config.as_deref())?;
+    let model: Box<dyn PropagationModel> = match options.model.as_deref().unwrap_or("sigmoid") {
+        "sigmoid" => Box::new(SigmoidModel {
+            frequency_mhz: budget.frequency_mhz,
+            ..SigmoidModel::default()
+        }),
+        "free-space" => Box::new(FreeSpaceModel::new(budget)),
+        "terrain-aware" => Box::new(TerrainAwareModel::new(budget)),
+        "log-distance" => {
+            let path = options.calibration.as_ref()
+                .ok_or("--model log-distance needs --calibration <yaml> (see the calibrate binary)")?;
+            Box::new(LogDistanceModel::new(PathLossParams::load(Path::new(path))?))
+        }
+        other => return Err(format!("Unknown model: {}", other).into()),
+    };
+    let model = match options.k_factor {
+        Some(k) => model.with_k_factor(k),
+        None => model,
+    };
+
+    let estimator = match options.estimator.as_deref().unwrap_or("centroid") {
+        "centroid" => LocationEstimator::Centroid,
+        "multilateration" => LocationEstimator::Multilateration,
+        other => return Err(format!("Unknown estimator: {}", other).into()),
+    };
+
+    let decoder_config = match &options.decoder_config {
+        Some(path) => DecoderConfig::load(Path::new(path))?,
+        None => DecoderConfig::default(),
+    };
 
     // Read Packets
     // Example: timestamp,start_lat,start_lon,end_lat,end_lon,repeater_prefixes
     // Example: 2023-10-27T10:00:00Z,34.05,-118.25,34.10,-118.30,12:a4:b6
+    // Hops may also be 2- or 3-byte hashes (e.g. 12f0:a4c1), one width per packet.
     let mut packet_reader = csv::Reader::from_path(packets_path)?;
-    let mut outputs: Vec<PathOutput> = Vec::new();
-    let mut all_decoded_paths: Vec<Vec<PathNode>> = Vec::new();
-
+    let mut packets: Vec<(PacketInput, Vec<PathHash>)> = Vec::new();
     for result in packet_reader.deserialize() {
         let packet: PacketInput = result?;
+        match parse_path_hashes(&packet.repeater_prefixes) {
+            Ok(prefixes_vec) => packets.push((packet, prefixes_vec)),
+            Err(e) => eprintln!("Skipping packet at {}: {}", packet.timestamp, e),
+        }
+    }
 
-        // Parse prefixes (assume colon-separated hex strings)
-        let prefixes_vec: Vec<u8> = packet.repeater_prefixes
-            .split(':')
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                let clean = s.trim_start_matches("0x");
-                u8::from_str_radix(clean, 16).unwrap_or(0)
-            })
-            .collect();
-
-        match graph.decode_path(&prefixes_vec) {
-            Ok(path_nodes) => {
-                let path_strings: Vec<String> = path_nodes.iter().map(|node| {
-                    match node {
-                        PathNode::Known(idx) => lookup_nodes[*idx].id.clone(),
-                        PathNode::Unknown(prefix) => format!("{:02x}", prefix),
-                    }
-                }).collect();
-
-                outputs.push(PathOutput {
-                    timestamp: packet.timestamp,
-                    start_lat: packet.start_lat,
-                    start_lon: packet.start_lon,
-                    end_lat: packet.end_lat,
-                    end_lon: packet.end_lon,
-                    path: path_strings,
-                });
+    // Refraction sensitivity sweep (optional)
+    if !options.k_sweep.is_empty() {
+        let observations: Vec<Vec<PathHash>> = packets.iter().map(|(_, obs)| obs.clone()).collect();
+        eprintln!("k_factor\tedges\tknown_hops\tunknown_hops\tfailed");
+        for r in k_factor_sweep(
+            &repeaters,
+            terrain.clone(),
+            model.as_ref(),
+            &decoder_config,
+            &options.k_sweep,
+            &observations,
+        ) {
+            eprintln!(
+                "{:.3}\t{}\t{}\t{}\t{}",
+                r.k_factor, r.edge_count, r.known_hops, r.unknown_hops, r.failed_packets
+            );
+        }
+    }
+
+    let mut graph =
+        NetworkGraph::with_shared_terrain(repeaters, terrain, model, decoder_config);
+
+    // Pass 2 only runs when a heatmap config is given
+    let heatmap = match &options.heatmap_config {
+        Some(path) => Some(HeatmapConfig::load(Path::new(path))?),
+        None => None,
+    };
+    let multilateration = match &options.multilateration_config {
+        Some(path) => MultilaterationConfig::load(Path::new(path))?,
+        None => MultilaterationConfig::default(),
+    };
+    let localization_config = LocalizationConfig {
+        estimator,
+        multilateration,
+        heatmap,
+    };
+    localization_config.validate()?;
+
+    let packet_paths: Vec<PacketPath> = packets.iter().map(|(packet, prefixes_vec)| PacketPath {
+        hashes: prefixes_vec.clone(),
+        origin: Some(Anchor::new(packet.start_lat, packet.start_lon)),
+        observer: Some(Anchor::new(packet.end_lat, packet.end_lon)),
+    }).collect();
+    let timestamps: Vec<Option<String>> =
+        packets.iter().map(|(packet, _)| Some(packet.timestamp.clone())).collect();
+
+    // Promote well-supported Unknowns to inferred repeaters and re-decode (optional)
+    let mut promoted = None;
+    if let Some(path) = &options.promotion_config {
+        let promotion_config = PromotionConfig::load(Path::new(path))?;
+        let result = promotion::promote_unknowns(
+            &mut graph,
+            &packet_paths,
+            &timestamps,
+            &localization_config,
+            &promotion_config,
+            options.threads,
+        );
+        eprintln!("iteration\tinferred\tedges\tinferred_edges\tknown_hops\tinferred_hops\tunknown_hops\tfailed\tchanged");
+        for r in &result.iterations {
+            eprintln!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                r.iteration, r.inferred_nodes, r.edge_count, r.inferred_edges, r.known_hops,
+                r.inferred_hops, r.unknown_hops, r.failed_packets, r.changed_packets
+            );
+        }
+        if !result.converged {
+            eprintln!("Promotion stopped after {} iterations without converging", result.iterations.len());
+        }
+        promoted = Some((result.decoded, result.inferred));
+    }
+    // The graph owns the repeaters; inferred ones, if any, follow the database ones
+    let lookup_nodes = graph.nodes();
+
+    // Decode packets in parallel, unless promotion's last pass already did against this
+    // graph; results come back in input order
+    let (decoded_paths, promoted_unknowns) = match promoted {
+        Some((decoded, inferred)) => (decoded, Some(inferred)),
+        None => (graph.decode_batch(&packet_paths, options.threads), None),
+    };
+    let work: Vec<_> = packets.iter().zip(&decoded_paths).collect();
+    let results = parallel_map(&work, options.threads, |&((packet, prefixes_vec), decoded)| {
+        decoded.as_ref().map(|decoded| {
+            decode_packet(&graph, packet, prefixes_vec, decoded, lookup_nodes, options.k_best)
+        })
+    });
 
-                all_decoded_paths.push(path_nodes);
-            },
+    let mut outputs: Vec<PathOutput> = Vec::new();
+    let mut all_decoded_paths: Vec<PathObservation> = Vec::new();
+    for ((packet, _), result) in packets.iter().zip(results) {
+        match result {
+            Ok((output, hops)) => {
+                outputs.push(output);
+                all_decoded_paths.push(PathObservation {
+                    path: hops,
+                    origin: Some(Anchor::new(packet.start_lat, packet.start_lon)),
+                    observer: Some(Anchor::new(packet.end_lat, packet.end_lon)),
+                    timestamp: Some(packet.timestamp.clone()),
+                });
+            }
             Err(e) => {
                 eprintln!("Failed to decode path for packet at {}: {}", packet.timestamp, e);
             }
@@ -106,8 +434,18 @@ fn main() -> Result<(), Box<dyn Error>> {
     let f = File::create(output_path)?;
     serde_yaml::to_writer(f, &outputs)?;
 
-    // Step 4: Localize Unknowns
-    let inferred_unknowns = localization::localize_unknowns(&all_decoded_paths, &lookup_nodes);
+    // Step 4: Localize Unknowns (centroids or multilateration, then the terrain-aware reachability heatmap).
+    // Promotion already localized its last pass, with the inferred repeaters' hops included.
+    let inferred_unknowns = match promoted_unknowns {
+        Some(inferred) => inferred,
+        None => localization::localize_unknowns_with_model(
+            &all_decoded_paths,
+            lookup_nodes,
+            graph.model(),
+            graph.terrain(),
+            &localization_config,
+        ),
+    };
 
     // Write Inferred Unknowns to JSON
     let json_file = File::create(inferred_json_path)?;
@@ -1,6 +1,15 @@
+use crate::physics::DEFAULT_ANTENNA_HEIGHT_M;
+use crate::terrain::Antenna;
+use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A repeater from the database.
+///
+/// The radio columns are optional in the CSV; when missing, the antenna height falls back to
+/// `DEFAULT_ANTENNA_HEIGHT_M`, the ground elevation comes from the terrain map, and TX power /
+/// antenna gain / noise floor fall back to the propagation model's `LinkBudget`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Repeater {
     #[serde(rename = "ID")]
     pub id: String,
@@ -10,12 +19,128 @@ pub struct Repeater {
     pub lat: f64,
     #[serde(rename = "Lon")]
     pub lon: f64,
+    /// Antenna height above ground level in meters.
+    #[serde(rename = "AntennaHeight", default, skip_serializing_if = "Option::is_none")]
+    pub antenna_height_m: Option<f64>,
+    /// Transmit power in dBm.
+    #[serde(rename = "TxPower", default, skip_serializing_if = "Option::is_none")]
+    pub tx_power_dbm: Option<f64>,
+    /// Antenna gain in dBi (used for both transmit and receive).
+    #[serde(rename = "AntennaGain", default, skip_serializing_if = "Option::is_none")]
+    pub antenna_gain_dbi: Option<f64>,
+    /// Ground elevation in meters, overriding the terrain map at this site.
+    #[serde(rename = "Elevation", default, skip_serializing_if = "Option::is_none")]
+    pub elevation_m: Option<f64>,
+    /// Measured noise floor at this site in dBm. A noisy receiver hears less, so links
+    /// *into* this repeater get more expensive than links out of it.
+    #[serde(rename = "NoiseFloor", default, skip_serializing_if = "Option::is_none")]
+    pub noise_floor_dbm: Option<f64>,
+    /// A provisional repeater placed by localizing Unknown hops, not one from the database
+    /// (see `promotion::promote_unknowns`).
+    #[serde(rename = "Inferred", default, skip_serializing_if = "is_false")]
+    pub inferred: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 impl Repeater {
-    pub fn prefix(&self) -> u8 {
+    /// The 1-byte path hash, as logged by older firmware, or `None` for a malformed ID.
+    pub fn prefix(&self) -> Option<u8> {
+        self.path_hash(1).map(|hash| hash.value as u8)
+    }
+
+    /// The `width`-byte path hash: the leading bytes of the ID.
+    /// An ID too short for `width` yields a narrower hash, which never matches a `width`-byte hop.
+    ///
+    /// `None` unless the ID (after an optional `0x`) is a non-empty, whole number of hex bytes.
+    pub fn path_hash(&self, width: u8) -> Option<PathHash> {
         let clean_id = self.id.trim_start_matches("0x");
-        u8::from_str_radix(&clean_id[0..2], 16).unwrap_or(0)
+        if clean_id.is_empty()
+            || !clean_id.len().is_multiple_of(2)
+            || !clean_id.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return None;
+        }
+        let width = width.min((clean_id.len() / 2) as u8);
+        let value = u32::from_str_radix(&clean_id[..2 * width as usize], 16).ok()?;
+        Some(PathHash { value, width })
+    }
+
+    /// Antenna height above ground, or `DEFAULT_ANTENNA_HEIGHT_M` if not specified.
+    pub fn antenna_height(&self) -> f64 {
+        self.antenna_height_m.unwrap_or(DEFAULT_ANTENNA_HEIGHT_M)
+    }
+
+    /// The repeater's antenna as a link endpoint for terrain profiles.
+    pub fn antenna(&self) -> Antenna {
+        Antenna {
+            lat: self.lat,
+            lon: self.lon,
+            height_m: self.antenna_height(),
+            ground_elevation_m: self.elevation_m,
+        }
+    }
+}
+
+/// One hop of a packet's path as logged: the leading `width` bytes of the repeater's ID.
+///
+/// Older firmware logs a 1-byte hash per hop, newer firmware 2 or 3 bytes. All hops of one
+/// packet share a width, but a mesh in the middle of an upgrade carries both forms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PathHash {
+    pub value: u32,
+    /// Width in bytes, 1 to `PathHash::MAX_WIDTH`.
+    pub width: u8,
+}
+
+impl PathHash {
+    pub const MAX_WIDTH: u8 = 3;
+
+    /// A 1-byte hash.
+    pub const fn byte(value: u8) -> Self {
+        PathHash { value: value as u32, width: 1 }
+    }
+
+    /// Parses a logged hop: 2, 4 or 6 hex digits, optionally prefixed with `0x`.
+    pub fn parse(hop: &str) -> Result<Self> {
+        let clean = hop.trim().trim_start_matches("0x");
+        let width = clean.len() / 2;
+        if !clean.len().is_multiple_of(2) || width == 0 || width > Self::MAX_WIDTH as usize {
+            return Err(anyhow!("Invalid path hash '{}': expected 2, 4 or 6 hex digits", hop));
+        }
+        let value = u32::from_str_radix(clean, 16)
+            .map_err(|e| anyhow!("Invalid path hash '{}': {}", hop, e))?;
+        Ok(PathHash { value, width: width as u8 })
+    }
+
+    /// The leading `width` bytes of this hash (itself if it is not wider).
+    pub fn truncate(self, width: u8) -> Self {
+        if width >= self.width {
+            return self;
+        }
+        PathHash {
+            value: self.value >> (8 * (self.width - width)),
+            width,
+        }
+    }
+
+    /// Whether a repeater with the (wider or equal) hash `full` would log this hash.
+    pub fn matches(self, full: PathHash) -> bool {
+        full.width >= self.width && full.truncate(self.width) == self
+    }
+}
+
+impl From<u8> for PathHash {
+    fn from(value: u8) -> Self {
+        PathHash::byte(value)
+    }
+}
+
+impl fmt::Display for PathHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0width$x}", self.value, width = 2 * self.width as usize)
     }
 }
 
@@ -24,6 +149,55 @@ impl Repeater {
 pub enum PathNode {
     /// A known repeater from the database (index into the nodes list).
     Known(usize),
-    /// An unknown repeater, identified only by its 1-byte prefix.
-    Unknown(u8),
+    /// An unknown repeater, identified only by its path hash.
+    Unknown(PathHash),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_hash_parse_and_display() {
+        assert_eq!(PathHash::parse("a4").unwrap(), PathHash::byte(0xA4));
+        assert_eq!(PathHash::parse("0x12F0").unwrap(), PathHash { value: 0x12F0, width: 2 });
+        assert_eq!(PathHash::parse("a4c1b2").unwrap().width, 3);
+        assert!(PathHash::parse("abc").is_err());
+        assert!(PathHash::parse("a4c1b2d3").is_err());
+        assert!(PathHash::parse("zz").is_err());
+
+        assert_eq!(PathHash { value: 0x0A, width: 2 }.to_string(), "000a");
+        assert_eq!(PathHash::byte(0xB0).to_string(), "b0");
+    }
+
+    #[test]
+    fn test_repeater_path_hash() {
+        let repeater = Repeater {
+            id: "0xA1B2C3D4".to_string(),
+            ..Default::default()
+        };
+        let full = repeater.path_hash(PathHash::MAX_WIDTH).unwrap();
+        assert_eq!(full, PathHash { value: 0xA1B2C3, width: 3 });
+        assert_eq!(repeater.path_hash(1), repeater.prefix().map(PathHash::byte));
+        assert_eq!(full.truncate(2), PathHash { value: 0xA1B2, width: 2 });
+
+        assert!(PathHash::byte(0xA1).matches(full));
+        assert!(PathHash { value: 0xA1B2, width: 2 }.matches(full));
+        assert!(!PathHash { value: 0xA1B3, width: 2 }.matches(full));
+
+        // Too short for a 2-byte hash: never matches one
+        let short = Repeater { id: "A1".to_string(), ..Default::default() };
+        let short_hash = short.path_hash(2).unwrap();
+        assert_eq!(short_hash.width, 1);
+        assert!(!PathHash { value: 0xA100, width: 2 }.matches(short_hash));
+    }
+
+    #[test]
+    fn test_malformed_ids_have_no_path_hash() {
+        for id in ["", "0x", "relay", "0xA1B", "0xzz11", "é1", "0xA1é"] {
+            let repeater = Repeater { id: id.to_string(), ..Default::default() };
+            assert_eq!(repeater.path_hash(PathHash::MAX_WIDTH), None, "{:?}", id);
+            assert_eq!(repeater.prefix(), None, "{:?}", id);
+        }
+    }
 }
@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Applies `f` to every item on up to `threads` scoped threads (0 = one per available core)
+/// and returns the results in input order.
+///
+/// Workers take the next unclaimed item each time, so a few slow packets don't hold up
+/// a whole chunk. Used by `NetworkGraph::decode_batch`, the Pass 2 heatmap and the
+/// per-packet work in the binaries.
+pub fn parallel_map<T, R, F>(items: &[T], threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break;
+                        };
+                        done.push((i, f(item)));
+                    }
+                    done
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            let done = worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            for (i, result) in done {
+                results[i] = Some(result);
+            }
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|r| r.expect("every item is claimed by exactly one worker"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parallel_map_keeps_input_order() {
+        let items: Vec<usize> = (0..100).collect();
+        for threads in [0, 1, 4, 200] {
+            let squares = parallel_map(&items, threads, |&i| i * i);
+            assert_eq!(squares, items.iter().map(|&i| i * i).collect::<Vec<_>>(), "threads = {}", threads);
+        }
+        assert!(parallel_map(&[] as &[usize], 4, |&i| i).is_empty());
+    }
+}
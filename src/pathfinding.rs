@@ -13,14 +13,14 @@ impl Eq for State {}
 
 impl PartialOrd for State {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // Reverse because BinaryHeap is a max-heap, we want min-cost
-        other.cost.partial_cmp(&self.cost)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for State {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+        // Reverse because BinaryHeap is a max-heap, we want min-cost
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
     }
 }
 
@@ -105,18 +105,21 @@ mod tests {
                 name: "A".to_string(),
                 lat: 0.0,
                 lon: 0.0,
+                ..Default::default()
             },
             Repeater {
                 id: "000002".to_string(),
                 name: "B".to_string(),
                 lat: 0.1,
                 lon: 0.0,
+                ..Default::default()
             },
             Repeater {
                 id: "000003".to_string(),
                 name: "C".to_string(),
                 lat: 0.2,
                 lon: 0.0,
+                ..Default::default()
             },
         ];
 
@@ -133,12 +136,14 @@ mod tests {
                 name: "A".to_string(),
                 lat: 0.0,
                 lon: 0.0,
+                ..Default::default()
             },
             Repeater {
                 id: "000002".to_string(),
                 name: "B".to_string(),
                 lat: 10.0, // > 1000km away
                 lon: 0.0,
+                ..Default::default()
             },
         ];
 
@@ -162,24 +167,28 @@ mod tests {
                 name: "S".into(),
                 lat: 0.0,
                 lon: 0.0,
+                ..Default::default()
             },
             Repeater {
                 id: "01".into(),
                 name: "1".into(),
                 lat: 0.05,
                 lon: 0.05,
+                ..Default::default()
             }, // Path 1: ~15km total
             Repeater {
                 id: "02".into(),
                 name: "2".into(),
                 lat: 0.5,
                 lon: 0.5,
+                ..Default::default()
             }, // Path 2: ~150km total
             Repeater {
                 id: "03".into(),
                 name: "E".into(),
                 lat: 0.1,
                 lon: 0.1,
+                ..Default::default()
             },
         ];
 
@@ -1,4 +1,36 @@
+use crate::terrain::{Antenna, TerrainMap, smooth_earth_profile};
+use serde::{Deserialize, Serialize};
+
 const EARTH_RADIUS_KM: f64 = 6371.0;
+const SPEED_OF_LIGHT_M_S: f64 = 299_792_458.0;
+
+/// LoRa centre frequency used by MeshCore in the EU/UK (MHz).
+pub const LORA_FREQ_EU_MHZ: f64 = 869.525;
+/// LoRa centre frequency used in the US 915 MHz band (MHz).
+pub const LORA_FREQ_US_MHZ: f64 = 915.0;
+
+/// Effective earth radius factor for a standard atmosphere, used by radio planners
+/// to account for refraction bending signals back towards the ground.
+pub const STANDARD_K_FACTOR: f64 = 4.0 / 3.0;
+
+/// Links longer than this are impossible for `link_cost` and, by default, for the
+/// propagation models and `DecoderConfig::max_link_range_km`.
+pub const MAX_LINK_RANGE_KM: f64 = 150.0;
+
+/// Antenna height above ground assumed for repeaters that don't specify one (m).
+pub const DEFAULT_ANTENNA_HEIGHT_M: f64 = 30.0;
+
+/// Antenna height above ground assumed for handheld and other ground-level nodes (m),
+/// e.g. a packet's origin or the observer that logged it.
+pub const GROUND_ANTENNA_HEIGHT_M: f64 = 1.5;
+
+/// Cost added per dB of terrain diffraction loss.
+/// Every 10dB of extra loss is treated as one decade less likely: ln(10) / 10.
+const COST_PER_DB_EXCESS_LOSS: f64 = std::f64::consts::LN_10 / 10.0;
+
+/// Diffraction loss beyond which a link is treated as blocked outright (infinite cost).
+/// Below it the loss is costed continuously at `COST_PER_DB_EXCESS_LOSS` per dB.
+pub const MAX_DIFFRACTION_LOSS_DB: f64 = 40.0;
 
 /// Calculates the Haversine distance between two points in km.
 pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
@@ -17,19 +49,60 @@ pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
     EARTH_RADIUS_KM * c
 }
 
+/// Effective earth radius in km for refraction factor `k_factor`
+/// (1.0 = geometric, `STANDARD_K_FACTOR` = standard atmosphere).
+pub fn effective_earth_radius_km(k_factor: f64) -> f64 {
+    EARTH_RADIUS_KM * k_factor
+}
+
 /// Calculates the "Earth Bulge" in meters.
-/// Formula: h = d^2 / (8 * R)
-pub fn earth_bulge(distance_km: f64) -> f64 {
+/// Formula: h = d^2 / (8 * k * R)
+pub fn earth_bulge(distance_km: f64, k_factor: f64) -> f64 {
     if distance_km <= 0.0 {
         return 0.0;
     }
     // Convert R to meters for result in meters
-    let r_meters = EARTH_RADIUS_KM * 1000.0;
+    let r_meters = effective_earth_radius_km(k_factor) * 1000.0;
     let d_meters = distance_km * 1000.0;
 
     (d_meters * d_meters) / (8.0 * r_meters)
 }
 
+/// Wavelength in meters for a frequency in MHz.
+pub fn wavelength_m(freq_mhz: f64) -> f64 {
+    SPEED_OF_LIGHT_M_S / (freq_mhz * 1.0e6)
+}
+
+/// Radius of the first Fresnel zone in meters at a point `d1_m` from one end
+/// and `d2_m` from the other.
+/// Formula: r1 = sqrt(lambda * d1 * d2 / (d1 + d2))
+pub fn fresnel_radius_m(d1_m: f64, d2_m: f64, freq_mhz: f64) -> f64 {
+    let total = d1_m + d2_m;
+    if total <= 0.0 {
+        return 0.0;
+    }
+    (wavelength_m(freq_mhz) * d1_m * d2_m / total).sqrt()
+}
+
+/// Fresnel-Kirchhoff diffraction parameter `v` for an obstacle `h_m` above the ray
+/// (negative if below), `d1_m` from one end and `d2_m` from the other.
+/// Formula: v = h * sqrt(2 * (d1 + d2) / (lambda * d1 * d2))
+pub fn fresnel_kirchhoff_v(h_m: f64, d1_m: f64, d2_m: f64, freq_mhz: f64) -> f64 {
+    if d1_m <= 0.0 || d2_m <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+    h_m * (2.0 * (d1_m + d2_m) / (wavelength_m(freq_mhz) * d1_m * d2_m)).sqrt()
+}
+
+/// Single knife-edge diffraction loss in dB for parameter `v` (ITU-R P.526 approximation).
+/// Zero when the obstacle is well below the ray (v <= -0.78), ~6dB at grazing (v = 0).
+pub fn knife_edge_loss_db(v: f64) -> f64 {
+    if v <= -0.78 {
+        return 0.0;
+    }
+    6.9 + 20.0 * (((v - 0.1).powi(2) + 1.0).sqrt() + v - 0.1).log10()
+}
+
 /// Calculates the cost (Negative Log Probability) of a link.
 ///
 /// We model the probability as a sigmoid function of distance and "feasibility".
@@ -40,36 +113,61 @@ pub fn earth_bulge(distance_km: f64) -> f64 {
 /// For this simulation:
 /// - Max realistic range: ~100km
 /// - Antenna height assumption: ~30m. If bulge > 30m, probability drops sharply.
+///   See `antenna_link_cost` for links with other antenna heights.
+/// - With terrain, the diffraction loss over the terrain profile (Deygout) is added at
+///   `COST_PER_DB_EXCESS_LOSS` per dB, so links just behind a ridge stay plausible.
+///   Links losing more than `MAX_DIFFRACTION_LOSS_DB` are blocked: `f64::INFINITY`.
+///   The diffraction is computed at `LORA_FREQ_EU_MHZ`; see `antenna_link_cost` for others.
 pub fn link_cost(
     lat1: f64,
     lon1: f64,
     lat2: f64,
     lon2: f64,
-    terrain: Option<&crate::terrain::TerrainMap>,
+    terrain: Option<&TerrainMap>,
+) -> f64 {
+    // Hard cutoff for performance/reality
+    if haversine_distance(lat1, lon1, lat2, lon2) > MAX_LINK_RANGE_KM {
+        return f64::INFINITY;
+    }
+    antenna_link_cost(
+        Antenna::new(lat1, lon1, DEFAULT_ANTENNA_HEIGHT_M),
+        Antenna::new(lat2, lon2, DEFAULT_ANTENNA_HEIGHT_M),
+        terrain,
+        1.0,
+        LORA_FREQ_EU_MHZ,
+    )
+}
+
+/// Same as `link_cost`, but for antennas of any height (and known ground elevation)
+/// and any radio frequency. There is no distance cutoff: the caller applies its own
+/// (see `PropagationModel::with_max_range_km`).
+///
+/// The terrain profile is taken between the actual antenna tips, and the bulge sigmoid
+/// is shifted by how much the mean mast height differs from the 30m it was tuned for.
+/// Both the bulge and the terrain curvature use the effective earth radius for `k_factor`
+/// (`link_cost` uses the geometric radius, k = 1). `freq_mhz` sets the Fresnel zones
+/// for the diffraction loss.
+pub fn antenna_link_cost(
+    a: Antenna,
+    b: Antenna,
+    terrain: Option<&TerrainMap>,
+    k_factor: f64,
+    freq_mhz: f64,
 ) -> f64 {
-    let dist_km = haversine_distance(lat1, lon1, lat2, lon2);
+    let dist_km = haversine_distance(a.lat, a.lon, b.lat, b.lon);
 
     // Terrain Check
+    let mut obstruction_cost = 0.0;
     if let Some(map) = terrain {
-        // Assume 30m antenna height for both
-        if !map.check_line_of_sight(lat1, lon1, 30.0, lat2, lon2, 30.0) {
-            // Blocked by terrain!
-            // Add a massive penalty. e.g. +30.0 in log-space (e^-30 is tiny)
-            // Existing max cost is around 1000.0 (from 1e-10).
-            // Let's return a very high cost that isn't INFINITY but effectively rules it out
-            // unless there are NO other options.
-            // But if it's blocked, it's physically impossible in this model.
-            // Let's add 50.0 to the calculated cost or just return High.
-            return 2000.0;
+        let loss_db = map.diffraction_loss_db(a, b, freq_mhz, k_factor);
+        if loss_db > MAX_DIFFRACTION_LOSS_DB {
+            // Deeply shadowed by terrain: no link, like one beyond the distance cutoff
+            return f64::INFINITY;
         }
+        obstruction_cost = loss_db * COST_PER_DB_EXCESS_LOSS;
     }
 
-    // Hard cutoff for performance/reality
-    if dist_km > 150.0 {
-        return f64::INFINITY;
-    }
-
-    let bulge_m = earth_bulge(dist_km);
+    let bulge_m = earth_bulge(dist_km, k_factor);
 
     // Sigmoid for distance decay
     // Center at 50km, steepness 0.1
@@ -77,8 +175,9 @@ pub fn link_cost(
 
     // Sigmoid for bulge/horizon
     // If bulge > 30m (approx horizon for low towers), prob drops
-    // Center at 30m, steepness 0.5
-    let bulge_prob = 1.0 / (1.0 + (0.5 * (bulge_m - 40.0)).exp());
+    // Center at 40m for 30m masts, steepness 0.5
+    let mast_offset_m = (a.height_m + b.height_m) / 2.0 - DEFAULT_ANTENNA_HEIGHT_M;
+    let bulge_prob = 1.0 / (1.0 + (0.5 * (bulge_m - 40.0 - mast_offset_m)).exp());
 
     let combined_prob = dist_prob * bulge_prob;
 
@@ -87,7 +186,156 @@ pub fn link_cost(
         return 1000.0; // Very high cost
     }
 
-    -combined_prob.ln()
+    -combined_prob.ln() + obstruction_cost
+}
+
+/// Free-space path loss in dB.
+/// Formula: FSPL = 20 log10(d_km) + 20 log10(f_MHz) + 32.44
+pub fn free_space_path_loss_db(distance_km: f64, freq_mhz: f64) -> f64 {
+    if distance_km <= 0.0 {
+        return 0.0;
+    }
+    20.0 * distance_km.log10() + 20.0 * freq_mhz.log10() + 32.44
+}
+
+/// Minimum SNR (dB) at which a LoRa receiver can demodulate a given spreading factor.
+pub fn lora_snr_limit_db(spreading_factor: u8) -> f64 {
+    match spreading_factor {
+        0..=6 => -5.0,
+        7 => -7.5,
+        8 => -10.0,
+        9 => -12.5,
+        10 => -15.0,
+        11 => -17.5,
+        _ => -20.0,
+    }
+}
+
+/// Maps a link margin (dB above receiver sensitivity) to a delivery probability.
+///
+/// Uses a logistic curve: 50% at 0dB margin, rising towards 1 as the margin grows.
+/// `spread_db` sets how quickly: it models shadow fading around the predicted signal level.
+pub fn margin_to_probability(margin_db: f64, spread_db: f64) -> f64 {
+    1.0 / (1.0 + (-margin_db / spread_db).exp())
+}
+
+/// Radio parameters for the link-budget cost model.
+///
+/// Defaults follow the MeshCore EU/UK preset (869.525MHz, 250kHz, SF11) with
+/// 22dBm SX1262 radios and 3dBi antennas. Per-repeater values override these
+/// (see `propagation::TerrainAwareModel`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkBudget {
+    pub tx_power_dbm: f64,
+    pub tx_antenna_gain_dbi: f64,
+    pub rx_antenna_gain_dbi: f64,
+    pub frequency_mhz: f64,
+    pub spreading_factor: u8,
+    pub bandwidth_khz: f64,
+    /// Receiver noise figure (dB).
+    pub noise_figure_db: f64,
+    /// Measured noise floor at the receiver over the channel bandwidth (dBm).
+    /// When set it replaces the thermal noise plus `noise_figure_db`, e.g. for a
+    /// repeater on a noisy rooftop.
+    pub noise_floor_dbm: Option<f64>,
+    /// Cable, connector and clutter losses not captured by the propagation model (dB).
+    pub system_losses_db: f64,
+    /// Spread of the margin-to-probability curve (dB), see `margin_to_probability`.
+    pub fading_spread_db: f64,
+    /// Effective earth radius factor for refraction (see `effective_earth_radius_km`).
+    pub k_factor: f64,
+}
+
+impl Default for LinkBudget {
+    fn default() -> Self {
+        LinkBudget {
+            tx_power_dbm: 22.0,
+            tx_antenna_gain_dbi: 3.0,
+            rx_antenna_gain_dbi: 3.0,
+            frequency_mhz: LORA_FREQ_EU_MHZ,
+            spreading_factor: 11,
+            bandwidth_khz: 250.0,
+            noise_figure_db: 6.0,
+            noise_floor_dbm: None,
+            system_losses_db: 10.0,
+            fading_spread_db: 4.0,
+            k_factor: STANDARD_K_FACTOR,
+        }
+    }
+}
+
+impl LinkBudget {
+    /// Noise power at the receiver (dBm): the measured `noise_floor_dbm` if known,
+    /// otherwise thermal noise over the bandwidth plus the noise figure.
+    pub fn noise_floor(&self) -> f64 {
+        self.noise_floor_dbm.unwrap_or_else(|| {
+            -174.0 + 10.0 * (self.bandwidth_khz * 1000.0).log10() + self.noise_figure_db
+        })
+    }
+
+    /// Receiver sensitivity (dBm): the noise floor plus the SNR limit for the spreading factor.
+    pub fn sensitivity_dbm(&self) -> f64 {
+        self.noise_floor() + lora_snr_limit_db(self.spreading_factor)
+    }
+
+    /// Received signal strength (dBm) after `path_loss_db` of propagation loss.
+    pub fn received_power_dbm(&self, path_loss_db: f64) -> f64 {
+        self.tx_power_dbm + self.tx_antenna_gain_dbi + self.rx_antenna_gain_dbi
+            - self.system_losses_db
+            - path_loss_db
+    }
+
+    /// Link margin (dB) of a received signal over the receiver sensitivity.
+    pub fn margin_db(&self, rx_power_dbm: f64) -> f64 {
+        rx_power_dbm - self.sensitivity_dbm()
+    }
+
+    /// Cost (Negative Log Probability) of a link received at `rx_power_dbm`.
+    ///
+    /// Useful for putting fixed penalties on the same scale as real links,
+    /// e.g. `cost_for_rx_power(-122.0)` for a "-122dBm" ghost link.
+    pub fn cost_for_rx_power(&self, rx_power_dbm: f64) -> f64 {
+        let prob = margin_to_probability(self.margin_db(rx_power_dbm), self.fading_spread_db);
+        if prob < 1e-10 {
+            return 1000.0;
+        }
+        -prob.ln()
+    }
+}
+
+/// Calculates the cost (Negative Log Probability) of a link from a LoRa link budget.
+///
+/// Path loss is free-space loss plus diffraction over the terrain profile (or over a smooth
+/// earth when no terrain is given). The received power is compared with the receiver
+/// sensitivity and the margin mapped to a probability with `margin_to_probability`.
+/// There is no distance cutoff: the caller applies its own.
+pub fn link_budget_cost(
+    a: Antenna,
+    b: Antenna,
+    terrain: Option<&TerrainMap>,
+    budget: &LinkBudget,
+) -> f64 {
+    let dist_km = haversine_distance(a.lat, a.lon, b.lat, b.lon);
+    let excess_loss_db = excess_path_loss_db(a, b, terrain, budget);
+
+    let path_loss_db = free_space_path_loss_db(dist_km, budget.frequency_mhz) + excess_loss_db;
+    budget.cost_for_rx_power(budget.received_power_dbm(path_loss_db))
+}
+
+/// Diffraction loss (dB) over the terrain profile between two antennas, or over a smooth
+/// earth when no terrain is given, using the budget's frequency and k-factor.
+pub fn excess_path_loss_db(
+    a: Antenna,
+    b: Antenna,
+    terrain: Option<&TerrainMap>,
+    budget: &LinkBudget,
+) -> f64 {
+    let profile = match terrain {
+        Some(map) => map.path_profile(a, b, budget.k_factor),
+        None => smooth_earth_profile(a, b, budget.k_factor),
+    };
+    profile.diffraction_loss_db(budget.frequency_mhz)
 }
 
 #[cfg(test)]
@@ -105,8 +353,34 @@ mod tests {
     fn test_earth_bulge() {
         // For 100km, bulge should be ~196m
         // h = (100000^2) / (8 * 6371000) = 10000000000 / 50968000 = 196.2
-        let b = earth_bulge(100.0);
+        let b = earth_bulge(100.0, 1.0);
         assert!((b - 196.0).abs() < 1.0);
+
+        // Standard refraction flattens the earth: 196 / (4/3) = ~147m
+        let b_refracted = earth_bulge(100.0, STANDARD_K_FACTOR);
+        assert!((b_refracted - 147.2).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_fresnel_radius() {
+        // 10km link at 869.525MHz: r1 at the midpoint is ~29.4m
+        let r = fresnel_radius_m(5000.0, 5000.0, LORA_FREQ_EU_MHZ);
+        assert!((r - 29.4).abs() < 0.5);
+        // Shrinks towards the ends and with higher frequency
+        assert!(fresnel_radius_m(1000.0, 9000.0, LORA_FREQ_EU_MHZ) < r);
+        assert!(fresnel_radius_m(5000.0, 5000.0, LORA_FREQ_US_MHZ) < r);
+    }
+
+    #[test]
+    fn test_knife_edge_loss() {
+        // Well clear: no loss
+        assert_eq!(knife_edge_loss_db(-1.0), 0.0);
+        // Grazing: ~6dB
+        assert!((knife_edge_loss_db(0.0) - 6.0).abs() < 0.1);
+        // Deep shadow: ~20dB at v = 2.4
+        assert!((knife_edge_loss_db(2.4) - 20.5).abs() < 0.5);
+        // Monotonic
+        assert!(knife_edge_loss_db(1.0) < knife_edge_loss_db(2.0));
     }
 
     #[test]
@@ -122,4 +396,111 @@ mod tests {
         let c_very_long = link_cost(51.50, 0.0, 55.00, 0.0, None); // ~300km
         assert!(c_very_long == f64::INFINITY || c_very_long > 500.0);
     }
+
+    #[test]
+    fn test_link_cost_diffraction_penalty() {
+        use crate::terrain::TerrainMap;
+
+        let mut map = TerrainMap::new_flat(0.0, 0.0, 20.0, 20.0, 30.0);
+        let clear = link_cost(0.0, -0.05, 0.0, 0.05, Some(&map));
+
+        // Ridge that intrudes into the Fresnel zone but leaves the ray clear
+        let mid_col = map.width / 2;
+        for r in 0..map.height {
+            for c in mid_col - 1..=mid_col + 1 {
+                map.data[r * map.width + c] = 20.0;
+            }
+        }
+        let grazing = link_cost(0.0, -0.05, 0.0, 0.05, Some(&map));
+        assert!(grazing > clear);
+        assert!(grazing.is_finite());
+
+        // Just over the ridge top: still plausible
+        for r in 0..map.height {
+            for c in mid_col - 1..=mid_col + 1 {
+                map.data[r * map.width + c] = 35.0;
+            }
+        }
+        let shadowed = link_cost(0.0, -0.05, 0.0, 0.05, Some(&map));
+        assert!(shadowed > grazing);
+        assert!(shadowed < 10.0);
+
+        // Mountain: ruled out
+        for r in 0..map.height {
+            for c in mid_col - 1..=mid_col + 1 {
+                map.data[r * map.width + c] = 1000.0;
+            }
+        }
+        assert_eq!(link_cost(0.0, -0.05, 0.0, 0.05, Some(&map)), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_free_space_path_loss() {
+        // 10km at 869.525MHz: ~111.2dB
+        assert!((free_space_path_loss_db(10.0, LORA_FREQ_EU_MHZ) - 111.2).abs() < 0.1);
+        // +6dB per doubling of distance
+        let d = free_space_path_loss_db(20.0, LORA_FREQ_EU_MHZ)
+            - free_space_path_loss_db(10.0, LORA_FREQ_EU_MHZ);
+        assert!((d - 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_link_budget_sensitivity() {
+        // SF11 / 250kHz / NF6: -174 + 54 + 6 - 17.5 = -131.5dBm
+        let budget = LinkBudget::default();
+        assert!((budget.sensitivity_dbm() - -131.5).abs() < 0.1);
+
+        // Slower spreading factors are more sensitive
+        let sf12 = LinkBudget {
+            spreading_factor: 12,
+            ..LinkBudget::default()
+        };
+        assert!(sf12.sensitivity_dbm() < budget.sensitivity_dbm());
+
+        // A measured noise floor replaces the thermal estimate
+        let noisy = LinkBudget {
+            noise_floor_dbm: Some(-100.0),
+            ..LinkBudget::default()
+        };
+        assert!((noisy.sensitivity_dbm() - -117.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_margin_to_probability() {
+        assert!((margin_to_probability(0.0, 4.0) - 0.5).abs() < 1e-9);
+        assert!(margin_to_probability(20.0, 4.0) > 0.99);
+        assert!(margin_to_probability(-20.0, 4.0) < 0.01);
+
+        // Signals well above sensitivity are cheap, signals below it are expensive.
+        let budget = LinkBudget::default();
+        assert!(budget.cost_for_rx_power(-100.0) < 0.01);
+        assert!(budget.cost_for_rx_power(-140.0) > 2.0);
+    }
+
+    #[test]
+    fn test_link_budget_cost() {
+        let budget = LinkBudget::default();
+        let at = |lat: f64| Antenna::new(lat, 0.0, DEFAULT_ANTENNA_HEIGHT_M);
+        let c_short = link_budget_cost(at(51.50), at(51.51), None, &budget); // ~1km
+        let c_mid = link_budget_cost(at(51.50), at(51.90), None, &budget); // ~44km
+        let c_long = link_budget_cost(at(51.50), at(52.50), None, &budget); // ~111km
+
+        assert!(c_short < 0.01);
+        assert!(c_short < c_mid);
+        assert!(c_mid < c_long);
+        // Beyond the radio horizon the smooth earth adds heavy diffraction loss.
+        assert!(c_long > 1.0);
+
+
+        // Taller masts see further over the horizon
+        let tall = |lat: f64| Antenna::new(lat, 0.0, 150.0);
+        assert!(link_budget_cost(tall(51.50), tall(52.50), None, &budget) < c_long);
+
+        // ...as do sites with a known elevation above the surrounding sea-level earth
+        let hilltop = Antenna {
+            ground_elevation_m: Some(300.0),
+            ..at(51.50)
+        };
+        assert!(link_budget_cost(hilltop, at(52.50), None, &budget) < c_long);
+    }
 }
@@ -0,0 +1,207 @@
+use crate::graph::{DecodedPath, NetworkGraph, PacketPath};
+use crate::localization::{
+    InferredRepeater, LocalizationConfig, PathObservation, localize_unknowns_with_model,
+};
+use crate::models::{PathNode, Repeater};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+
+/// Options for `promote_unknowns`.
+///
+/// Loadable from YAML with `load`; missing fields keep their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromotionConfig {
+    /// Fewest observations a cluster of Unknowns needs to be promoted.
+    pub min_observations: usize,
+    /// Lowest `InferredRepeater::confidence` a cluster needs to be promoted, on top of
+    /// `min_observations`. At the default of 0.25, three observations between two distinct
+    /// witnesses pass when tightly clustered (0.3) but not when spread over the whole
+    /// clustering radius (0.11), nor with one witness.
+    pub min_confidence: f64,
+    /// Upper bound on decode passes, in case the assignments keep oscillating.
+    pub max_iterations: usize,
+}
+
+impl Default for PromotionConfig {
+    fn default() -> Self {
+        PromotionConfig {
+            min_observations: 3,
+            min_confidence: 0.25,
+            max_iterations: 5,
+        }
+    }
+}
+
+impl PromotionConfig {
+    /// Reads a config from a YAML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open promotion config {}", path.display()))?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+}
+
+/// What one decode pass of `promote_unknowns` looked like.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PromotionIteration {
+    /// 1 for the first pass, which has no inferred repeaters.
+    pub iteration: usize,
+    /// Inferred repeaters in the graph during this pass.
+    pub inferred_nodes: usize,
+    /// Number of feasible directed edges in the graph.
+    pub edge_count: usize,
+    /// Of `edge_count`, the edges into or out of an inferred repeater.
+    pub inferred_edges: usize,
+    /// Hops resolved to a database repeater across all packets.
+    pub known_hops: usize,
+    /// Hops resolved to an inferred repeater across all packets.
+    pub inferred_hops: usize,
+    /// Hops left as Unknown across all packets.
+    pub unknown_hops: usize,
+    /// Packets that could not be decoded at all.
+    pub failed_packets: usize,
+    /// Packets whose decoded path differs from the previous pass (every packet on the first).
+    pub changed_packets: usize,
+}
+
+/// Outcome of `promote_unknowns`.
+#[derive(Debug)]
+pub struct PromotionResult {
+    /// Decodes from the last pass, in the same order as the packets.
+    pub decoded: Vec<Result<DecodedPath>>,
+    /// Unknowns localized from the last pass, the promoted ones included.
+    pub inferred: Vec<InferredRepeater>,
+    pub iterations: Vec<PromotionIteration>,
+    /// Whether the last pass left every packet's path unchanged.
+    pub converged: bool,
+}
+
+/// Feeds localized Unknowns back into the graph until the decoded paths stop changing.
+///
+/// Each pass decodes every packet, localizes the Unknown hops, and replaces the graph's
+/// inferred repeaters with the clusters that meet `config.min_observations` and
+/// `config.min_confidence`, placed at the best Pass 2 candidate when the heatmap ran.
+/// Hops already resolved to an inferred repeater are localized as Unknowns again, so its
+/// position is re-estimated from everything assigned to it. The graph keeps the inferred
+/// repeaters of the last pass.
+///
+/// `timestamps` runs parallel to `packets` and only feeds `InferredRepeater::first_seen`
+/// and `last_seen`; it may be shorter, or empty, when packets have none.
+pub fn promote_unknowns(
+    graph: &mut NetworkGraph,
+    packets: &[PacketPath],
+    timestamps: &[Option<String>],
+    localization: &LocalizationConfig,
+    config: &PromotionConfig,
+    threads: usize,
+) -> PromotionResult {
+    graph.set_inferred_nodes(Vec::new());
+    let mut iterations = Vec::new();
+    let mut previous: Option<Vec<Option<Vec<PathNode>>>> = None;
+
+    loop {
+        let decoded = graph.decode_batch(packets, threads);
+        let paths: Vec<Option<Vec<PathNode>>> = decoded
+            .iter()
+            .map(|result| result.as_ref().ok().map(|d| d.hops.clone()))
+            .collect();
+
+        let mut iteration = PromotionIteration {
+            iteration: iterations.len() + 1,
+            inferred_nodes: graph.inferred_count(),
+            edge_count: graph.edge_count(),
+            inferred_edges: graph.inferred_edge_count(),
+            known_hops: 0,
+            inferred_hops: 0,
+            unknown_hops: 0,
+            failed_packets: 0,
+            changed_packets: 0,
+        };
+        for (t, path) in paths.iter().enumerate() {
+            let Some(path) = path else {
+                iteration.failed_packets += 1;
+                continue;
+            };
+            for node in path {
+                match node {
+                    PathNode::Known(idx) if graph.nodes()[*idx].inferred => iteration.inferred_hops += 1,
+                    PathNode::Known(_) => iteration.known_hops += 1,
+                    PathNode::Unknown(_) => iteration.unknown_hops += 1,
+                }
+            }
+            if previous.as_ref().is_none_or(|p| p[t].as_ref() != Some(path)) {
+                iteration.changed_packets += 1;
+            }
+        }
+        let converged = previous.is_some() && iteration.changed_packets == 0;
+        iterations.push(iteration);
+
+        // Localize with inferred hops turned back into the Unknowns they were logged as
+        let observations: Vec<PathObservation> = packets
+            .iter()
+            .zip(&paths)
+            .enumerate()
+            .filter_map(|(t, (packet, path))| {
+                let path = path
+                    .as_ref()?
+                    .iter()
+                    .zip(&packet.hashes)
+                    .map(|(node, &hash)| match node {
+                        PathNode::Known(idx) if graph.nodes()[*idx].inferred => PathNode::Unknown(hash),
+                        _ => node.clone(),
+                    })
+                    .collect();
+                Some(PathObservation {
+                    path,
+                    origin: packet.origin,
+                    observer: packet.observer,
+                    timestamp: timestamps.get(t).cloned().flatten(),
+                })
+            })
+            .collect();
+        let inferred = localize_unknowns_with_model(
+            &observations,
+            graph.nodes(),
+            graph.model(),
+            graph.terrain(),
+            localization,
+        );
+
+        if converged || iterations.len() >= config.max_iterations.max(1) {
+            return PromotionResult {
+                decoded,
+                inferred,
+                iterations,
+                converged,
+            };
+        }
+
+        let promoted = inferred
+            .iter()
+            .filter(|r| r.observation_count >= config.min_observations && r.confidence >= config.min_confidence)
+            .map(provisional_repeater)
+            .collect();
+        graph.set_inferred_nodes(promoted);
+        previous = Some(paths);
+    }
+}
+
+/// A repeater at the inferred location whose ID is the cluster's prefix, so it matches
+/// exactly the hops that were localized into it.
+fn provisional_repeater(inferred: &InferredRepeater) -> Repeater {
+    let (lat, lon) = inferred
+        .candidates
+        .first()
+        .map_or((inferred.lat, inferred.lon), |c| (c.lat, c.lon));
+    Repeater {
+        id: inferred.prefix.clone(),
+        name: format!("Inferred {}", inferred.prefix),
+        lat,
+        lon,
+        inferred: true,
+        ..Default::default()
+    }
+}
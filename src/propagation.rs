@@ -0,0 +1,462 @@
+use crate::calibration::PathLossParams;
+use crate::models::Repeater;
+use crate::physics::{
+    LORA_FREQ_EU_MHZ, LinkBudget, MAX_LINK_RANGE_KM, antenna_link_cost, excess_path_loss_db, free_space_path_loss_db,
+    haversine_distance, link_budget_cost,
+};
+use crate::terrain::TerrainMap;
+
+/// Computes link costs between repeaters for `NetworkGraph`.
+///
+/// Costs are Negative Log Probabilities: 0 for a certain link, larger for less likely links,
+/// `f64::INFINITY` for impossible ones, including links longer than the model's maximum range.
+/// Implementations must be thread-safe so a graph built from them can be shared across
+/// decoding threads.
+pub trait PropagationModel: Send + Sync {
+    /// Short identifier used in logs and on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Cost of a transmission from `from` being received by `to`.
+    ///
+    /// Not necessarily symmetric: TX power belongs to `from` and the noise floor to `to`.
+    fn link_cost(&self, from: &Repeater, to: &Repeater, terrain: Option<&TerrainMap>) -> f64;
+
+    /// Effective earth radius factor used for the earth bulge and terrain curvature.
+    fn k_factor(&self) -> f64;
+
+    /// A copy of this model using a different `k_factor`, e.g. for a refraction sweep.
+    fn with_k_factor(&self, k_factor: f64) -> Box<dyn PropagationModel>;
+
+    /// A copy of this model treating links longer than `max_range_km` as impossible.
+    /// `NetworkGraph` sets this from `DecoderConfig::max_link_range_km`.
+    fn with_max_range_km(&self, max_range_km: f64) -> Box<dyn PropagationModel>;
+
+    /// A boxed copy of this model, for a graph to own.
+    fn box_clone(&self) -> Box<dyn PropagationModel>;
+}
+
+/// The original hand-tuned model: sigmoids on distance and earth bulge, plus terrain
+/// diffraction when terrain is available (see `physics::antenna_link_cost`).
+///
+/// Defaults to the geometric earth radius (k = 1) that the sigmoids were tuned against,
+/// and to the EU frequency; take `frequency_mhz` from the radio config for other bands.
+#[derive(Debug, Clone)]
+pub struct SigmoidModel {
+    pub k_factor: f64,
+    /// Radio frequency for the terrain diffraction loss.
+    pub frequency_mhz: f64,
+    /// Links longer than this are impossible.
+    pub max_range_km: f64,
+}
+
+impl Default for SigmoidModel {
+    fn default() -> Self {
+        SigmoidModel {
+            k_factor: 1.0,
+            frequency_mhz: LORA_FREQ_EU_MHZ,
+            max_range_km: MAX_LINK_RANGE_KM,
+        }
+    }
+}
+
+impl PropagationModel for SigmoidModel {
+    fn name(&self) -> &'static str {
+        "sigmoid"
+    }
+
+    fn link_cost(&self, from: &Repeater, to: &Repeater, terrain: Option<&TerrainMap>) -> f64 {
+        if haversine_distance(from.lat, from.lon, to.lat, to.lon) > self.max_range_km {
+            return f64::INFINITY;
+        }
+        antenna_link_cost(from.antenna(), to.antenna(), terrain, self.k_factor, self.frequency_mhz)
+    }
+
+    fn k_factor(&self) -> f64 {
+        self.k_factor
+    }
+
+    fn with_k_factor(&self, k_factor: f64) -> Box<dyn PropagationModel> {
+        Box::new(SigmoidModel {
+            k_factor,
+            ..self.clone()
+        })
+    }
+
+    fn with_max_range_km(&self, max_range_km: f64) -> Box<dyn PropagationModel> {
+        Box::new(SigmoidModel {
+            max_range_km,
+            ..self.clone()
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn PropagationModel> {
+        Box::new(self.clone())
+    }
+}
+
+/// Link budget with free-space path loss only.
+/// Ignores terrain and earth curvature entirely, so it is an optimistic upper bound on range.
+#[derive(Debug, Clone)]
+pub struct FreeSpaceModel {
+    pub budget: LinkBudget,
+    /// Links longer than this are impossible.
+    pub max_range_km: f64,
+}
+
+impl FreeSpaceModel {
+    pub fn new(budget: LinkBudget) -> Self {
+        FreeSpaceModel {
+            budget,
+            max_range_km: MAX_LINK_RANGE_KM,
+        }
+    }
+}
+
+impl Default for FreeSpaceModel {
+    fn default() -> Self {
+        FreeSpaceModel::new(LinkBudget::default())
+    }
+}
+
+impl PropagationModel for FreeSpaceModel {
+    fn name(&self) -> &'static str {
+        "free-space"
+    }
+
+    fn link_cost(&self, from: &Repeater, to: &Repeater, _terrain: Option<&TerrainMap>) -> f64 {
+        let dist_km = haversine_distance(from.lat, from.lon, to.lat, to.lon);
+        if dist_km > self.max_range_km {
+            return f64::INFINITY;
+        }
+        let budget = link_specific_budget(&self.budget, from, to);
+        let path_loss_db = free_space_path_loss_db(dist_km, budget.frequency_mhz);
+        budget.cost_for_rx_power(budget.received_power_dbm(path_loss_db))
+    }
+
+    /// Free space has no curvature, so this only round-trips the budget setting.
+    fn k_factor(&self) -> f64 {
+        self.budget.k_factor
+    }
+
+    fn with_k_factor(&self, k_factor: f64) -> Box<dyn PropagationModel> {
+        let mut model = self.clone();
+        model.budget.k_factor = k_factor;
+        Box::new(model)
+    }
+
+    fn with_max_range_km(&self, max_range_km: f64) -> Box<dyn PropagationModel> {
+        Box::new(FreeSpaceModel {
+            max_range_km,
+            ..self.clone()
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn PropagationModel> {
+        Box::new(self.clone())
+    }
+}
+
+/// Link budget with free-space loss plus diffraction over the terrain profile
+/// (or over a smooth earth when no terrain is loaded). See `physics::link_budget_cost`.
+#[derive(Debug, Clone)]
+pub struct TerrainAwareModel {
+    pub budget: LinkBudget,
+    /// Links longer than this are impossible.
+    pub max_range_km: f64,
+}
+
+impl TerrainAwareModel {
+    pub fn new(budget: LinkBudget) -> Self {
+        TerrainAwareModel {
+            budget,
+            max_range_km: MAX_LINK_RANGE_KM,
+        }
+    }
+}
+
+impl Default for TerrainAwareModel {
+    fn default() -> Self {
+        TerrainAwareModel::new(LinkBudget::default())
+    }
+}
+
+impl PropagationModel for TerrainAwareModel {
+    fn name(&self) -> &'static str {
+        "terrain-aware"
+    }
+
+    fn link_cost(&self, from: &Repeater, to: &Repeater, terrain: Option<&TerrainMap>) -> f64 {
+        if haversine_distance(from.lat, from.lon, to.lat, to.lon) > self.max_range_km {
+            return f64::INFINITY;
+        }
+        let budget = link_specific_budget(&self.budget, from, to);
+        link_budget_cost(from.antenna(), to.antenna(), terrain, &budget)
+    }
+
+    fn k_factor(&self) -> f64 {
+        self.budget.k_factor
+    }
+
+    fn with_k_factor(&self, k_factor: f64) -> Box<dyn PropagationModel> {
+        let mut model = self.clone();
+        model.budget.k_factor = k_factor;
+        Box::new(model)
+    }
+
+    fn with_max_range_km(&self, max_range_km: f64) -> Box<dyn PropagationModel> {
+        Box::new(TerrainAwareModel {
+            max_range_km,
+            ..self.clone()
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn PropagationModel> {
+        Box::new(self.clone())
+    }
+}
+
+/// Link budget with a log-distance path loss fitted to observed receptions
+/// (see `calibration::fit_path_loss`), plus diffraction over the terrain profile.
+///
+/// Receiver noise floors and the fading spread come from the calibration as well.
+#[derive(Debug, Clone)]
+pub struct LogDistanceModel {
+    pub params: PathLossParams,
+    budget: LinkBudget,
+    /// Links longer than this are impossible.
+    pub max_range_km: f64,
+}
+
+impl LogDistanceModel {
+    pub fn new(params: PathLossParams) -> Self {
+        let budget = params.calibrated_budget();
+        LogDistanceModel {
+            params,
+            budget,
+            max_range_km: MAX_LINK_RANGE_KM,
+        }
+    }
+}
+
+impl PropagationModel for LogDistanceModel {
+    fn name(&self) -> &'static str {
+        "log-distance"
+    }
+
+    fn link_cost(&self, from: &Repeater, to: &Repeater, terrain: Option<&TerrainMap>) -> f64 {
+        let dist_km = haversine_distance(from.lat, from.lon, to.lat, to.lon);
+        if dist_km > self.max_range_km {
+            return f64::INFINITY;
+        }
+        let budget = LinkBudget {
+            noise_floor_dbm: Some(self.params.noise_floor_for(to)),
+            ..link_specific_budget(&self.budget, from, to)
+        };
+        let path_loss_db = self.params.path_loss_db(dist_km)
+            + excess_path_loss_db(from.antenna(), to.antenna(), terrain, &budget);
+        budget.cost_for_rx_power(budget.received_power_dbm(path_loss_db))
+    }
+
+    fn k_factor(&self) -> f64 {
+        self.budget.k_factor
+    }
+
+    fn with_k_factor(&self, k_factor: f64) -> Box<dyn PropagationModel> {
+        let mut model = self.clone();
+        model.budget.k_factor = k_factor;
+        Box::new(model)
+    }
+
+    fn with_max_range_km(&self, max_range_km: f64) -> Box<dyn PropagationModel> {
+        Box::new(LogDistanceModel {
+            max_range_km,
+            ..self.clone()
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn PropagationModel> {
+        Box::new(self.clone())
+    }
+}
+
+/// Fills in the per-repeater radio parameters of a link (TX power and gain from the
+/// transmitter, gain and noise floor from the receiver), falling back to the network-wide `budget`.
+pub(crate) fn link_specific_budget(budget: &LinkBudget, from: &Repeater, to: &Repeater) -> LinkBudget {
+    LinkBudget {
+        tx_power_dbm: from.tx_power_dbm.unwrap_or(budget.tx_power_dbm),
+        tx_antenna_gain_dbi: from.antenna_gain_dbi.unwrap_or(budget.tx_antenna_gain_dbi),
+        rx_antenna_gain_dbi: to.antenna_gain_dbi.unwrap_or(budget.rx_antenna_gain_dbi),
+        noise_floor_dbm: to.noise_floor_dbm.or(budget.noise_floor_dbm),
+        ..budget.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(lat: f64, lon: f64) -> Repeater {
+        Repeater {
+            id: "000000".to_string(),
+            name: "Test".to_string(),
+            lat,
+            lon,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_models_rank_distance_consistently() {
+        let a = node(51.0, 0.0);
+        let near = node(51.2, 0.0); // ~22km
+        let far = node(51.9, 0.0); // ~100km
+
+        let models: Vec<Box<dyn PropagationModel>> = vec![
+            Box::new(SigmoidModel::default()),
+            Box::new(FreeSpaceModel::default()),
+            Box::new(TerrainAwareModel::default()),
+        ];
+
+        for model in &models {
+            let c_near = model.link_cost(&a, &near, None);
+            let c_far = model.link_cost(&a, &far, None);
+            assert!(c_near < c_far, "{} should prefer the nearer node", model.name());
+        }
+    }
+
+    #[test]
+    fn test_free_space_ignores_horizon() {
+        // ~100km is far beyond the radio horizon for 30m masts.
+        let a = node(51.0, 0.0);
+        let b = node(51.9, 0.0);
+
+        let free_space = FreeSpaceModel::default().link_cost(&a, &b, None);
+        let terrain_aware = TerrainAwareModel::default().link_cost(&a, &b, None);
+        assert!(free_space < terrain_aware);
+    }
+
+    #[test]
+    fn test_per_repeater_radio_parameters() {
+        let a = node(51.0, 0.0);
+        let b = node(51.7, 0.0); // ~78km
+        let model = TerrainAwareModel::default();
+        let base = model.link_cost(&a, &b, None);
+
+        let mast = Repeater {
+            antenna_height_m: Some(100.0),
+            ..a.clone()
+        };
+        let loud = Repeater {
+            tx_power_dbm: Some(30.0),
+            antenna_gain_dbi: Some(8.0),
+            ..a.clone()
+        };
+        let weak = Repeater {
+            tx_power_dbm: Some(10.0),
+            ..a.clone()
+        };
+
+        assert!(model.link_cost(&mast, &b, None) < base);
+        assert!(model.link_cost(&loud, &b, None) < base);
+        assert!(model.link_cost(&weak, &b, None) > base);
+
+        // Taller masts also help the sigmoid model's horizon term
+        let c = node(51.5, 0.0); // ~55km
+        let sigmoid = SigmoidModel::default();
+        assert!(sigmoid.link_cost(&mast, &c, None) < sigmoid.link_cost(&a, &c, None));
+    }
+
+    #[test]
+    fn test_directed_link_costs() {
+        let a = node(51.0, 0.0);
+        let b = node(51.4, 0.0); // ~44km
+        let model = TerrainAwareModel::default();
+        assert_eq!(model.link_cost(&a, &b, None), model.link_cost(&b, &a, None));
+
+        // A loud transmitter with a noisy receiver: A is heard well but hears poorly
+        let loud_and_deaf = Repeater {
+            tx_power_dbm: Some(30.0),
+            noise_floor_dbm: Some(-100.0),
+            ..a.clone()
+        };
+        let out = model.link_cost(&loud_and_deaf, &b, None);
+        let back = model.link_cost(&b, &loud_and_deaf, None);
+        assert!(out < model.link_cost(&a, &b, None));
+        assert!(back > model.link_cost(&b, &a, None));
+        assert!(out < back);
+    }
+
+    #[test]
+    fn test_log_distance_model() {
+        let params = PathLossParams {
+            path_loss_exponent: 3.0,
+            reference_loss_db: 100.0,
+            shadowing_sigma_db: 6.0,
+            noise_floor_dbm: -115.0,
+            noise_floor_by_repeater: [("0x02".to_string(), -100.0)].into_iter().collect(),
+            sample_count: 10,
+            budget: LinkBudget::default(),
+        };
+        let model = LogDistanceModel::new(params);
+        let a = node(51.0, 0.0);
+        let near = node(51.05, 0.0); // ~5.5km
+        let far = node(51.15, 0.0); // ~17km
+        assert!(model.link_cost(&a, &near, None) < model.link_cost(&a, &far, None));
+
+        // The calibrated noise floor of a receiver makes links into it more expensive
+        let noisy = Repeater {
+            id: "0x02".to_string(),
+            ..near.clone()
+        };
+        assert!(model.link_cost(&a, &noisy, None) > model.link_cost(&a, &near, None));
+        assert_eq!(model.link_cost(&noisy, &a, None), model.link_cost(&near, &a, None));
+    }
+
+    #[test]
+    fn test_sigmoid_frequency() {
+        use crate::physics::LORA_FREQ_US_MHZ;
+
+        // A ridge poking just above the ray: shorter waves diffract less around it
+        let mut map = TerrainMap::new_flat(0.0, 0.0, 20.0, 20.0, 30.0);
+        let mid_col = map.width / 2;
+        for r in 0..map.height {
+            for c in mid_col - 1..=mid_col + 1 {
+                map.data[r * map.width + c] = 35.0;
+            }
+        }
+        let (a, b) = (node(0.0, -0.05), node(0.0, 0.05));
+        let eu = SigmoidModel::default();
+        let us = SigmoidModel {
+            frequency_mhz: LORA_FREQ_US_MHZ,
+            ..SigmoidModel::default()
+        };
+        assert!(us.link_cost(&a, &b, Some(&map)) > eu.link_cost(&a, &b, Some(&map)));
+        // ...and the frequency survives a change of k-factor
+        assert_eq!(us.with_k_factor(1.0).link_cost(&a, &b, Some(&map)), us.link_cost(&a, &b, Some(&map)));
+
+        // Without terrain the frequency doesn't matter
+        assert_eq!(us.link_cost(&a, &b, None), eu.link_cost(&a, &b, None));
+    }
+
+    #[test]
+    fn test_with_k_factor() {
+        let a = node(51.0, 0.0);
+        let b = node(51.6, 0.0); // ~67km, beyond the geometric radio horizon
+
+        let models: Vec<Box<dyn PropagationModel>> = vec![
+            Box::new(SigmoidModel::default()),
+            Box::new(TerrainAwareModel::default()),
+        ];
+
+        for model in &models {
+            let refracted = model.with_k_factor(2.0);
+            assert_eq!(refracted.k_factor(), 2.0);
+            assert_eq!(refracted.name(), model.name());
+            assert!(
+                refracted.link_cost(&a, &b, None) < model.link_cost(&a, &b, None),
+                "{} should reach further with more refraction",
+                model.name()
+            );
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use crate::models::Repeater;
+use crate::terrain::TerrainMap;
+use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Value used by SRTM to mark a sample with no data (radar shadow, water, etc).
+pub const SRTM_VOID: i16 = -32768;
+
+/// Samples per tile edge for 1-arcsecond (~30m) tiles.
+pub const SAMPLES_1_ARCSEC: usize = 3601;
+
+/// Samples per tile edge for 3-arcsecond (~90m) tiles.
+pub const SAMPLES_3_ARCSEC: usize = 1201;
+
+/// A single 1x1 degree SRTM tile.
+///
+/// `.hgt` files are a square grid of big-endian `i16` elevations in meters,
+/// stored row by row from North to South. Adjacent tiles share their edge rows/columns,
+/// so a tile with `samples` points per edge has `samples - 1` intervals per degree.
+#[derive(Debug, Clone)]
+pub struct HgtTile {
+    /// Latitude of the South-West corner (the `N51` in `N51W001.hgt`).
+    pub lat: i32,
+    /// Longitude of the South-West corner (the `W001` in `N51W001.hgt`).
+    pub lon: i32,
+    /// Number of samples per edge (1201 or 3601).
+    pub samples: usize,
+    /// Row-major elevations, row 0 is the Northern edge.
+    pub data: Vec<i16>,
+}
+
+impl HgtTile {
+    /// Parses a raw `.hgt` buffer for the tile whose South-West corner is (`lat`, `lon`).
+    pub fn from_bytes(lat: i32, lon: i32, bytes: &[u8]) -> Result<Self> {
+        let samples = match bytes.len() {
+            n if n == SAMPLES_1_ARCSEC * SAMPLES_1_ARCSEC * 2 => SAMPLES_1_ARCSEC,
+            n if n == SAMPLES_3_ARCSEC * SAMPLES_3_ARCSEC * 2 => SAMPLES_3_ARCSEC,
+            n => {
+                return Err(anyhow!(
+                    "Unexpected .hgt size {} bytes for tile {}",
+                    n,
+                    tile_name(lat, lon)
+                ));
+            }
+        };
+
+        let data = bytes
+            .chunks_exact(2)
+            .map(|b| i16::from_be_bytes([b[0], b[1]]))
+            .collect();
+
+        Ok(HgtTile {
+            lat,
+            lon,
+            samples,
+            data,
+        })
+    }
+
+    /// Reads a `.hgt` file from disk.
+    pub fn read(path: &Path, lat: i32, lon: i32) -> Result<Self> {
+        let bytes =
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_bytes(lat, lon, &bytes)
+    }
+
+    /// Number of sample intervals per degree (1200 or 3600).
+    pub fn samples_per_degree(&self) -> usize {
+        self.samples - 1
+    }
+
+    /// Replaces void samples with the mean of their valid neighbours.
+    ///
+    /// Runs repeatedly so that large voids are filled inwards from their edges.
+    /// A tile that is entirely void is set to sea level. `load_terrain` fills the stitched
+    /// mosaic instead, so voids on a tile edge can grow from the neighbouring tile.
+    pub fn fill_voids(&mut self) {
+        let mut grid: Vec<f32> = self
+            .data
+            .iter()
+            .map(|&v| if v == SRTM_VOID { f32::NAN } else { v as f32 })
+            .collect();
+        fill_grid_voids(&mut grid, self.samples, self.samples);
+        for (v, filled) in self.data.iter_mut().zip(grid) {
+            *v = filled.round() as i16;
+        }
+    }
+
+    /// Elevation at a point inside the tile using bilinear interpolation,
+    /// or NaN where it depends on a void sample.
+    pub fn get_elevation(&self, lat: f64, lon: f64) -> f64 {
+        let spd = self.samples_per_degree() as f64;
+        let max_idx = (self.samples - 1) as f64;
+
+        // Row 0 is the North edge.
+        let r_float = ((self.lat as f64 + 1.0 - lat) * spd).clamp(0.0, max_idx);
+        let c_float = ((lon - self.lon as f64) * spd).clamp(0.0, max_idx);
+
+        let r0 = r_float.floor() as usize;
+        let c0 = c_float.floor() as usize;
+        let r1 = (r0 + 1).min(self.samples - 1);
+        let c1 = (c0 + 1).min(self.samples - 1);
+
+        let dr = r_float - r0 as f64;
+        let dc = c_float - c0 as f64;
+
+        let corners = [
+            (r0, c0, (1.0 - dr) * (1.0 - dc)),
+            (r0, c1, (1.0 - dr) * dc),
+            (r1, c0, dr * (1.0 - dc)),
+            (r1, c1, dr * dc),
+        ];
+        let mut h = 0.0;
+        for (r, c, weight) in corners {
+            if weight == 0.0 {
+                continue;
+            }
+            let v = self.data[r * self.samples + c];
+            if v == SRTM_VOID {
+                return f64::NAN;
+            }
+            h += v as f64 * weight;
+        }
+        h
+    }
+}
+
+/// Replaces the void (NaN) cells of a row-major grid with the mean of their valid neighbours.
+///
+/// Fills large voids inwards from their edges, one ring at a time: a breadth-first frontier
+/// of void cells bordering filled data, so each void cell is visited once.
+/// A grid that is entirely void is set to sea level.
+fn fill_grid_voids(data: &mut [f32], width: usize, height: usize) {
+    let neighbours = |i: usize| {
+        let (r, c) = (i / width, i % width);
+        (r.saturating_sub(1)..=(r + 1).min(height - 1))
+            .flat_map(move |nr| (c.saturating_sub(1)..=(c + 1).min(width - 1)).map(move |nc| nr * width + nc))
+            .filter(move |&n| n != i)
+    };
+
+    // The first ring: void cells next to valid data
+    let mut queued = vec![false; data.len()];
+    let mut frontier: Vec<usize> = Vec::new();
+    for i in 0..data.len() {
+        if data[i].is_nan() && neighbours(i).any(|n| !data[n].is_nan()) {
+            queued[i] = true;
+            frontier.push(i);
+        }
+    }
+
+    while !frontier.is_empty() {
+        // Compute the whole ring before applying it, so each ring only uses values from
+        // the ones before.
+        let filled: Vec<f32> = frontier
+            .iter()
+            .map(|&i| {
+                let (sum, count) = neighbours(i)
+                    .map(|n| data[n])
+                    .filter(|v| !v.is_nan())
+                    .fold((0.0f64, 0usize), |(sum, count), v| (sum + v as f64, count + 1));
+                (sum / count as f64) as f32
+            })
+            .collect();
+        for (&i, v) in frontier.iter().zip(filled) {
+            data[i] = v;
+        }
+
+        let mut next = Vec::new();
+        for &i in &frontier {
+            for n in neighbours(i) {
+                if data[n].is_nan() && !queued[n] {
+                    queued[n] = true;
+                    next.push(n);
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    // Nothing to grow from
+    if data.iter().any(|v| v.is_nan()) {
+        data.fill(0.0);
+    }
+}
+
+/// The integer degrees of the tiles containing coordinate `x`: one, or two when it lies
+/// exactly on the edge that adjacent tiles share.
+fn tile_degrees(x: f64) -> (i32, Option<i32>) {
+    let floor = x.floor();
+    (floor as i32, (x == floor).then_some(floor as i32 - 1))
+}
+
+/// Returns the standard SRTM file name (without directory) for the tile whose
+/// South-West corner is at (`lat`, `lon`), e.g. `N51W001.hgt`.
+pub fn tile_name(lat: i32, lon: i32) -> String {
+    let ns = if lat >= 0 { 'N' } else { 'S' };
+    let ew = if lon >= 0 { 'E' } else { 'W' };
+    format!("{}{:02}{}{:03}.hgt", ns, lat.abs(), ew, lon.abs())
+}
+
+/// Lists the (lat, lon) South-West corners of every tile touched by the bounding box.
+pub fn tiles_for_bounds(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Vec<(i32, i32)> {
+    let lat_start = min_lat.floor() as i32;
+    let lon_start = min_lon.floor() as i32;
+    // A box ending exactly on a tile edge doesn't need the next tile.
+    let lat_end = (max_lat.ceil() as i32 - 1).max(lat_start);
+    let lon_end = (max_lon.ceil() as i32 - 1).max(lon_start);
+
+    let mut tiles = Vec::new();
+    for lat in lat_start..=lat_end {
+        for lon in lon_start..=lon_end {
+            tiles.push((lat, lon));
+        }
+    }
+    tiles
+}
+
+/// Margin added around the repeater bounding box by `load_terrain_around`, so that
+/// links near the edge of the network are still sampled over real elevation.
+pub const DEFAULT_TERRAIN_MARGIN_DEG: f64 = 0.2;
+
+/// Loads the terrain covering all `repeaters` plus `margin_deg` on every side.
+pub fn load_terrain_around(dir: &Path, repeaters: &[Repeater], margin_deg: f64) -> Result<TerrainMap> {
+    if repeaters.is_empty() {
+        return Err(anyhow!("No repeaters to load SRTM terrain around"));
+    }
+    let min_lat = repeaters.iter().map(|r| r.lat).fold(f64::INFINITY, f64::min);
+    let max_lat = repeaters.iter().map(|r| r.lat).fold(f64::NEG_INFINITY, f64::max);
+    let min_lon = repeaters.iter().map(|r| r.lon).fold(f64::INFINITY, f64::min);
+    let max_lon = repeaters.iter().map(|r| r.lon).fold(f64::NEG_INFINITY, f64::max);
+
+    load_terrain(
+        dir,
+        min_lat - margin_deg,
+        min_lon - margin_deg,
+        max_lat + margin_deg,
+        max_lon + margin_deg,
+    )
+}
+
+/// Loads every tile needed to cover the bounding box from `dir` and stitches them
+/// into a single `TerrainMap` cropped to the box.
+///
+/// * Tiles missing from the directory are treated as sea level (SRTM omits all-ocean tiles).
+/// * Mixed 1- and 3-arcsecond tiles are resampled to the finest resolution present.
+/// * Void samples are filled from their neighbours after stitching, so a void on a tile
+///   edge fills from the neighbouring tile too.
+/// * Elevations are stored as `f32`: a 1-arcsecond mosaic costs 4 bytes per sample.
+pub fn load_terrain(
+    dir: &Path,
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+) -> Result<TerrainMap> {
+    if min_lat >= max_lat || min_lon >= max_lon {
+        return Err(anyhow!("Empty bounding box for SRTM terrain"));
+    }
+
+    let mut tiles: HashMap<(i32, i32), HgtTile> = HashMap::new();
+    for (lat, lon) in tiles_for_bounds(min_lat, min_lon, max_lat, max_lon) {
+        let path = dir.join(tile_name(lat, lon));
+        if !path.exists() {
+            continue;
+        }
+        tiles.insert((lat, lon), HgtTile::read(&path, lat, lon)?);
+    }
+
+    if tiles.is_empty() {
+        return Err(anyhow!(
+            "No SRTM tiles found in {} for bounds ({}, {}) - ({}, {})",
+            dir.display(),
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon
+        ));
+    }
+
+    let spd = tiles
+        .values()
+        .map(HgtTile::samples_per_degree)
+        .max()
+        .unwrap_or(SAMPLES_3_ARCSEC - 1);
+    let step = 1.0 / spd as f64;
+
+    // Snap the box outwards onto the sample grid so tile samples land exactly on cells.
+    let grid_min_lat = (min_lat * spd as f64).floor() / spd as f64;
+    let grid_min_lon = (min_lon * spd as f64).floor() / spd as f64;
+    let rows = ((max_lat - grid_min_lat) * spd as f64).ceil() as usize + 1;
+    let cols = ((max_lon - grid_min_lon) * spd as f64).ceil() as usize + 1;
+    let grid_max_lat = grid_min_lat + (rows - 1) as f64 * step;
+    let grid_max_lon = grid_min_lon + (cols - 1) as f64 * step;
+
+    let mut data = vec![0.0f32; rows * cols];
+
+    let col_lons: Vec<(i32, Option<i32>)> = (0..cols)
+        .map(|c| tile_degrees(grid_min_lon + c as f64 * step))
+        .collect();
+    for r in 0..rows {
+        let lat = grid_min_lat + r as f64 * step;
+        let lat_tiles = tile_degrees(lat);
+        for c in 0..cols {
+            let lon = grid_min_lon + c as f64 * step;
+            // Shared edge samples come from whichever neighbouring tile is actually present.
+            let lon_tiles = col_lons[c];
+            let tile = [lat_tiles.0].into_iter().chain(lat_tiles.1).find_map(|tile_lat| {
+                [lon_tiles.0]
+                    .into_iter()
+                    .chain(lon_tiles.1)
+                    .find_map(|tile_lon| tiles.get(&(tile_lat, tile_lon)))
+            });
+            if let Some(tile) = tile {
+                data[r * cols + c] = tile.get_elevation(lat, lon) as f32;
+            }
+        }
+    }
+    fill_grid_voids(&mut data, cols, rows);
+
+    Ok(TerrainMap {
+        min_lat: grid_min_lat,
+        min_lon: grid_min_lon,
+        max_lat: grid_max_lat,
+        max_lon: grid_max_lon,
+        resolution_deg: step,
+        width: cols,
+        height: rows,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_tile(dir: &Path, lat: i32, lon: i32, samples: usize, f: impl Fn(usize, usize) -> i16) {
+        let mut bytes = Vec::with_capacity(samples * samples * 2);
+        for r in 0..samples {
+            for c in 0..samples {
+                bytes.extend_from_slice(&f(r, c).to_be_bytes());
+            }
+        }
+        fs::write(dir.join(tile_name(lat, lon)), bytes).unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("srtm_test_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_tile_name() {
+        assert_eq!(tile_name(51, -1), "N51W001.hgt");
+        assert_eq!(tile_name(-34, 151), "S34E151.hgt");
+        assert_eq!(tile_name(0, 0), "N00E000.hgt");
+    }
+
+    #[test]
+    fn test_tiles_for_bounds() {
+        let tiles = tiles_for_bounds(51.2, -1.5, 52.4, 0.3);
+        assert_eq!(tiles, vec![(51, -2), (51, -1), (51, 0), (52, -2), (52, -1), (52, 0)]);
+
+        // Box ending exactly on a tile edge only needs one tile.
+        assert_eq!(tiles_for_bounds(51.0, 0.0, 52.0, 1.0), vec![(51, 0)]);
+    }
+
+    #[test]
+    fn test_fill_voids() {
+        let n = SAMPLES_3_ARCSEC;
+        let mut data = vec![100i16; n * n];
+        // A 3x3 hole in the middle
+        for r in 10..13 {
+            for c in 10..13 {
+                data[r * n + c] = SRTM_VOID;
+            }
+        }
+        let mut tile = HgtTile { lat: 0, lon: 0, samples: n, data };
+        tile.fill_voids();
+        assert!(tile.data.iter().all(|&v| v == 100));
+    }
+
+    #[test]
+    fn test_fill_grid_voids_grows_inwards() {
+        // Only the left column is known; the rest fills in ring by ring
+        let (width, height) = (50, 40);
+        let mut data = vec![f32::NAN; width * height];
+        for r in 0..height {
+            data[r * width] = 10.0;
+        }
+        fill_grid_voids(&mut data, width, height);
+        assert!(data.iter().all(|&v| (v - 10.0).abs() < 1e-4));
+
+        let mut empty = vec![f32::NAN; 12];
+        fill_grid_voids(&mut empty, 4, 3);
+        assert!(empty.iter().all(|&v| v == 0.0));
+    }
+
+    #[test]
+    fn test_load_mosaic_across_tiles() {
+        let dir = temp_dir("mosaic");
+        // Two 3-arcsecond tiles side by side with different constant heights.
+        write_tile(&dir, 51, 0, SAMPLES_3_ARCSEC, |_, _| 100);
+        write_tile(&dir, 51, 1, SAMPLES_3_ARCSEC, |_, _| 300);
+
+        let map = load_terrain(&dir, 51.4, 0.5, 51.6, 1.5).expect("mosaic should load");
+
+        assert!((map.get_elevation(51.5, 0.6) - 100.0).abs() < 1e-6);
+        assert!((map.get_elevation(51.5, 1.4) - 300.0).abs() < 1e-6);
+        assert!(map.min_lat <= 51.4 && map.max_lat >= 51.6);
+        assert!(map.min_lon <= 0.5 && map.max_lon >= 1.5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_orientation_and_missing_tiles() {
+        let dir = temp_dir("orientation");
+        // Elevation rises towards the North: row 0 (North edge) is highest.
+        write_tile(&dir, 10, 20, SAMPLES_3_ARCSEC, |r, _| (1200 - r) as i16);
+
+        // Bounds also cover the (missing) tile to the East, which should read as sea level.
+        let map = load_terrain(&dir, 10.1, 20.5, 10.9, 21.5).expect("mosaic should load");
+
+        let south = map.get_elevation(10.2, 20.6);
+        let north = map.get_elevation(10.8, 20.6);
+        assert!((south - 240.0).abs() < 1.0, "south was {}", south);
+        assert!((north - 960.0).abs() < 1.0, "north was {}", north);
+        assert_eq!(map.get_elevation(10.5, 21.4), 0.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_voids_fill_across_tile_edges() {
+        let dir = temp_dir("edge_voids");
+        // The East tile is entirely void: filled from its own samples it would drop to sea
+        // level, but stitched first it grows from the West tile's shared edge.
+        write_tile(&dir, 51, 0, SAMPLES_3_ARCSEC, |_, _| 100);
+        write_tile(&dir, 51, 1, SAMPLES_3_ARCSEC, |_, _| SRTM_VOID);
+
+        let map = load_terrain(&dir, 51.4, 0.9, 51.6, 1.1).expect("mosaic should load");
+
+        assert!((map.get_elevation(51.5, 1.05) - 100.0).abs() < 1e-3);
+        assert!(map.data.iter().all(|v| !v.is_nan()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 
+#[derive(Clone)]
 pub struct TerrainMap {
     pub min_lat: f64,
     pub min_lon: f64,
@@ -9,7 +10,7 @@ pub struct TerrainMap {
     pub resolution_deg: f64, // approximate degrees per cell
     pub width: usize,
     pub height: usize,
-    pub data: Vec<f64>, // Row-major elevation data in meters
+    pub data: Vec<f32>, // Row-major elevation data in meters (f32 keeps country-scale SRTM mosaics affordable)
 }
 
 impl TerrainMap {
@@ -58,9 +59,7 @@ impl TerrainMap {
                 let mut amp = amplitude;
                 let mut freq = 4.0; // Start with some hills
 
-                for i in 0..octaves {
-                    let phase_x = random_phases[i].0;
-                    let phase_y = random_phases[i].1;
+                for &(phase_x, phase_y) in random_phases.iter().take(octaves) {
 
                     // Simple superposition of sine waves
                     elevation += amp * ((x * freq + phase_x).sin() * (y * freq + phase_y).cos());
@@ -74,7 +73,7 @@ impl TerrainMap {
                     elevation = 0.0;
                 }
 
-                data[r * cols + c] = elevation;
+                data[r * cols + c] = elevation as f32;
             }
         }
 
@@ -171,10 +170,10 @@ impl TerrainMap {
         let dr = r_float - r0 as f64;
         let dc = c_float - c0 as f64;
 
-        let h00 = self.data[r0 * self.width + c0];
-        let h01 = self.data[r0 * self.width + c1];
-        let h10 = self.data[r1 * self.width + c0];
-        let h11 = self.data[r1 * self.width + c1];
+        let h00 = self.data[r0 * self.width + c0] as f64;
+        let h01 = self.data[r0 * self.width + c1] as f64;
+        let h10 = self.data[r1 * self.width + c0] as f64;
+        let h11 = self.data[r1 * self.width + c1] as f64;
 
         // Bilinear interpolation
         let h0 = h00 * (1.0 - dc) + h01 * dc;
@@ -183,109 +182,386 @@ impl TerrainMap {
         h0 * (1.0 - dr) + h1 * dr
     }
 
-    /// Checks if there is Line of Sight (LOS) between two points.
+    /// Samples the terrain along the great-circle path between two antennas.
+    ///
+    /// Samples are taken every ~30m (excluding the endpoints). Each sample records the height
+    /// of the straight ray between the two antenna tips and the height of the "obstacle"
+    /// beneath it, i.e. the terrain elevation plus the earth curvature correction.
+    ///
+    /// Curvature: the ray is a straight chord while the earth is curved, so relative to the
+    /// chord the ground rises by h = d1 * d2 / (2 * R) at a point d1 from the start and
+    /// d2 from the end. Adding this to the terrain lets us compare against a straight ray.
+    /// Atmospheric refraction bends the ray back towards the ground, which is modelled by
+    /// using an effective radius R = k * 6371km (`k_factor` 1.0 is purely geometric,
+    /// 4/3 is the standard atmosphere).
+    pub fn path_profile(&self, a: Antenna, b: Antenna, k_factor: f64) -> PathProfile {
+        sample_profile(
+            |lat, lon| self.get_elevation(lat, lon),
+            a,
+            b,
+            TERRAIN_SAMPLE_STEP_M,
+            k_factor,
+        )
+    }
+
+    /// Checks if there is Line of Sight (LOS) between two antennas.
     /// Returns true if the path is CLEAR (no obstruction).
     /// Returns false if BLOCKED.
     ///
-    /// * `h1_m`, `h2_m`: Antenna heights in meters (added to terrain elevation).
-    pub fn check_line_of_sight(
+    /// Antenna heights are added to the terrain elevation at each end;
+    /// see `path_profile` for `k_factor`.
+    pub fn check_line_of_sight(&self, a: Antenna, b: Antenna, k_factor: f64) -> bool {
+        self.path_profile(a, b, k_factor)
+            .samples
+            .iter()
+            .all(|s| s.ray_height_m >= s.obstacle_height_m)
+    }
+
+    /// Computes the worst first-Fresnel-zone clearance along the link.
+    ///
+    /// Returns the minimum over all samples of `clearance / r1`, where `clearance` is the
+    /// height of the ray above the obstacle and `r1` is the first Fresnel zone radius at that
+    /// point for `freq_mhz`:
+    /// * `>= 1.0`: the whole first Fresnel zone is clear.
+    /// * `0.0..1.0`: partially obstructed (0.6 is the usual planning target).
+    /// * `< 0.0`: the direct ray itself is blocked.
+    ///
+    /// Links too short to sample return `f64::INFINITY`.
+    pub fn fresnel_clearance(&self, a: Antenna, b: Antenna, freq_mhz: f64, k_factor: f64) -> f64 {
+        let profile = self.path_profile(a, b, k_factor);
+
+        profile
+            .samples
+            .iter()
+            .map(|s| {
+                let r1 = crate::physics::fresnel_radius_m(
+                    s.distance_m,
+                    profile.distance_m - s.distance_m,
+                    freq_mhz,
+                );
+                (s.ray_height_m - s.obstacle_height_m) / r1
+            })
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Excess path loss in dB caused by terrain between two antennas, using the
+    /// Deygout multi-edge diffraction method (see `PathProfile::diffraction_loss_db`).
+    pub fn diffraction_loss_db(
         &self,
-        lat1: f64,
-        lon1: f64,
-        h1_m: f64,
-        lat2: f64,
-        lon2: f64,
-        h2_m: f64,
-    ) -> bool {
-        // TODO: Add Fresnel zone calculation for more realistic physics.
-        // Currently implements simple geometric line-of-sight.
-
-        let dist_km = crate::physics::haversine_distance(lat1, lon1, lat2, lon2);
-        if dist_km == 0.0 {
-            return true;
-        }
+        a: Antenna,
+        b: Antenna,
+        freq_mhz: f64,
+        k_factor: f64,
+    ) -> f64 {
+        self.path_profile(a, b, k_factor).diffraction_loss_db(freq_mhz)
+    }
+}
+
+/// One end of a radio link: a position plus the antenna height above ground.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Antenna {
+    pub lat: f64,
+    pub lon: f64,
+    pub height_m: f64,
+    /// Known ground elevation at the site. When `None` it is read from the terrain.
+    pub ground_elevation_m: Option<f64>,
+}
 
-        let steps = (dist_km * 1000.0 / 30.0).ceil() as usize; // Sample every ~30m
-        if steps < 2 {
-            return true;
+impl Antenna {
+    pub fn new(lat: f64, lon: f64, height_m: f64) -> Self {
+        Antenna {
+            lat,
+            lon,
+            height_m,
+            ground_elevation_m: None,
         }
+    }
+
+    /// Height of the antenna tip above sea level, using `elevation` when the
+    /// ground elevation is not known.
+    fn tip_height(&self, elevation: impl Fn(f64, f64) -> f64) -> f64 {
+        self.ground_elevation_m
+            .unwrap_or_else(|| elevation(self.lat, self.lon))
+            + self.height_m
+    }
+}
+
+/// Sample spacing along a link over real terrain.
+const TERRAIN_SAMPLE_STEP_M: f64 = 30.0;
+
+/// Sample spacing along a link over a smooth (sea level) earth, which has no fine detail.
+const SMOOTH_EARTH_SAMPLE_STEP_M: f64 = 250.0;
+
+/// Profile of a link over a smooth sea-level earth, used when no terrain is available.
+/// Only the earth curvature can obstruct the path.
+pub fn smooth_earth_profile(a: Antenna, b: Antenna, k_factor: f64) -> PathProfile {
+    sample_profile(|_, _| 0.0, a, b, SMOOTH_EARTH_SAMPLE_STEP_M, k_factor)
+}
 
-        let start_elev = self.get_elevation(lat1, lon1);
-        let end_elev = self.get_elevation(lat2, lon2);
+fn sample_profile(
+    elevation: impl Fn(f64, f64) -> f64,
+    a: Antenna,
+    b: Antenna,
+    step_m: f64,
+    k_factor: f64,
+) -> PathProfile {
+    let dist_km = crate::physics::haversine_distance(a.lat, a.lon, b.lat, b.lon);
+    let distance_m = dist_km * 1000.0;
+
+    let steps = (distance_m / step_m).ceil() as usize;
+    let start_total_h = a.tip_height(&elevation);
+    let end_total_h = b.tip_height(&elevation);
+
+    if dist_km == 0.0 || steps < 2 {
+        return PathProfile {
+            distance_m,
+            start_height_m: start_total_h,
+            end_height_m: end_total_h,
+            samples: Vec::new(),
+        };
+    }
 
-        let start_total_h = start_elev + h1_m;
-        let end_total_h = end_elev + h2_m;
+    let r_meters = crate::physics::effective_earth_radius_km(k_factor) * 1000.0;
 
-        for i in 1..steps {
+    let samples = (1..steps)
+        .map(|i| {
             let t = i as f64 / steps as f64;
-            let lat = lat1 + (lat2 - lat1) * t;
-            let lon = lon1 + (lon2 - lon1) * t;
-
-            // Simple linear interpolation of the ray height (flat earth approximation for short segments,
-            // but we should ideally account for earth curvature if segments are long).
-            // Since we are already doing Earth Bulge calculation in the cost function,
-            // we should ideally combine them.
-            // However, usually LOS checks include earth curvature by adjusting the terrain height relative to the chord
-            // or adjusting the ray.
-            // For simplicity here: Ray is a straight line in 3D cartesian space?
-            // No, we operate in lat/lon/alt.
-
-            // "Correct" approach for long distance:
-            // The ray height drops relative to the surface due to earth curvature.
-            // Drop d = dist_from_start * dist_from_end / (2 * R)
-            // But physics.rs calculates bulge separately.
-            // If we want this function to be pure "is there a mountain", we should just check geometric LOS
-            // assuming "up" is parallel.
-            // BUT, a mountain 50km away might block LOS because of curvature + height.
-            // Let's include curvature in the "ray height" check.
-
-            // Ray height at fraction t (linear interpolation)
-            let ray_h = start_total_h * (1.0 - t) + end_total_h * t;
-
-            // Earth bulge at this point (relative to the straight line chord between surface points)
-            // Note: physics::earth_bulge calculates the "hump" height.
-            // Effectively, the terrain "rises" by the bulge amount relative to the chord.
-            // OR the ray "sinks".
-            // Let's model it as: Is (Terrain + Bulge) > Ray?
-            // Wait, usually Bulge is calculated for the midpoint.
-            // Correct logic:
-            //   Effective Terrain Height = Actual Terrain Elevation + Earth Curvature Drop
-            //   We compare this to the Line between (Start+H1) and (End+H2).
-            //   Curvature Drop h = d1 * d2 / (2 * R). (Approx).
-            //   Let's reuse `physics::earth_bulge` but applied to the segments.
-            //   Actually `physics::earth_bulge` is h = d^2/8R (midpoint).
-            //   General formula for point at distance x from start in link of length D:
-            //   h_curvature = x * (D - x) / (2 * R)
-            //   We check: Ray_Height(t) < Terrain_Height(t) + h_curvature?
-            //   Wait, Ray_Height is altitude above sea level (approx).
-            //   We should compare:
-            //     Height_of_Ray_Above_Sea_Level vs Terrain_Height_Above_Sea_Level + Earth_Bulge_Correction?
-            //   No.
-            //   Let's visualize. Earth is curved. Ray is straight.
-            //   At midpoint, the earth surface is "higher" relative to the chord connecting the endpoints' sea-levels.
-            //   So yes, we ADD the bulge to the terrain elevation to check against the straight ray.
-
-            let dist_from_start_km = dist_km * t;
-            let dist_from_end_km = dist_km * (1.0 - t);
-
-            // Earth radius 6371km.
-            // h = (d1 * d2) / (2 * R)
-            // We need consistent units. Meters.
-            let r_meters = 6371.0 * 1000.0;
-            let d1_m = dist_from_start_km * 1000.0;
-            let d2_m = dist_from_end_km * 1000.0;
+            let lat = a.lat + (b.lat - a.lat) * t;
+            let lon = a.lon + (b.lon - a.lon) * t;
+
+            let d1_m = distance_m * t;
+            let d2_m = distance_m * (1.0 - t);
             let curvature_m = (d1_m * d2_m) / (2.0 * r_meters);
 
-            let terrain_h = self.get_elevation(lat, lon);
+            ProfileSample {
+                distance_m: d1_m,
+                ray_height_m: start_total_h * (1.0 - t) + end_total_h * t,
+                obstacle_height_m: elevation(lat, lon) + curvature_m,
+            }
+        })
+        .collect();
+
+    PathProfile {
+        distance_m,
+        start_height_m: start_total_h,
+        end_height_m: end_total_h,
+        samples,
+    }
+}
+
+/// A single terrain sample along a link (see `TerrainMap::path_profile`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileSample {
+    /// Distance from the first antenna in meters.
+    pub distance_m: f64,
+    /// Height of the straight ray between the antenna tips (meters above sea level).
+    pub ray_height_m: f64,
+    /// Terrain elevation plus the earth curvature correction (meters).
+    pub obstacle_height_m: f64,
+}
+
+/// Terrain samples along a link, ordered from the first antenna to the second.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathProfile {
+    /// Total link length in meters.
+    pub distance_m: f64,
+    /// Height of the first antenna tip (ground elevation + mast, meters above sea level).
+    pub start_height_m: f64,
+    /// Height of the second antenna tip (ground elevation + mast, meters above sea level).
+    pub end_height_m: f64,
+    pub samples: Vec<ProfileSample>,
+}
+
+/// Number of levels of sub-path recursion in the Deygout method.
+/// Depth 2 considers the main edge plus up to two secondary edges either side of it.
+const DEYGOUT_MAX_DEPTH: usize = 2;
+
+impl PathProfile {
+    /// Diffraction loss in dB over the terrain profile using the Deygout method.
+    ///
+    /// The sample with the largest Fresnel-Kirchhoff `v` relative to the direct ray is
+    /// treated as the main knife edge. The sub-paths from each terminal to the top of that
+    /// edge are then searched recursively for secondary edges, and all losses are summed.
+    ///
+    /// Neighbouring samples that are part of the same obstruction as an edge (e.g. the flat
+    /// top of a ridge) are skipped, otherwise they would be counted as extra grazing edges.
+    pub fn diffraction_loss_db(&self, freq_mhz: f64) -> f64 {
+        // Terminals plus every sample, as (distance, height) points.
+        let mut points = Vec::with_capacity(self.samples.len() + 2);
+        points.push((0.0, self.start_height_m));
+        points.extend(
+            self.samples
+                .iter()
+                .map(|s| (s.distance_m, s.obstacle_height_m)),
+        );
+        points.push((self.distance_m, self.end_height_m));
+
+        let last = points.len() - 1;
+        if last < 2 {
+            return 0.0;
+        }
+        deygout(&points, (0, last), (1, last - 1), freq_mhz, DEYGOUT_MAX_DEPTH)
+    }
+}
+
+/// Recursive Deygout step for the sub-path between terminals `points[lo]` and `points[hi]`,
+/// considering only the candidate edges `points[first..=last]`.
+fn deygout(
+    points: &[(f64, f64)],
+    (lo, hi): (usize, usize),
+    (first, last): (usize, usize),
+    freq_mhz: f64,
+    depth: usize,
+) -> f64 {
+    if first > last {
+        return 0.0;
+    }
+
+    let (d_lo, h_lo) = points[lo];
+    let (d_hi, h_hi) = points[hi];
+    let span = d_hi - d_lo;
+    let ray_height = |d: f64| h_lo + (h_hi - h_lo) * (d - d_lo) / span;
+
+    let v_at = |j: usize| {
+        let (d, h) = points[j];
+        crate::physics::fresnel_kirchhoff_v(h - ray_height(d), d - d_lo, d_hi - d, freq_mhz)
+    };
+
+    let mut best: Option<(usize, f64)> = None;
+    for k in first..=last {
+        let v = v_at(k);
+        if best.is_none_or(|(_, best_v)| v > best_v) {
+            best = Some((k, v));
+        }
+    }
+
+    let Some((k, v)) = best else {
+        return 0.0;
+    };
+    if v <= -0.78 {
+        return 0.0;
+    }
+
+    let mut loss = crate::physics::knife_edge_loss_db(v);
+    if depth > 0 {
+        // Samples either side of the main edge that also intrude into the Fresnel zone belong
+        // to the same obstacle (e.g. the flat top of a ridge), so exclude them from the sub-paths.
+        let intrudes = |j: usize| v_at(j) > -0.78;
+        let mut a = k;
+        while a > first && intrudes(a - 1) {
+            a -= 1;
+        }
+        let mut b = k;
+        while b < last && intrudes(b + 1) {
+            b += 1;
+        }
+
+        if a > first {
+            loss += deygout(points, (lo, k), (first, a - 1), freq_mhz, depth - 1);
+        }
+        loss += deygout(points, (k, hi), (b + 1, last), freq_mhz, depth - 1);
+    }
+    loss
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Raises a 3-cell wide North-South ridge through the middle of the map.
+    fn set_ridge(map: &mut TerrainMap, height_m: f64) {
+        let mid_col = map.width / 2;
+        for r in 0..map.height {
+            for c in mid_col - 1..=mid_col + 1 {
+                map.data[r * map.width + c] = height_m as f32;
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_of_sight_ridge() {
+        let mut map = TerrainMap::new_flat(0.0, 0.0, 20.0, 20.0, 30.0);
+        // North-South ridge through the middle of the map
+        set_ridge(&mut map, 100.0);
+
+        // 30m masts either side of a 100m ridge are blocked...
+        let a = Antenna::new(0.0, -0.05, 30.0);
+        let b = Antenna::new(0.0, 0.05, 30.0);
+        assert!(!map.check_line_of_sight(a, b, 1.0));
+        // ...but 150m masts can see over it.
+        let a = Antenna::new(0.0, -0.05, 150.0);
+        let b = Antenna::new(0.0, 0.05, 150.0);
+        assert!(map.check_line_of_sight(a, b, 1.0));
+    }
+
+    #[test]
+    fn test_fresnel_clearance() {
+        let mut map = TerrainMap::new_flat(0.0, 0.0, 20.0, 20.0, 30.0);
+        let a = Antenna::new(0.0, -0.05, 50.0);
+        let b = Antenna::new(0.0, 0.05, 50.0);
+
+        // ~11km over flat ground with 50m masts: r1 at the midpoint is ~31m,
+        // so the ray is comfortably clear.
+        let clear = map.fresnel_clearance(a, b, crate::physics::LORA_FREQ_EU_MHZ, 1.0);
+        assert!(clear > 1.0, "clearance was {}", clear);
+
+        // A 35m ridge in the middle grazes the Fresnel zone without blocking the ray.
+        set_ridge(&mut map, 35.0);
+        let grazing = map.fresnel_clearance(a, b, crate::physics::LORA_FREQ_EU_MHZ, 1.0);
+        assert!(grazing > 0.0 && grazing < 0.6, "clearance was {}", grazing);
+        assert!(map.check_line_of_sight(a, b, 1.0));
+
+        // A 60m ridge blocks the ray outright.
+        set_ridge(&mut map, 60.0);
+        assert!(map.fresnel_clearance(a, b, crate::physics::LORA_FREQ_EU_MHZ, 1.0) < 0.0);
+    }
 
-            // Check
-            // We assume the ray is a straight line between the two antenna tips.
-            // The "ground" effectively rises up by `curvature_m` relative to that chord.
-            if ray_h < (terrain_h + curvature_m) {
-                return false; // Blocked
+    #[test]
+    fn test_diffraction_loss() {
+        let freq = crate::physics::LORA_FREQ_EU_MHZ;
+        let mut map = TerrainMap::new_flat(0.0, 0.0, 20.0, 20.0, 30.0);
+        let a = Antenna::new(0.0, -0.05, 50.0);
+        let b = Antenna::new(0.0, 0.05, 50.0);
+
+        assert_eq!(map.diffraction_loss_db(a, b, freq, 1.0), 0.0);
+
+        // Loss grows smoothly with ridge height
+        set_ridge(&mut map, 45.0);
+        let low = map.diffraction_loss_db(a, b, freq, 1.0);
+        set_ridge(&mut map, 60.0);
+        let mid = map.diffraction_loss_db(a, b, freq, 1.0);
+        set_ridge(&mut map, 300.0);
+        let high = map.diffraction_loss_db(a, b, freq, 1.0);
+
+        assert!(low > 0.0 && low < 6.0, "low was {}", low);
+        assert!(mid > low && mid < 15.0, "mid was {}", mid);
+        assert!(high > 30.0, "high was {}", high);
+
+        // A second ridge adds further loss
+        let quarter_col = map.width / 4;
+        for r in 0..map.height {
+            for c in quarter_col - 1..=quarter_col + 1 {
+                map.data[r * map.width + c] = 200.0;
             }
         }
+        assert!(map.diffraction_loss_db(a, b, freq, 1.0) > high);
+    }
 
-        true
+    #[test]
+    fn test_refraction_extends_horizon() {
+        // ~60km over flat ground with 30m masts: the earth bulge at the midpoint is ~70m
+        // geometrically, ~53m with standard refraction (k = 4/3) and ~18m with strong
+        // ducting (k = 4), where the ray finally clears.
+        let map = TerrainMap::new_flat(0.0, 0.0, 80.0, 20.0, 30.0);
+        let a = Antenna::new(0.0, -0.27, 30.0);
+        let b = Antenna::new(0.0, 0.27, 30.0);
+
+        assert!(!map.check_line_of_sight(a, b, 1.0));
+        assert!(map.check_line_of_sight(a, b, 4.0));
+
+        let freq = crate::physics::LORA_FREQ_EU_MHZ;
+        let geometric = map.diffraction_loss_db(a, b, freq, 1.0);
+        let standard = map.diffraction_loss_db(a, b, freq, 4.0 / 3.0);
+        assert!(standard < geometric);
     }
 }
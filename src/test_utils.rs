@@ -24,6 +24,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
             name: format!("Node_{}", i),
             lat: center_lat + lat_offset,
             lon: center_lon + lon_offset,
+            ..Default::default()
         });
     }
 
@@ -36,6 +37,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
         name: "Clash_Local_A".to_string(),
         lat: center_lat + 0.1, // ~11km North
         lon: center_lon,
+        ..Default::default()
     });
     // Node B
     nodes.push(Repeater {
@@ -44,6 +46,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
         lat: center_lat + 0.15, // ~16km North (5km apart) - wait, plan said ~30km apart.
         // 0.1 deg lat is ~11km. 0.3 deg is ~33km.
         lon: center_lon + 0.3, // ~20km East
+        ..Default::default()
     });
 
     // 3. Inject Global Clash
@@ -55,6 +58,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
         name: "Clash_Global_C".to_string(),
         lat: center_lat,
         lon: center_lon,
+        ..Default::default()
     });
     // Node D (Far away)
     // 1.0 degrees lat is ~111km away from center.
@@ -64,6 +68,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
         name: "Clash_Global_D".to_string(),
         lat: center_lat + 1.0,
         lon: center_lon + 0.5,
+        ..Default::default()
     });
 
     // Bridge nodes to reach Node D
@@ -74,6 +79,7 @@ pub fn generate_dummy_nodes() -> Vec<Repeater> {
         name: "Bridge_1".to_string(),
         lat: center_lat + 0.7,
         lon: center_lon + 0.2,
+        ..Default::default()
     });
 
     nodes
@@ -1,9 +1,10 @@
 use app::graph::NetworkGraph;
-use app::models::{PathNode, Repeater};
+use app::models::{PathHash, PathNode, Repeater};
 use app::pathfinding::find_path;
 use app::physics;
 use app::terrain::TerrainMap;
 use app::test_utils::generate_dummy_nodes;
+use std::sync::Arc;
 
 fn find_node_idx(nodes: &[Repeater], id: &str) -> Option<usize> {
     nodes.iter().position(|n| n.id == id)
@@ -29,16 +30,17 @@ fn find_closest(nodes: &[Repeater], target_idx: usize) -> usize {
 
 fn verify_path_reconstruction(nodes: &[Repeater], ground_truth_indices: &[usize]) {
     // 1. Convert to Prefixes
-    let prefixes: Vec<u8> = ground_truth_indices
+    let prefixes: Vec<PathHash> = ground_truth_indices
         .iter()
-        .map(|&idx| nodes[idx].prefix())
+        .map(|&idx| PathHash::byte(nodes[idx].prefix().unwrap()))
         .collect();
 
     // 2. Run Viterbi
     let graph = NetworkGraph::new(nodes.to_vec(), None);
     let reconstructed_path = graph
         .decode_path(&prefixes)
-        .expect("Viterbi failed to decode path");
+        .expect("Viterbi failed to decode path")
+        .hops;
 
     // 3. Verify
     // Convert reconstructed path (Vec<PathNode>) to indices (Vec<usize>) for comparison
@@ -48,7 +50,7 @@ fn verify_path_reconstruction(nodes: &[Repeater], ground_truth_indices: &[usize]
         .map(|node| match node {
             PathNode::Known(idx) => *idx,
             PathNode::Unknown(prefix) => panic!(
-                "Unexpected Unknown node with prefix {:02x} in fully known scenario",
+                "Unexpected Unknown node with prefix {} in fully known scenario",
                 prefix
             ),
         })
@@ -139,6 +141,7 @@ fn test_complex_multipath() {
         name: "Start".to_string(),
         lat: center_lat,
         lon: center_lon,
+        ..Default::default()
     });
 
     // Grid Layers (Indices 1..9)
@@ -159,6 +162,7 @@ fn test_complex_multipath() {
                 name: format!("Grid_{}_{}", i, j),
                 lat: center_lat + lat_offset,
                 lon: center_lon + lon_offset,
+                ..Default::default()
             });
         }
     }
@@ -173,6 +177,7 @@ fn test_complex_multipath() {
         name: "Shortcut".to_string(),
         lat: center_lat + 0.3,
         lon: center_lon + 0.4,
+        ..Default::default()
     });
 
     // 11. End Node (at 0.8 lon)
@@ -181,6 +186,7 @@ fn test_complex_multipath() {
         name: "End".to_string(),
         lat: center_lat,
         lon: center_lon + 0.8,
+        ..Default::default()
     });
     let end_idx = nodes.len() - 1;
 
@@ -191,7 +197,7 @@ fn test_complex_multipath() {
 
         // Define the Expected Path IDs (Straight line through the center)
         // Start -> Grid_1_0 (110000) -> Grid_2_0 (210000) -> Grid_3_0 (310000) -> End
-        let expected_ids = vec!["000000", "110000", "210000", "310000", "EE0000"];
+        let expected_ids = ["000000", "110000", "210000", "310000", "EE0000"];
 
         let expected_indices: Vec<usize> = expected_ids
             .iter()
@@ -264,6 +270,7 @@ fn test_viterbi_with_terrain() {
         name: "Start".to_string(),
         lat: 0.0,
         lon: -0.2, // ~22km West of center
+        ..Default::default()
     };
 
     // End Node (East)
@@ -272,6 +279,7 @@ fn test_viterbi_with_terrain() {
         name: "End".to_string(),
         lat: 0.0,
         lon: 0.2, // ~22km East of center
+        ..Default::default()
     };
 
     // Candidate Middle Nodes
@@ -283,6 +291,7 @@ fn test_viterbi_with_terrain() {
         name: "Blocked".to_string(),
         lat: 0.0,
         lon: 0.1,
+        ..Default::default()
     };
 
     // 2. Detour path node (Through the gap at the top)
@@ -295,6 +304,7 @@ fn test_viterbi_with_terrain() {
         name: "Detour".to_string(),
         lat: gap_lat,
         lon: 0.0, // Center lon, but at South gap
+        ..Default::default()
     };
 
     // All nodes
@@ -314,9 +324,10 @@ fn test_viterbi_with_terrain() {
 
     // Case 1: Packet header says [A0, B2, C0].
     // Should be easy, B2 is valid.
-    let obs_easy = vec![0xA0, 0xB2, 0xC0];
-    let graph = NetworkGraph::new(nodes.clone(), Some(&map));
-    let path_easy = graph.decode_path(&obs_easy).unwrap();
+    let obs_easy = [PathHash::byte(0xA0), PathHash::byte(0xB2), PathHash::byte(0xC0)];
+    let map = Arc::new(map);
+    let graph = NetworkGraph::new(nodes.clone(), Some(map.clone()));
+    let path_easy = graph.decode_path(&obs_easy).unwrap().hops;
     // Should contain index 3 (Detour)
     if let PathNode::Known(idx) = path_easy[1] {
         assert_eq!(idx, 3, "Should pick Detour node");
@@ -327,8 +338,8 @@ fn test_viterbi_with_terrain() {
     // Case 2: Packet header says [A0, B1, C0].
     // B1 is blocked.
     // Viterbi should return path with B1 but with VERY high cost, OR fail if we set cost to infinity?
-    // Our implementation sets cost to 2000.0 (very high) but not infinity.
-    // So it should still return it, but if we had an alternative unknown path, it might prefer that.
+    // Our implementation makes a deeply shadowed link infinitely expensive, so it is pruned
+    // from the graph.
     // Let's see if we can force it to pick an "Unknown" node instead if we provide a wildcard.
 
     // Case 3: Ambiguous prefix?
@@ -342,19 +353,21 @@ fn test_viterbi_with_terrain() {
         name: "Blocked_B0".to_string(),
         lat: 0.0,
         lon: 0.1,
+        ..Default::default()
     };
     let mid_detour_b0 = Repeater {
         id: "B00002".to_string(),
         name: "Detour_B0".to_string(),
         lat: gap_lat,
         lon: 0.0,
+        ..Default::default()
     };
 
     let nodes_ambiguous = vec![start.clone(), end.clone(), mid_blocked_b0, mid_detour_b0];
-    let obs_ambiguous = vec![0xA0, 0xB0, 0xC0];
+    let obs_ambiguous = [PathHash::byte(0xA0), PathHash::byte(0xB0), PathHash::byte(0xC0)];
 
-    let graph_ambiguous = NetworkGraph::new(nodes_ambiguous.clone(), Some(&map));
-    let path_ambiguous = graph_ambiguous.decode_path(&obs_ambiguous).unwrap();
+    let graph_ambiguous = NetworkGraph::new(nodes_ambiguous.clone(), Some(map.clone()));
+    let path_ambiguous = graph_ambiguous.decode_path(&obs_ambiguous).unwrap().hops;
 
     if let PathNode::Known(idx) = path_ambiguous[1] {
         // Indices: 0=Start, 1=End, 2=Blocked, 3=Detour
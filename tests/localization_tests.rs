@@ -1,5 +1,12 @@
-use app::localization::localize_unknowns;
-use app::models::{PathNode, Repeater};
+use app::localization::{
+    HeatmapConfig, LocalizationConfig, LocationEstimator, MultilaterationConfig, PathObservation,
+    localize_unknowns, localize_unknowns_with_model,
+};
+use app::graph::Anchor;
+use app::models::{PathHash, PathNode, Repeater};
+use app::physics::haversine_distance;
+use app::propagation::SigmoidModel;
+use app::terrain::TerrainMap;
 
 fn make_repeater(id: &str, lat: f64, lon: f64) -> Repeater {
     Repeater {
@@ -7,9 +14,14 @@ fn make_repeater(id: &str, lat: f64, lon: f64) -> Repeater {
         name: "Test".to_string(),
         lat,
         lon,
+        ..Default::default()
     }
 }
 
+fn observations(paths: &[Vec<PathNode>]) -> Vec<PathObservation> {
+    paths.iter().cloned().map(PathObservation::from).collect()
+}
+
 #[test]
 fn test_localize_simple_midpoint() {
     // Scenario 1: Simple case
@@ -23,7 +35,7 @@ fn test_localize_simple_midpoint() {
     // Path: K1 -> U(AA) -> K2
     let path = vec![
         PathNode::Known(0),
-        PathNode::Unknown(0xAA),
+        PathNode::Unknown(PathHash::byte(0xAA)),
         PathNode::Known(1),
     ];
 
@@ -53,12 +65,12 @@ fn test_localize_multiple_observations_cluster() {
 
     let path1 = vec![
         PathNode::Known(0),
-        PathNode::Unknown(0xBB),
+        PathNode::Unknown(PathHash::byte(0xBB)),
         PathNode::Known(1),
     ];
     let path2 = vec![
         PathNode::Known(2),
-        PathNode::Unknown(0xBB),
+        PathNode::Unknown(PathHash::byte(0xBB)),
         PathNode::Known(3),
     ];
 
@@ -92,12 +104,12 @@ fn test_localize_split_clusters() {
 
     let path1 = vec![
         PathNode::Known(0),
-        PathNode::Unknown(0xCC),
+        PathNode::Unknown(PathHash::byte(0xCC)),
         PathNode::Known(1),
     ];
     let path2 = vec![
         PathNode::Known(2),
-        PathNode::Unknown(0xCC),
+        PathNode::Unknown(PathHash::byte(0xCC)),
         PathNode::Known(3),
     ];
 
@@ -122,23 +134,313 @@ fn test_localize_split_clusters() {
 }
 
 #[test]
-fn test_localize_ignore_non_triplets() {
-    // Path: K1 -> U -> U -> K2
-    // Should be ignored by Phase 1 as per requirements (only K->U->K)
+fn test_localize_merges_compatible_hash_widths() {
+    // The same hidden repeater logged as `a1` by old firmware and `a1b2` by new,
+    // next to a different one logged as `a1c3`
+    let known_nodes = vec![
+        make_repeater("0x11", 0.0, 0.0),
+        make_repeater("0x22", 0.0, 0.4),
+        make_repeater("0x33", 0.0, 0.6),
+    ];
+    let hop = |hex: &str| PathNode::Unknown(PathHash::parse(hex).unwrap());
+    let paths = vec![
+        vec![PathNode::Known(0), hop("a1"), PathNode::Known(1)],
+        vec![PathNode::Known(0), hop("a1b2"), PathNode::Known(1)],
+        vec![PathNode::Known(0), hop("a1c3"), PathNode::Known(2)],
+    ];
+
+    let results = localize_unknowns(&paths, &known_nodes);
+
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].prefix, "a1b2");
+    assert_eq!(results[0].observation_count, 2);
+    assert!((results[0].lon - 0.2).abs() < 1e-6);
+    assert_eq!(results[1].prefix, "a1c3");
+    assert_eq!(results[1].observation_count, 1);
+}
 
+#[test]
+fn test_localize_unknown_chain() {
+    // Path: K1 -> U(DD) -> U(EE) -> K2
+    // The gap is split evenly: DD a third of the way from K1, EE two thirds.
     let k1 = make_repeater("0x11", 0.0, 0.0);
-    let k2 = make_repeater("0x22", 0.0, 2.0);
+    let k2 = make_repeater("0x22", 0.0, 0.6);
     let known_nodes = vec![k1, k2];
 
     let path = vec![
         PathNode::Known(0),
-        PathNode::Unknown(0xDD),
-        PathNode::Unknown(0xEE), // Chain
+        PathNode::Unknown(PathHash::byte(0xDD)),
+        PathNode::Unknown(PathHash::byte(0xEE)), // Chain
         PathNode::Known(1),
     ];
 
     let paths = vec![path];
 
     let results = localize_unknowns(&paths, &known_nodes);
-    assert!(results.is_empty());
+    assert_eq!(results.len(), 2);
+    let dd = results.iter().find(|r| r.prefix == "dd").unwrap();
+    let ee = results.iter().find(|r| r.prefix == "ee").unwrap();
+    assert!(dd.lat.abs() < 1e-6 && (dd.lon - 0.2).abs() < 1e-6);
+    assert!(ee.lat.abs() < 1e-6 && (ee.lon - 0.4).abs() < 1e-6);
+
+    // Multilateration keeps each Unknown on its side of the gap: one hop from its
+    // nearer witness, two from the other.
+    let config = LocalizationConfig {
+        estimator: LocationEstimator::Multilateration,
+        ..LocalizationConfig::default()
+    };
+    let fitted = localize_unknowns_with_model(&observations(&paths), &known_nodes, &SigmoidModel::default(), None, &config);
+    let dd = fitted.iter().find(|r| r.prefix == "dd").unwrap();
+    let ee = fitted.iter().find(|r| r.prefix == "ee").unwrap();
+    assert!(dd.lon < 0.3, "DD should be nearer K1, got {}", dd.lon);
+    assert!(ee.lon > 0.3, "EE should be nearer K2, got {}", ee.lon);
+}
+
+#[test]
+fn test_localize_run_fitted_jointly() {
+    // Origin (0,0) -> U(AA) -> U(BB) -> K1 (0,0.6). The origin is a handheld, whose weak
+    // leg pulls AA towards it; BB follows, since the AA -> BB link is in the fit too.
+    let known_nodes = vec![make_repeater("0x11", 0.0, 0.6)];
+    let observation = PathObservation {
+        path: vec![
+            PathNode::Unknown(PathHash::byte(0xAA)),
+            PathNode::Unknown(PathHash::byte(0xBB)),
+            PathNode::Known(0),
+        ],
+        origin: Some(Anchor::new(0.0, 0.0)),
+        ..Default::default()
+    };
+    let config = LocalizationConfig {
+        heatmap: Some(HeatmapConfig {
+            radius_km: 2.0,
+            cell_size_m: 500.0,
+            ..HeatmapConfig::default()
+        }),
+        ..LocalizationConfig::default()
+    };
+
+    let results = localize_unknowns_with_model(&[observation], &known_nodes, &SigmoidModel::default(), None, &config);
+    assert_eq!(results.len(), 2);
+    let aa = results.iter().find(|r| r.prefix == "aa").unwrap();
+    let bb = results.iter().find(|r| r.prefix == "bb").unwrap();
+    assert!(aa.lat.abs() < 1e-6 && bb.lat.abs() < 1e-6);
+    assert!(aa.lon > 0.0 && aa.lon < 0.19, "AA should be pulled towards the origin, got {}", aa.lon);
+    assert!(bb.lon > aa.lon && bb.lon < 0.39, "BB should follow AA, got {}", bb.lon);
+
+    // Both report the known repeater at the end of the run, but AA's links are costed
+    // against the origin and BB
+    assert_eq!(aa.witnesses, vec!["0x11"]);
+    assert_eq!(bb.witnesses, vec!["0x11"]);
+    assert_eq!(aa.candidates[0].witnesses_reached, 2);
+}
+
+#[test]
+fn test_localize_from_packet_endpoints() {
+    // Origin (0,0) -> U(AA) -> K1 (0,2), and K1 (0,2) -> U(BB) -> observer (2,2)
+    let known_nodes = vec![make_repeater("0x11", 0.0, 2.0)];
+    let observations = vec![
+        PathObservation {
+            path: vec![PathNode::Unknown(PathHash::byte(0xAA)), PathNode::Known(0)],
+            origin: Some(Anchor::new(0.0, 0.0)),
+            observer: None,
+            timestamp: None,
+        },
+        PathObservation {
+            path: vec![PathNode::Known(0), PathNode::Unknown(PathHash::byte(0xBB))],
+            origin: None,
+            observer: Some(Anchor::new(2.0, 2.0)),
+            timestamp: None,
+        },
+    ];
+
+    let results = localize_unknowns_with_model(
+        &observations,
+        &known_nodes,
+        &SigmoidModel::default(),
+        None,
+        &LocalizationConfig::default(),
+    );
+    assert_eq!(results.len(), 2);
+    let aa = results.iter().find(|r| r.prefix == "aa").unwrap();
+    let bb = results.iter().find(|r| r.prefix == "bb").unwrap();
+    assert!(aa.lat.abs() < 1e-6 && (aa.lon - 1.0).abs() < 1e-6);
+    assert!((bb.lat - 1.0).abs() < 1e-6 && (bb.lon - 2.0).abs() < 1e-6);
+
+    // Without the end points there is nothing to place them against
+    let paths: Vec<Vec<PathNode>> = observations.into_iter().map(|o| o.path).collect();
+    assert!(localize_unknowns(&paths, &known_nodes).is_empty());
+}
+
+#[test]
+fn test_heatmap_refinement_avoids_shadowed_midpoint() {
+    // K1 (0,0) -> U -> K2 (0,0.6). A ridge just east of K1 shadows everything south of
+    // the equator, so the hidden repeater must be north of the midpoint.
+    let known_nodes = vec![
+        make_repeater("0x1111", 0.0, 0.0),
+        make_repeater("0x2222", 0.0, 0.6),
+    ];
+    let paths = vec![vec![
+        PathNode::Known(0),
+        PathNode::Unknown(PathHash::byte(0xAA)),
+        PathNode::Known(1),
+    ]];
+    let config = LocalizationConfig {
+        heatmap: Some(HeatmapConfig {
+            radius_km: 8.0,
+            cell_size_m: 500.0,
+            ..HeatmapConfig::default()
+        }),
+        ..LocalizationConfig::default()
+    };
+
+    // Flat: one blob around the midpoint, reaching both witnesses
+    let flat = TerrainMap::new_flat(0.0, 0.3, 90.0, 30.0, 100.0);
+    let results = localize_unknowns_with_model(&observations(&paths), &known_nodes, &SigmoidModel::default(), Some(&flat), &config);
+    assert_eq!(results.len(), 1);
+    let candidates = &results[0].candidates;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].witnesses_reached, 2);
+    assert!(candidates[0].lat.abs() < 0.01);
+    assert!((candidates[0].lon - 0.3).abs() < 0.01);
+    assert!(candidates[0].area_m2 > 0.0);
+    assert!(candidates[0].mean_link_cost.is_finite());
+
+    // Ridge: 1000m high at lon 0.1, from lat -0.12 up to the equator
+    let mut ridged = TerrainMap::new_flat(0.0, 0.3, 90.0, 30.0, 100.0);
+    for row in 0..ridged.height {
+        let lat = ridged.min_lat + (ridged.max_lat - ridged.min_lat) * row as f64 / (ridged.height - 1) as f64;
+        if !(-0.12..=0.0).contains(&lat) {
+            continue;
+        }
+        for col in 0..ridged.width {
+            let lon = ridged.min_lon + (ridged.max_lon - ridged.min_lon) * col as f64 / (ridged.width - 1) as f64;
+            if (lon - 0.1).abs() < 0.005 {
+                ridged.data[row * ridged.width + col] = 1000.0;
+            }
+        }
+    }
+    let results = localize_unknowns_with_model(&observations(&paths), &known_nodes, &SigmoidModel::default(), Some(&ridged), &config);
+    let best = &results[0].candidates[0];
+    assert_eq!(best.witnesses_reached, 2);
+    assert!(best.lat > 0.02, "candidate should move north of the ridge's shadow, got {}", best.lat);
+    assert!(best.area_m2 < candidates[0].area_m2);
+    // The spread is measured from the candidate reported, not the midpoint
+    let offset_km = haversine_distance(best.lat, best.lon, 0.0, 0.3);
+    assert!((results[0].radius_km - offset_km).abs() < 1e-6);
+    assert!((results[0].std_dev_km - offset_km).abs() < 1e-6);
+
+    // Pass 1 alone is unchanged
+    assert!(localize_unknowns(&paths, &known_nodes)[0].candidates.is_empty());
+}
+
+#[test]
+fn test_multilateration_ellipse() {
+    // K1 (0,0) and K2 (0,0.6) on an east-west line: the Unknown is somewhere in the lens
+    // where both discs overlap, which is longer north-south than east-west.
+    let known_nodes = vec![
+        make_repeater("0x1111", 0.0, 0.0),
+        make_repeater("0x2222", 0.0, 0.6),
+        make_repeater("0x3333", 0.5, 0.3),
+    ];
+    let triplet = |a: usize, b: usize| {
+        vec![PathNode::Known(a), PathNode::Unknown(PathHash::byte(0xAA)), PathNode::Known(b)]
+    };
+    let config = LocalizationConfig {
+        estimator: LocationEstimator::Multilateration,
+        ..LocalizationConfig::default()
+    };
+    let model = SigmoidModel::default();
+
+    let pair = localize_unknowns_with_model(&[triplet(0, 1).into()], &known_nodes, &model, None, &config);
+    assert_eq!(pair.len(), 1);
+    let fit = pair[0].multilateration.as_ref().expect("no multilateration fit");
+    assert_eq!((pair[0].lat, pair[0].lon), (fit.lat, fit.lon));
+    // Symmetric, so the best spot is between the witnesses
+    assert!(fit.lat.abs() < 0.02 && (fit.lon - 0.3).abs() < 0.02);
+    assert!(fit.ellipse.semi_major_m > 1.5 * fit.ellipse.semi_minor_m);
+    assert!(fit.ellipse.orientation_deg < 10.0 || fit.ellipse.orientation_deg > 170.0);
+    assert!(fit.covariance_m2[1][1] > fit.covariance_m2[0][0]);
+    assert!(!fit.flat_likelihood);
+
+    // A third witness to the north pulls the estimate towards it and tightens the ellipse
+    let paths = vec![triplet(0, 1), triplet(2, 1)];
+    let three = localize_unknowns_with_model(&observations(&paths), &known_nodes, &model, None, &config);
+    assert_eq!(three.len(), 1);
+    let tighter = three[0].multilateration.as_ref().unwrap();
+    assert!(tighter.lat > fit.lat);
+    assert!(tighter.ellipse.semi_major_m < fit.ellipse.semi_major_m);
+
+    // Searched too narrowly, the likelihood barely changes and the ellipse is the box's
+    let narrow = LocalizationConfig {
+        multilateration: MultilaterationConfig { radius_km: 1.0, cell_size_m: 250.0 },
+        ..config.clone()
+    };
+    let clipped = localize_unknowns_with_model(&[triplet(0, 1).into()], &known_nodes, &model, None, &narrow);
+    let clipped = clipped[0].multilateration.as_ref().unwrap();
+    assert!(clipped.flat_likelihood);
+    assert!(clipped.ellipse.semi_major_m < fit.ellipse.semi_major_m);
+
+    // The centroid estimator leaves the fit out
+    let centroid = localize_unknowns_with_model(&observations(&paths), &known_nodes, &model, None, &LocalizationConfig::default());
+    assert!(centroid[0].multilateration.is_none());
+}
+
+#[test]
+fn test_inferred_repeater_metadata() {
+    // Three AA observations around (0,1), next to a known AA repeater; one BB heard from
+    // a packet's origin.
+    let known_nodes = vec![
+        make_repeater("110000", 0.0, 0.0),
+        make_repeater("220000", 0.0, 2.0),
+        make_repeater("330000", 1.0, 1.0),
+        make_repeater("440000", -1.0, 1.0),
+        make_repeater("550000", 0.2, 0.0),
+        make_repeater("AA5555", 0.0, 1.05),
+    ];
+    let observe = |path: Vec<PathNode>, timestamp: &str| PathObservation {
+        path,
+        timestamp: Some(timestamp.to_string()),
+        ..Default::default()
+    };
+    let aa = || PathNode::Unknown(PathHash::byte(0xAA));
+    let observations = vec![
+        observe(vec![PathNode::Known(0), aa(), PathNode::Known(1)], "2024-03-02T08:00:00Z"),
+        observe(vec![PathNode::Known(2), aa(), PathNode::Known(3)], "2024-03-01T12:00:00Z"),
+        observe(vec![PathNode::Known(4), aa(), PathNode::Known(1)], "2024-03-05T18:30:00Z"),
+        PathObservation {
+            path: vec![PathNode::Unknown(PathHash::byte(0xBB)), PathNode::Known(1)],
+            origin: Some(Anchor::new(0.0, 3.0)),
+            ..Default::default()
+        },
+    ];
+
+    let results = localize_unknowns_with_model(
+        &observations,
+        &known_nodes,
+        &SigmoidModel::default(),
+        None,
+        &LocalizationConfig::default(),
+    );
+    assert_eq!(results.len(), 2);
+
+    let aa = &results[0];
+    assert_eq!(aa.prefix, "aa");
+    // Midpoints (0,1), (0,1), (0.1,1): the outlier is 2/3 of ~11.1km from the centroid
+    assert!((aa.radius_km - 7.41).abs() < 0.05, "radius {}", aa.radius_km);
+    assert!(aa.std_dev_km > 0.0 && aa.std_dev_km < aa.radius_km);
+    assert_eq!(aa.witnesses, vec!["110000", "220000", "330000", "440000", "550000"]);
+    assert_eq!(aa.first_seen.as_deref(), Some("2024-03-01T12:00:00Z"));
+    assert_eq!(aa.last_seen.as_deref(), Some("2024-03-05T18:30:00Z"));
+    assert!(aa.overlaps_known);
+
+    let bb = &results[1];
+    assert_eq!(bb.prefix, "bb");
+    assert_eq!((bb.std_dev_km, bb.radius_km), (0.0, 0.0));
+    assert_eq!(bb.witnesses, vec!["220000"]);
+    assert_eq!((bb.first_seen.as_ref(), bb.last_seen.as_ref()), (None, None));
+    assert!(!bb.overlaps_known);
+    // One observation and one named witness: (1/3) * (1/3)
+    assert!((bb.confidence - 1.0 / 9.0).abs() < 1e-9);
+
+    assert!(aa.confidence > bb.confidence && aa.confidence < 1.0);
 }
@@ -0,0 +1,109 @@
+use app::graph::{NetworkGraph, PacketPath};
+use app::localization::LocalizationConfig;
+use app::models::{PathHash, PathNode, Repeater};
+use app::promotion::{PromotionConfig, promote_unknowns};
+
+fn make_repeater(id: &str, lat: f64, lon: f64) -> Repeater {
+    Repeater {
+        id: id.to_string(),
+        name: "Test".to_string(),
+        lat,
+        lon,
+        ..Default::default()
+    }
+}
+
+fn packet(prefixes: &[u8]) -> PacketPath {
+    PacketPath {
+        hashes: prefixes.iter().map(|&p| PathHash::byte(p)).collect(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_promotion_resolves_hidden_repeater() {
+    // A repeater with prefix AA at (0, 0.3) is missing from the database. Five packets
+    // cross it between the four known repeaters around it; BB is seen only once.
+    let known_nodes = vec![
+        make_repeater("110000", 0.0, 0.0),
+        make_repeater("220000", 0.0, 0.6),
+        make_repeater("330000", 0.25, 0.3),
+        make_repeater("440000", -0.25, 0.3),
+    ];
+    let packets = vec![
+        packet(&[0x11, 0xAA, 0x22]),
+        packet(&[0x22, 0xAA, 0x11]),
+        packet(&[0x33, 0xAA, 0x44]),
+        packet(&[0x44, 0xAA, 0x33]),
+        packet(&[0x11, 0xAA, 0x33]),
+        packet(&[0x11, 0xBB, 0x44]),
+    ];
+    let timestamps: Vec<Option<String>> = (1..=6).map(|day| Some(format!("2024-01-0{}", day))).collect();
+    let mut graph = NetworkGraph::new(known_nodes, None);
+
+    let result = promote_unknowns(
+        &mut graph,
+        &packets,
+        &timestamps,
+        &LocalizationConfig::default(),
+        &PromotionConfig::default(),
+        2,
+    );
+
+    // Pass 1 leaves both as Unknown, pass 2 resolves the AA hops, pass 3 confirms
+    let first = &result.iterations[0];
+    assert_eq!((first.inferred_nodes, first.inferred_hops, first.unknown_hops), (0, 0, 6));
+    assert_eq!(first.changed_packets, 6);
+    let second = &result.iterations[1];
+    assert_eq!((second.inferred_nodes, second.inferred_hops, second.unknown_hops), (1, 5, 1));
+    assert_eq!(second.changed_packets, 5);
+    assert!(second.inferred_edges > 0);
+    assert_eq!(second.edge_count, first.edge_count + second.inferred_edges);
+    assert!(result.converged);
+    assert_eq!(result.iterations.len(), 3);
+    assert_eq!(result.iterations[2].changed_packets, 0);
+
+    // The graph keeps the promoted repeater, near where it really is
+    assert_eq!(graph.inferred_count(), 1);
+    let hidden = &graph.nodes()[4];
+    assert!(hidden.inferred);
+    assert_eq!(hidden.id, "aa");
+    assert!(hidden.lat.abs() < 0.05 && (hidden.lon - 0.3).abs() < 0.05);
+    let decoded = result.decoded[0].as_ref().unwrap();
+    assert_eq!(decoded.hops[1], PathNode::Known(4));
+
+    // Localization still reports both, the promoted one with every hop assigned to it
+    let aa = result.inferred.iter().find(|r| r.prefix == "aa").unwrap();
+    assert_eq!(aa.observation_count, 5);
+    assert_eq!(aa.first_seen.as_deref(), Some("2024-01-01"));
+    assert_eq!(aa.last_seen.as_deref(), Some("2024-01-05"));
+    assert!(result.inferred.iter().any(|r| r.prefix == "bb"));
+
+    // Demanding more confidence than the cluster has keeps it out of the graph
+    assert!(aa.confidence >= PromotionConfig::default().min_confidence);
+    assert!(aa.confidence < 0.9);
+    let strict = PromotionConfig {
+        min_confidence: 0.9,
+        ..PromotionConfig::default()
+    };
+    let result = promote_unknowns(&mut graph, &packets, &[], &LocalizationConfig::default(), &strict, 2);
+    assert!(result.converged);
+    assert_eq!(graph.inferred_count(), 0);
+}
+
+#[test]
+fn test_promotion_without_candidates_converges_immediately() {
+    let known_nodes = vec![make_repeater("110000", 0.0, 0.0), make_repeater("220000", 0.0, 0.4)];
+    let mut graph = NetworkGraph::new(known_nodes, None);
+    let result = promote_unknowns(
+        &mut graph,
+        &[packet(&[0x11, 0x22]), packet(&[0x11, 0xCC, 0x22])],
+        &[],
+        &LocalizationConfig::default(),
+        &PromotionConfig::default(),
+        1,
+    );
+    assert!(result.converged);
+    assert_eq!(result.iterations.len(), 2);
+    assert_eq!(graph.inferred_count(), 0);
+}